@@ -1,6 +1,8 @@
 #![doc = include_str!("../README.md")]
 #![no_std]
 
+pub mod rtttl;
+
 #[derive(Debug, Copy, Clone, num_enum::IntoPrimitive, num_enum::TryFromPrimitive)]
 #[repr(u8)]
 pub enum Command {
@@ -14,6 +16,51 @@ pub enum Command {
 	/// * Length: 32
 	/// * Mode: RO
 	FirmwareVersion = 0x01,
+	/// # BMC Reset Cause
+	/// Why the NBMC last reset
+	/// * Length: 1
+	/// * Mode: R/W1C
+	BmcResetCause = 0x02,
+	/// # Crash Report
+	/// Where and why the NBMC last crashed
+	/// * Length: 48
+	/// * Mode: R/W
+	CrashReport = 0x03,
+	/// # Event Log
+	/// Things that have happened to the system, oldest first
+	/// * Length: 7
+	/// * Mode: FIFO
+	EventLog = 0x04,
+	/// # Event Log Persist
+	/// Whether the most recent events are kept in flash
+	/// * Length: 1
+	/// * Mode: R/W
+	EventLogPersist = 0x05,
+	/// # Diagnostic Counters
+	/// Counts of the errors the NBMC has seen, as `u16le` values
+	/// * Length: up to 28
+	/// * Mode: R/W
+	DiagnosticCounters = 0x06,
+	/// # POST Code
+	/// The last POST code the host wrote, since it was turned on or reset
+	/// * Length: 1
+	/// * Mode: R/W
+	PostCode = 0x07,
+	/// # Settings Control
+	/// Save, restore or reload the settings kept in flash
+	/// * Length: 1
+	/// * Mode: R/W
+	SettingsControl = 0x08,
+	/// # POST Code Log
+	/// The POST codes from recent boots, oldest first
+	/// * Length: 7
+	/// * Mode: FIFO
+	PostCodeLog = 0x09,
+	/// # Previous POST Code
+	/// The last POST code the host wrote before it was last turned on or reset
+	/// * Length: 1
+	/// * Mode: RO
+	PreviousPostCode = 0x0A,
 	/// # Interrupt Status
 	/// Which interrupts are currently active, as a bitmask.
 	/// * Length: 2
@@ -54,6 +101,42 @@ pub enum Command {
 	/// * Length: 1
 	/// * Mode: R/W
 	PowerControl = 0x25,
+	/// # Power Restore Policy
+	/// What to do when standby power returns
+	/// * Length: 1
+	/// * Mode: R/W
+	PowerRestorePolicy = 0x26,
+	/// # Power Restore Delay
+	/// How long to wait before restoring power, in seconds
+	/// * Length: 1
+	/// * Mode: R/W
+	PowerRestoreDelay = 0x27,
+	/// # Power Restore Random Delay
+	/// The most extra time to wait before restoring power, in seconds
+	/// * Length: 1
+	/// * Mode: R/W
+	PowerRestoreRandomDelay = 0x28,
+	/// # Wake Sources
+	/// What else, apart from the power button, can turn the system on
+	/// * Length: 1
+	/// * Mode: R/W
+	WakeSources = 0x29,
+	/// # Power LED Pattern
+	/// What the power LED shows, as a step length (or breathing) and a `u32le`
+	/// bit pattern
+	/// * Length: 5
+	/// * Mode: R/W
+	PowerLedPattern = 0x2B,
+	/// # Host Reset Control
+	/// Holds the host in reset, releases it, or pulses its reset line
+	/// * Length: 1
+	/// * Mode: R/W
+	HostResetControl = 0x2C,
+	/// # Boot Reason
+	/// Why the host was last turned on, and why it was last reset since then
+	/// * Length: 1
+	/// * Mode: RO
+	BootReason = 0x2D,
 	/// # UART Receive/Transmit Buffer
 	/// Data received/to be sent over the UART
 	/// * Length: up to 64
@@ -154,4 +237,165 @@ pub enum Command {
 	/// * Length: 1
 	/// * Mode: R/W
 	SpeakerDutyCycle = 0x73,
+	/// # Speaker Note Queue
+	/// Notes to play one after the other, as (period, duty cycle, duration)
+	/// tuples
+	/// * Length: up to 60, in multiples of 5
+	/// * Mode: WO
+	SpeakerNoteQueue = 0x74,
+	/// # Speaker Queue Control
+	/// How many notes are queued, and stops the speaker
+	/// * Length: 1
+	/// * Mode: R/W
+	SpeakerQueueControl = 0x75,
+	/// # Speaker Tone Frequency
+	/// Frequency of note, in Hz, as a `u16le`
+	/// * Length: 2
+	/// * Mode: R/W
+	SpeakerFrequency = 0x76,
+	/// # Speaker MIDI Note
+	/// Frequency of note, as a MIDI note number (60 = middle C)
+	/// * Length: 1
+	/// * Mode: R/W
+	SpeakerMidiNote = 0x77,
+	/// # Speaker Boot Chime
+	/// Tune played when the host is turned on or reset
+	/// * Length: 16
+	/// * Mode: R/W
+	SpeakerBootChime = 0x7D,
+	/// # Speaker Key Click
+	/// Click played for each key press on the keyboard
+	/// * Length: 2
+	/// * Mode: R/W
+	SpeakerKeyClick = 0x7E,
+	/// # Button Poll Interval
+	/// How often the buttons are polled, in milliseconds
+	/// * Length: 1
+	/// * Mode: R/W
+	ButtonPollInterval = 0x80,
+	/// # Power Button Short Press
+	/// How many polls the power button must be held for to be a short press
+	/// * Length: 1
+	/// * Mode: R/W
+	ButtonPowerShortPress = 0x81,
+	/// # Power Button Long Press
+	/// How many polls the power button must be held for to be a long press
+	/// * Length: 1
+	/// * Mode: R/W
+	ButtonPowerLongPress = 0x82,
+	/// # Reset Button Press
+	/// How many polls the reset button must be held for to be a press
+	/// * Length: 1
+	/// * Mode: R/W
+	ButtonResetPress = 0x83,
+	/// # Button Double Press
+	/// How many polls may pass between the two presses of a double-press
+	/// * Length: 1
+	/// * Mode: R/W
+	ButtonDoublePress = 0x84,
+	/// # Power Button Very Long Hold
+	/// How many polls the power button must be held for to be a very long hold
+	/// * Length: 1
+	/// * Mode: R/W
+	ButtonPowerVeryLongHold = 0x85,
+	/// # Reset Button Long Hold
+	/// How many polls the reset button must be held for to be a long hold
+	/// * Length: 1
+	/// * Mode: R/W
+	ButtonResetLongHold = 0x86,
+	/// # Button Gesture Buffer
+	/// Button gestures recognised by the NBMC
+	/// * Length: up to 5
+	/// * Mode: FIFO
+	ButtonGestureBuffer = 0x87,
+	/// # Double Press Action
+	/// What the NBMC does when the power button is double-pressed
+	/// * Length: 1
+	/// * Mode: R/W
+	GestureActionDoublePress = 0x88,
+	/// # Boot Chord Action
+	/// What the NBMC does when both buttons are held as it boots
+	/// * Length: 1
+	/// * Mode: R/W
+	GestureActionBootChord = 0x89,
+	/// # Reset Long Hold Action
+	/// What the NBMC does when the reset button is held for a long time
+	/// * Length: 1
+	/// * Mode: R/W
+	GestureActionResetLongHold = 0x8A,
+	/// # Power Very Long Hold Action
+	/// What the NBMC does when the power button is held for a very long time
+	/// * Length: 1
+	/// * Mode: R/W
+	GestureActionPowerVeryLongHold = 0x8B,
+	/// # Host Watchdog Timeout
+	/// How long the host may go without kicking the watchdog, in seconds (0 = disabled)
+	/// * Length: 1
+	/// * Mode: R/W
+	HostWatchdogTimeout = 0x90,
+	/// # Host Watchdog Kick
+	/// Write any value to tell the NBMC the host is still alive
+	/// * Length: 1
+	/// * Mode: WO
+	HostWatchdogKick = 0x91,
+	/// # Host Watchdog Action
+	/// What the NBMC does when the host watchdog fires
+	/// * Length: 1
+	/// * Mode: R/W
+	HostWatchdogAction = 0x92,
+	/// # Host Watchdog Count
+	/// How many times the host watchdog has fired
+	/// * Length: 1
+	/// * Mode: RO
+	HostWatchdogCount = 0x93,
+	/// # Host Watchdog Reason
+	/// Why the host watchdog last fired
+	/// * Length: 1
+	/// * Mode: RO
+	HostWatchdogReason = 0x94,
+	/// # Host Boot Timeout
+	/// How long the host has to boot, in seconds (0 = no limit)
+	/// * Length: 1
+	/// * Mode: R/W
+	HostBootTimeout = 0x95,
+	/// # Host Boot Retries
+	/// How many times a failed boot is retried
+	/// * Length: 1
+	/// * Mode: R/W
+	HostBootRetries = 0x96,
+	/// # RTC Date/Time
+	/// The current date and time, as `[year - 2000, month, day, hours, minutes, seconds]`
+	/// * Length: 6
+	/// * Mode: R/W
+	RtcDateTime = 0xA0,
+	/// # RTC Alarm
+	/// When the alarm goes off, in the same format as *RTC Date/Time*
+	/// * Length: 6
+	/// * Mode: R/W
+	RtcAlarm = 0xA1,
+	/// # RTC Alarm Action
+	/// What happens when the alarm goes off, and whether it has
+	/// * Length: 1
+	/// * Mode: R/W
+	RtcAlarmAction = 0xA2,
+	/// # RTC Frequency
+	/// How fast the RTC's clock really runs, in millihertz, as a `u32le`
+	/// * Length: 4
+	/// * Mode: R/W
+	RtcFrequency = 0xA3,
+	/// # Firmware Update Control
+	/// Starts, finishes and reports on a firmware update
+	/// * Length: 1
+	/// * Mode: R/W
+	FirmwareUpdateControl = 0xF0,
+	/// # Firmware Update Header
+	/// The length and CRC-32 of a new firmware image, as `u32le` values
+	/// * Length: 8
+	/// * Mode: WO
+	FirmwareUpdateHeader = 0xF1,
+	/// # Firmware Update Data
+	/// The next part of a new firmware image
+	/// * Length: up to 255
+	/// * Mode: WO
+	FirmwareUpdateData = 0xF2,
 }
@@ -0,0 +1,372 @@
+//! # RTTTL Parser
+//!
+//! Turns a tune in the Ring Tone Text Transfer Language into notes for the
+//! *Speaker Note Queue*, so you don't have to work out the periods yourself.
+//!
+//! An RTTTL tune has three sections, separated by colons - a name, some
+//! defaults, and a list of notes:
+//!
+//! ```text
+//! scale:d=4,o=5,b=120:c,d,e,f,g,a,b,c6,2p,8c6.
+//! ```
+//!
+//! The defaults are the duration (`d`, where 4 is a quarter note), the octave
+//! (`o`) and the tempo in quarter notes per minute (`b`). Any that are missing
+//! are 4, 6 and 63. Each note is an optional duration, a letter from `a` to `g`
+//! (or `p` for a rest), an optional `#`, an optional `.` (which makes the note
+//! half as long again) and an optional octave. Octaves are numbered so that
+//! `a4` is Concert-pitch A (440 Hz).
+//!
+//! ```
+//! use neotron_bmc_commands::rtttl::Parser;
+//! let mut parser = Parser::new("beep:d=8,o=4,b=120:a,p").unwrap();
+//! let note = parser.next().unwrap().unwrap();
+//! assert_eq!(note.period, 109);
+//! assert_eq!(note.duration_ms, 250);
+//! ```
+
+/// The speaker's tick rate. Note periods are given in these ticks.
+pub const TICK_HZ: u32 = 48_000;
+
+/// The duty cycle we give notes, unless told otherwise (a square wave)
+pub const DEFAULT_DUTY_CYCLE: u8 = 127;
+
+/// The default duration, if the tune doesn't give one (a quarter note)
+const DEFAULT_DURATION: u16 = 4;
+
+/// The default octave, if the tune doesn't give one
+const DEFAULT_OCTAVE: u8 = 6;
+
+/// The default tempo, if the tune doesn't give one, in beats per minute
+const DEFAULT_BPM: u16 = 63;
+
+/// The frequencies of the notes from C4 to B4, in millihertz
+const OCTAVE_4_MHZ: [u32; 12] = [
+	261_626, 277_183, 293_665, 311_127, 329_628, 349_228, 369_994, 391_995, 415_305, 440_000,
+	466_164, 493_883,
+];
+
+/// The ways parsing a tune can fail
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Error {
+	/// The tune doesn't have a name, defaults and notes, separated by colons
+	BadSections,
+	/// One of the defaults wasn't understood
+	BadDefault,
+	/// One of the notes wasn't understood
+	BadNote,
+}
+
+/// One note, ready for the *Speaker Note Queue*.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Note {
+	/// The period, in [`TICK_HZ`] ticks, or zero for a rest
+	pub period: u16,
+	/// The duty cycle (127 = 50%)
+	pub duty_cycle: u8,
+	/// How long the note lasts, in milliseconds
+	pub duration_ms: u16,
+}
+
+/// Parses an RTTTL tune, giving each note in turn.
+#[derive(Debug, Clone)]
+pub struct Parser<'a> {
+	/// The name of the tune
+	name: &'a str,
+	/// The notes we haven't parsed yet
+	notes: core::str::Split<'a, char>,
+	/// The duration of notes which don't give one
+	default_duration: u16,
+	/// The octave of notes which don't give one
+	default_octave: u8,
+	/// The tempo, in beats (quarter notes) per minute
+	bpm: u16,
+	/// The duty cycle we give each note
+	duty_cycle: u8,
+}
+
+impl Note {
+	/// Render the note in the form the *Speaker Note Queue* takes.
+	pub fn as_bytes(&self) -> [u8; 5] {
+		let period = self.period.to_le_bytes();
+		let duration = self.duration_ms.to_le_bytes();
+		[
+			period[0],
+			period[1],
+			self.duty_cycle,
+			duration[0],
+			duration[1],
+		]
+	}
+}
+
+impl<'a> Parser<'a> {
+	/// Start parsing a tune. This checks the name and the defaults, but the
+	/// notes are only checked as you get them.
+	pub fn new(tune: &'a str) -> Result<Parser<'a>, Error> {
+		let mut sections = tune.splitn(3, ':');
+		let name = sections.next().ok_or(Error::BadSections)?;
+		let defaults = sections.next().ok_or(Error::BadSections)?;
+		let notes = sections.next().ok_or(Error::BadSections)?;
+		let mut parser = Parser {
+			name: name.trim(),
+			notes: notes.split(','),
+			default_duration: DEFAULT_DURATION,
+			default_octave: DEFAULT_OCTAVE,
+			bpm: DEFAULT_BPM,
+			duty_cycle: DEFAULT_DUTY_CYCLE,
+		};
+		for default in defaults.split(',').map(str::trim) {
+			if default.is_empty() {
+				continue;
+			}
+			let (key, value) = default.split_once('=').ok_or(Error::BadDefault)?;
+			let value: u16 = value.trim().parse().map_err(|_| Error::BadDefault)?;
+			match (key.trim(), value) {
+				("d", 1..) => parser.default_duration = value,
+				("o", 0..=9) => parser.default_octave = value as u8,
+				("b", 1..) => parser.bpm = value,
+				_ => return Err(Error::BadDefault),
+			}
+		}
+		Ok(parser)
+	}
+
+	/// The name of the tune
+	pub fn name(&self) -> &'a str {
+		self.name
+	}
+
+	/// Change the duty cycle we give each note from now on. Lower values are
+	/// quieter.
+	pub fn set_duty_cycle(&mut self, duty_cycle: u8) {
+		self.duty_cycle = duty_cycle;
+	}
+
+	/// Parse one note, like `8c#.5`.
+	fn parse_note(&self, text: &str) -> Result<Note, Error> {
+		let (divisor, text) = split_number(text);
+		let divisor = match divisor {
+			Some(0) => return Err(Error::BadNote),
+			Some(divisor) => divisor,
+			None => self.default_duration,
+		};
+
+		let mut chars = text.chars().peekable();
+		let semitone = match chars.next().map(|c| c.to_ascii_lowercase()) {
+			Some('c') => Some(0),
+			Some('d') => Some(2),
+			Some('e') => Some(4),
+			Some('f') => Some(5),
+			Some('g') => Some(7),
+			Some('a') => Some(9),
+			Some('b') => Some(11),
+			Some('p') => None,
+			_ => return Err(Error::BadNote),
+		};
+		let sharp = chars.next_if_eq(&'#').is_some();
+		// The dot can come before or after the octave
+		let mut dotted = chars.next_if_eq(&'.').is_some();
+		let octave = match chars.next_if(char::is_ascii_digit) {
+			Some(digit) => digit as u8 - b'0',
+			None => self.default_octave,
+		};
+		dotted |= chars.next_if_eq(&'.').is_some();
+		if chars.next().is_some() {
+			return Err(Error::BadNote);
+		}
+
+		// A whole note is four beats, and a dot makes it half as long again
+		let whole_ms = 4 * 60_000;
+		let (numerator, denominator) = if dotted { (3, 2) } else { (1, 1) };
+		let numerator = whole_ms * numerator;
+		let denominator = u64::from(self.bpm) * u64::from(divisor) * denominator;
+		let duration_ms = (numerator + (denominator / 2)) / denominator;
+		let duration_ms = duration_ms.min(u64::from(u16::MAX)) as u16;
+
+		let Some(semitone) = semitone else {
+			return Ok(Note {
+				period: 0,
+				duty_cycle: 0,
+				duration_ms,
+			});
+		};
+		// B# is the next octave's C
+		let semitone = semitone + usize::from(sharp);
+		let octave = octave + (semitone / 12) as u8;
+		let frequency_mhz = u64::from(OCTAVE_4_MHZ[semitone % 12]) << octave;
+		// The table is for octave 4, so the frequency is 2^4 times too big
+		let ticks = (u64::from(TICK_HZ) * 1000) << 4;
+		let period = (ticks + (frequency_mhz / 2)) / frequency_mhz;
+		Ok(Note {
+			period: period.clamp(1, u64::from(u16::MAX)) as u16,
+			duty_cycle: self.duty_cycle,
+			duration_ms,
+		})
+	}
+}
+
+impl<'a> Iterator for Parser<'a> {
+	type Item = Result<Note, Error>;
+
+	fn next(&mut self) -> Option<Self::Item> {
+		loop {
+			let text = self.notes.next()?.trim();
+			// Skip empty notes, like the one after a trailing comma
+			if !text.is_empty() {
+				return Some(self.parse_note(text));
+			}
+		}
+	}
+}
+
+/// Split any leading decimal number off some text.
+fn split_number(text: &str) -> (Option<u16>, &str) {
+	let digits = text
+		.find(|c: char| !c.is_ascii_digit())
+		.unwrap_or(text.len());
+	let (number, rest) = text.split_at(digits);
+	(number.parse().ok(), rest)
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+
+	fn first_note(tune: &str) -> Result<Note, Error> {
+		Parser::new(tune).unwrap().next().unwrap()
+	}
+
+	#[test]
+	fn name_and_defaults() {
+		let parser = Parser::new(" Scale :d=8,o=5,b=100:c").unwrap();
+		assert_eq!(parser.name(), "Scale");
+		assert_eq!(parser.default_duration, 8);
+		assert_eq!(parser.default_octave, 5);
+		assert_eq!(parser.bpm, 100);
+	}
+
+	#[test]
+	fn missing_defaults() {
+		let parser = Parser::new("x::a").unwrap();
+		assert_eq!(parser.default_duration, DEFAULT_DURATION);
+		assert_eq!(parser.default_octave, DEFAULT_OCTAVE);
+		assert_eq!(parser.bpm, DEFAULT_BPM);
+	}
+
+	#[test]
+	fn bad_header() {
+		assert_eq!(Parser::new("x").unwrap_err(), Error::BadSections);
+		assert_eq!(Parser::new("x:d=4").unwrap_err(), Error::BadSections);
+		assert_eq!(Parser::new("x:q=4:a").unwrap_err(), Error::BadDefault);
+		assert_eq!(Parser::new("x:d=0:a").unwrap_err(), Error::BadDefault);
+		assert_eq!(Parser::new("x:o=10:a").unwrap_err(), Error::BadDefault);
+		assert_eq!(Parser::new("x:b:a").unwrap_err(), Error::BadDefault);
+	}
+
+	#[test]
+	fn tempo() {
+		// A quarter note is one beat
+		assert_eq!(first_note("x:d=4,b=120:a").unwrap().duration_ms, 500);
+		assert_eq!(first_note("x:d=4,b=60:a").unwrap().duration_ms, 1000);
+		assert_eq!(first_note("x:b=120:1a").unwrap().duration_ms, 2000);
+		assert_eq!(first_note("x:b=120:32a").unwrap().duration_ms, 63);
+		// 240000 / 63 / 4 = 952.38
+		assert_eq!(first_note("x::a").unwrap().duration_ms, 952);
+		// Very slow whole notes are as long as we can make them
+		assert_eq!(first_note("x:b=1:1a").unwrap().duration_ms, u16::MAX);
+	}
+
+	#[test]
+	fn dotted_notes() {
+		assert_eq!(first_note("x:b=120:4a.").unwrap().duration_ms, 750);
+		assert_eq!(first_note("x:b=120:4a5.").unwrap().duration_ms, 750);
+		assert_eq!(first_note("x:b=120:4a#.5").unwrap().duration_ms, 750);
+		// 1428.57 rounds up
+		assert_eq!(first_note("x:b=63:4p.").unwrap().duration_ms, 1429);
+	}
+
+	#[test]
+	fn rests() {
+		let note = first_note("x:b=120:8p").unwrap();
+		assert_eq!(
+			note,
+			Note {
+				period: 0,
+				duty_cycle: 0,
+				duration_ms: 250
+			}
+		);
+		assert_eq!(first_note("x:o=4:p5").unwrap().period, 0);
+	}
+
+	#[test]
+	fn octaves() {
+		// Concert-pitch A, as in the Speaker Tone Period docs
+		assert_eq!(first_note("x:o=4:a").unwrap().period, 109);
+		assert_eq!(first_note("x:o=5:a4").unwrap().period, 109);
+		assert_eq!(first_note("x::a5").unwrap().period, 55);
+		assert_eq!(first_note("x::a3").unwrap().period, 218);
+		// Middle C, at 261.626 Hz, is 183.47 ticks
+		assert_eq!(first_note("x::c4").unwrap().period, 183);
+		assert_eq!(first_note("x::c9").unwrap().period, 6);
+		// The default octave
+		assert_eq!(first_note("x::c").unwrap().period, 46);
+	}
+
+	#[test]
+	fn sharps() {
+		// 277.183 Hz
+		assert_eq!(first_note("x::c#4").unwrap().period, 173);
+		// F is E#, and C5 is B#4
+		assert_eq!(
+			first_note("x::e#4").unwrap().period,
+			first_note("x::f4").unwrap().period
+		);
+		assert_eq!(
+			first_note("x::b#4").unwrap().period,
+			first_note("x::c5").unwrap().period
+		);
+	}
+
+	#[test]
+	fn upper_case_and_spaces() {
+		let mut parser = Parser::new("x:d=4, o=4 ,b=120: A , 8C# ,").unwrap();
+		assert_eq!(parser.next().unwrap().unwrap().period, 109);
+		let note = parser.next().unwrap().unwrap();
+		assert_eq!(note.period, 173);
+		assert_eq!(note.duration_ms, 250);
+		assert_eq!(parser.next(), None);
+	}
+
+	#[test]
+	fn bad_notes() {
+		assert_eq!(first_note("x::h"), Err(Error::BadNote));
+		assert_eq!(first_note("x::0a"), Err(Error::BadNote));
+		assert_eq!(first_note("x::a55"), Err(Error::BadNote));
+		assert_eq!(first_note("x::4"), Err(Error::BadNote));
+		// Later notes still parse
+		let mut parser = Parser::new("x::z,a").unwrap();
+		assert_eq!(parser.next(), Some(Err(Error::BadNote)));
+		assert!(parser.next().unwrap().is_ok());
+		assert_eq!(parser.next(), None);
+	}
+
+	#[test]
+	fn duty_cycle() {
+		let mut parser = Parser::new("x::a,b").unwrap();
+		assert_eq!(parser.next().unwrap().unwrap().duty_cycle, 127);
+		parser.set_duty_cycle(10);
+		assert_eq!(parser.next().unwrap().unwrap().duty_cycle, 10);
+	}
+
+	#[test]
+	fn note_bytes() {
+		let note = Note {
+			period: 0x1234,
+			duty_cycle: 127,
+			duration_ms: 0x0102,
+		};
+		assert_eq!(note.as_bytes(), [0x34, 0x12, 127, 0x02, 0x01]);
+	}
+}
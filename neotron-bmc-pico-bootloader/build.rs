@@ -0,0 +1,18 @@
+/// This is the build-script for the Neotron BMC bootloader.
+///
+/// It just copies the memory.x file somewhere Cargo can find it.
+use std::env;
+use std::fs::File;
+use std::io::Write;
+use std::path::PathBuf;
+
+fn main() {
+	// Put the linker script somewhere the linker can find it
+	let out = &PathBuf::from(env::var_os("OUT_DIR").unwrap());
+	File::create(out.join("memory.x"))
+		.unwrap()
+		.write_all(include_bytes!("memory.x"))
+		.unwrap();
+	println!("cargo:rustc-link-search={}", out.display());
+	println!("cargo:rerun-if-changed=memory.x");
+}
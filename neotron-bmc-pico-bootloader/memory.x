@@ -0,0 +1,16 @@
+MEMORY
+{
+  /* NOTE K = KiBi = 1024 bytes */
+  /* The bootloader lives in the first 4K of the 32K flash. The application
+   * follows it, and the last 2K is kept back for the application's settings. */
+  FLASH : ORIGIN = 0x08000000, LENGTH = 4K
+  /* The first 256 bytes of RAM are where the application keeps its vector
+   * table, a crash report that must survive us running, and a note asking us
+   * to stay in the bootloader. */
+  RAM : ORIGIN = 0x20000100, LENGTH = 4K - 256
+}
+
+/* This is where the call stack will be allocated. */
+/* The stack is of the full descending type. */
+/* NOTE Do NOT modify `_stack_start` unless you know what you are doing */
+_stack_start = ORIGIN(RAM) + LENGTH(RAM);
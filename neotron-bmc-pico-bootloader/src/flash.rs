@@ -0,0 +1,113 @@
+//! # Flash Programming Driver for STM32F0
+//!
+//! A cut-down copy of the application's flash driver. Note that the CPU stalls
+//! whilst the flash is busy, as we execute from the same flash bank.
+
+use stm32f0xx_hal::pac;
+
+/// The size of one erasable page of flash on the STM32F030x6.
+pub const PAGE_SIZE: usize = 1024;
+
+/// Flash programming failed.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Error;
+
+/// Lets us erase and program the internal flash.
+pub struct Flash {
+	/// Our PAC object for register access
+	dev: pac::FLASH,
+}
+
+impl Flash {
+	/// Unlock key 1, from the Reference Manual
+	const KEY1: u32 = 0x4567_0123;
+	/// Unlock key 2, from the Reference Manual
+	const KEY2: u32 = 0xCDEF_89AB;
+
+	/// Construct a new driver
+	pub fn new(dev: pac::FLASH) -> Flash {
+		Flash { dev }
+	}
+
+	/// Erase the page which starts at the given address, and program it with
+	/// the given data.
+	///
+	/// The flash is programmed 16-bits at a time, so the length must be even.
+	/// The caller must make sure the page is in the application area.
+	pub fn write_page(&mut self, address: usize, data: &[u8]) -> Result<(), Error> {
+		self.erase_page(address)?;
+		self.unlock();
+		self.dev.cr.modify(|_r, w| w.pg().set_bit());
+		let mut result = Ok(());
+		for (idx, pair) in data.chunks_exact(2).enumerate() {
+			let half_word = u16::from_le_bytes([pair[0], pair[1]]);
+			let ptr = (address + (idx * 2)) as *mut u16;
+			// Safety: the caller checked the address, and the flash controller
+			// is in programming mode.
+			unsafe { ptr.write_volatile(half_word) };
+			result = self.wait();
+			if result.is_err() {
+				break;
+			}
+		}
+		self.dev.cr.modify(|_r, w| w.pg().clear_bit());
+		self.lock();
+		result?;
+		// Check it all reads back correctly
+		if read(address, data.len()) == data {
+			Ok(())
+		} else {
+			Err(Error)
+		}
+	}
+
+	/// Erase the page which starts at the given address.
+	///
+	/// The caller must make sure the page is in the application area.
+	pub fn erase_page(&mut self, address: usize) -> Result<(), Error> {
+		self.unlock();
+		self.dev.cr.modify(|_r, w| w.per().set_bit());
+		self.dev.ar.write(|w| w.far().bits(address as u32));
+		self.dev.cr.modify(|_r, w| w.strt().set_bit());
+		let result = self.wait();
+		self.dev.cr.modify(|_r, w| w.per().clear_bit());
+		self.lock();
+		result
+	}
+
+	/// Unlock the flash controller so we can erase and program.
+	fn unlock(&mut self) {
+		if self.dev.cr.read().lock().is_locked() {
+			self.dev.keyr.write(|w| w.fkeyr().bits(Self::KEY1));
+			self.dev.keyr.write(|w| w.fkeyr().bits(Self::KEY2));
+		}
+	}
+
+	/// Lock the flash controller again.
+	fn lock(&mut self) {
+		self.dev.cr.modify(|_r, w| w.lock().set_bit());
+	}
+
+	/// Wait for the current operation to finish, and check how it went.
+	fn wait(&mut self) -> Result<(), Error> {
+		while self.dev.sr.read().bsy().bit_is_set() {
+			// Spin
+		}
+		let sr = self.dev.sr.read();
+		// Clear all the flags (they are write-1-to-clear)
+		self.dev
+			.sr
+			.write(|w| w.eop().set_bit().pgerr().set_bit().wrprt().set_bit());
+		if sr.pgerr().bit_is_set() || sr.wrprt().bit_is_set() {
+			Err(Error)
+		} else {
+			Ok(())
+		}
+	}
+}
+
+/// Get a slice of flash.
+pub fn read(address: usize, length: usize) -> &'static [u8] {
+	// Safety: flash is always readable, and the caller only asks for flash
+	unsafe { core::slice::from_raw_parts(address as *const u8, length) }
+}
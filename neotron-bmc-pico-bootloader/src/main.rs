@@ -0,0 +1,390 @@
+//! Neotron BMC Bootloader
+//!
+//! This lives in the first 4 KiB of flash on the Neotron Pico's BMC. It starts
+//! the BMC firmware (the application) if there is a valid one, and otherwise
+//! lets the host send a new application over SPI, or lets someone send one
+//! over the UART with XMODEM. For more details, see the `README.md` file.
+//!
+//! # Licence
+//! This source code as a whole is licensed under the GPL v3. Third-party crates
+//! are covered by their respective licences.
+
+#![no_main]
+#![no_std]
+
+use core::convert::TryFrom;
+
+use cortex_m_rt::entry;
+use stm32f0xx_hal::pac;
+
+use neotron_bmc_commands::Command;
+use neotron_bmc_protocol::{RequestType, ResponseResult};
+
+mod flash;
+mod spi;
+mod uart;
+mod update;
+mod xmodem;
+
+use flash::PAGE_SIZE;
+use update::{Update, APP_START};
+
+/// Our version string, for the Firmware Version register.
+static VERSION: [u8; 32] = pad_version(concat!("bootloader-v", env!("CARGO_PKG_VERSION")));
+
+/// The application leaves a note here when it wants us to take an update.
+///
+/// This is in the RAM that both we and the application keep back.
+const BOOT_REQUEST: *mut u32 = 0x2000_00FC as *mut u32;
+
+/// The note that asks us to take an update
+const BOOT_REQUEST_MAGIC: u32 = 0xB007_10AD;
+
+/// Firmware Update Control value: check and commit the image
+const CONTROL_FINISH: u8 = 2;
+
+/// Firmware Update Control value: reboot the BMC
+const CONTROL_REBOOT: u8 = 3;
+
+/// The registers we support. We don't have room for `Command::try_from`.
+const PROTOCOL_VERSION: u8 = Command::ProtocolVersion as u8;
+const FIRMWARE_VERSION: u8 = Command::FirmwareVersion as u8;
+const FIRMWARE_UPDATE_CONTROL: u8 = Command::FirmwareUpdateControl as u8;
+const FIRMWARE_UPDATE_HEADER: u8 = Command::FirmwareUpdateHeader as u8;
+const FIRMWARE_UPDATE_DATA: u8 = Command::FirmwareUpdateData as u8;
+
+/// Length of a reset pulse, in 48 MHz clock cycles (so, 250 ms)
+const RESET_DURATION_CYCLES: u32 = 12_000_000;
+
+/// How many times we check the reset button and UART at start-up, one
+/// millisecond apart, before we believe them.
+const HOLD_CHECKS: u32 = 50;
+
+/// One millisecond, in 8 MHz clock cycles (as we start on the HSI)
+const HOLD_CHECK_CYCLES: u32 = 8_000;
+
+/// One millisecond, in 48 MHz clock cycles
+const TICK_CYCLES: u32 = 48_000;
+
+/// Space for the image we're being sent. This is in `.bss`, so it costs us no
+/// flash.
+static mut PAGES: [[u8; PAGE_SIZE]; 2] = [[0; PAGE_SIZE]; 2];
+
+/// Space for a Long Write Payload, and its CRC
+static mut PAYLOAD: [u8; 256] = [0; 256];
+
+/// Space for an XMODEM packet
+static mut PACKET: [u8; xmodem::PACKET_LEN] = [0; xmodem::PACKET_LEN];
+
+#[entry]
+fn main() -> ! {
+	// Safety: nothing else is running, and this word of RAM is kept back for
+	// the note.
+	let requested = unsafe {
+		let requested = BOOT_REQUEST.read_volatile() == BOOT_REQUEST_MAGIC;
+		BOOT_REQUEST.write_volatile(0);
+		requested
+	};
+
+	// Safety: this is the only place we take the peripherals, and the
+	// application (if it jumped here) has stopped using them.
+	let dp = unsafe { pac::Peripherals::steal() };
+	let mut cp = unsafe { pac::CorePeripherals::steal() };
+
+	let app_valid = update::can_start();
+	if !requested && app_valid && !is_held(&dp.RCC, &dp.GPIOA, &dp.GPIOF) {
+		// Safety: there's an application there, and nothing has been set up
+		// that it won't set up again for itself.
+		unsafe { cortex_m::asm::bootload(APP_START as *const u32) }
+	}
+
+	// If the application jumped here, its vector table is still mapped at
+	// address zero. Put ours back.
+	dp.RCC.apb2enr.modify(|_r, w| w.syscfgen().enabled());
+	dp.SYSCFG.cfgr1.modify(|_r, w| w.mem_mode().main_flash());
+
+	set_clocks(&dp.RCC, &dp.FLASH);
+
+	if !requested && !app_valid {
+		// The last update didn't finish, so the host is off. Turn it on so it
+		// can have another go.
+		power_on_host(&dp.RCC, &dp.GPIOA);
+	}
+
+	let mut spi = spi::SpiPeripheral::new(dp.SPI1, &dp.RCC, &dp.GPIOA);
+	let mut uart = uart::Uart::new(dp.USART1, &dp.RCC, &dp.GPIOA);
+	let mut flash = flash::Flash::new(dp.FLASH);
+	// Safety: this is the only place we use the pages
+	let mut update = Update::new(unsafe { &mut *core::ptr::addr_of_mut!(PAGES) });
+	// Safety: this is the only place we use the packet
+	let mut xmodem = xmodem::Receiver::new(unsafe { &mut *core::ptr::addr_of_mut!(PACKET) });
+	let mut last_req = [0u8; 4];
+
+	uart.write(b"\r\nNeotron BMC bootloader - send firmware with XMODEM-CRC\r\n");
+
+	// A millisecond tick, for the XMODEM time-outs
+	cp.SYST
+		.set_clock_source(cortex_m::peripheral::syst::SystClkSource::Core);
+	cp.SYST.set_reload(TICK_CYCLES - 1);
+	cp.SYST.clear_current();
+	cp.SYST.enable_counter();
+
+	loop {
+		// The application may have started the independent watchdog, and
+		// once started it can't be stopped.
+		dp.IWDG.kr.write(|w| w.key().reset());
+		if cp.SYST.has_wrapped() {
+			xmodem.tick(&mut uart);
+		}
+		if let Some(byte) = uart.read() {
+			if xmodem.byte(byte, &mut uart, &mut update, &mut flash) {
+				if update.status() == update::Status::Complete {
+					uart.write(b"\r\nUpdate complete - rebooting\r\n");
+					uart.flush();
+					cortex_m::peripheral::SCB::sys_reset();
+				}
+				uart.write(b"\r\nUpdate failed - please try again\r\n");
+			}
+		}
+		if spi.is_selected() {
+			spi.start();
+			let reboot = handle_request(&mut spi, &mut flash, &mut update, &mut last_req);
+			spi.wait_for_deselect();
+			spi.stop();
+			// Show how an update from the host is going, a dot per page
+			if let Some(status) = update.take_progress() {
+				let message: &[u8] = match status {
+					update::Status::Receiving => b".",
+					update::Status::Complete => b"\r\nUpdate complete\r\n",
+					_ => b"\r\nUpdate failed - please try again\r\n",
+				};
+				uart.write(message);
+			}
+			if reboot {
+				cortex_m::peripheral::SCB::sys_reset();
+			}
+		}
+	}
+}
+
+/// Run at 48 MHz, from the HSI via the PLL, like the application does.
+///
+/// If the application jumped here, it's already done this for us.
+fn set_clocks(rcc: &pac::RCC, flash: &pac::FLASH) {
+	if rcc.cfgr.read().sws().is_pll() {
+		return;
+	}
+	flash.acr.modify(|_r, w| {
+		w.prftbe().enabled();
+		w.latency().ws1()
+	});
+	// 8 MHz / 2 * 12 = 48 MHz
+	rcc.cfgr.modify(|_r, w| {
+		w.pllsrc().hsi_div2();
+		w.pllmul().mul12()
+	});
+	rcc.cr.modify(|_r, w| w.pllon().on());
+	while rcc.cr.read().pllrdy().is_not_ready() {}
+	rcc.cfgr.modify(|_r, w| w.sw().pll());
+	while !rcc.cfgr.read().sws().is_pll() {}
+}
+
+/// Is someone asking for the bootloader, by holding the reset button (but not
+/// the power button), or by sending a break on the UART, as the BMC starts?
+///
+/// The ports are put back as they were, in case we start the application.
+fn is_held(rcc: &pac::RCC, gpioa: &pac::GPIOA, gpiof: &pac::GPIOF) -> bool {
+	rcc.ahbenr.modify(|_r, w| {
+		w.iopaen().enabled();
+		w.iopfen().enabled()
+	});
+	// The buttons (PF0 and PF1) are active low
+	gpiof.pupdr.modify(|_r, w| {
+		w.pupdr0().pull_up();
+		w.pupdr1().pull_up()
+	});
+	let mut held = true;
+	for _ in 0..HOLD_CHECKS {
+		cortex_m::asm::delay(HOLD_CHECK_CYCLES);
+		let idr = gpiof.idr.read();
+		let reset_only = idr.idr0().is_high() && idr.idr1().is_low();
+		held &= reset_only || uart::is_break(gpioa);
+	}
+	rcc.ahbrstr.modify(|_r, w| {
+		w.ioparst().reset();
+		w.iopfrst().reset()
+	});
+	rcc.ahbrstr.modify(|_r, w| {
+		w.ioparst().clear_bit();
+		w.iopfrst().clear_bit()
+	});
+	rcc.ahbenr.modify(|_r, w| {
+		w.iopaen().disabled();
+		w.iopfen().disabled()
+	});
+	held
+}
+
+/// Turn the host on, so it can send us a new application.
+fn power_on_host(rcc: &pac::RCC, gpioa: &pac::GPIOA) {
+	rcc.ahbenr.modify(|_r, w| w.iopaen().enabled());
+	// Hold the host in reset (PA2) and turn the PSU on (PA3)
+	gpioa.bsrr.write(|w| {
+		w.br2().reset();
+		w.bs3().set()
+	});
+	gpioa.moder.modify(|_r, w| {
+		w.moder2().output();
+		w.moder3().output()
+	});
+	cortex_m::asm::delay(RESET_DURATION_CYCLES);
+	// Take the host out of reset
+	gpioa.bsrr.write(|w| w.bs2().set());
+}
+
+/// Handle one request from the host.
+///
+/// Returns `true` if we should reboot once the host has let go of us.
+fn handle_request(
+	spi: &mut spi::SpiPeripheral,
+	flash: &mut flash::Flash,
+	update: &mut Update,
+	last_req: &mut [u8; 4],
+) -> bool {
+	let mut req = [0u8; 4];
+	if !spi.receive(&mut req) {
+		return false;
+	}
+	if crc8(&req) != 0 {
+		respond(spi, ResponseResult::CrcFailure, &[]);
+		return false;
+	}
+	let request_type = match RequestType::try_from(req[0]) {
+		Ok(request_type) => request_type.flatten(),
+		Err(_) => {
+			respond(spi, ResponseResult::BadRequestType, &[]);
+			return false;
+		}
+	};
+
+	// Like the application, a retried write is acknowledged but not repeated
+	let is_retry = *last_req == req;
+	*last_req = [0; 4];
+
+	let register = req[1];
+	let length = usize::from(req[2]);
+	match (request_type, register) {
+		(RequestType::Read, PROTOCOL_VERSION) if length == 3 => {
+			respond(spi, ResponseResult::Ok, &[0, 1, 1]);
+		}
+		(RequestType::Read, FIRMWARE_VERSION) if length <= VERSION.len() => {
+			respond(spi, ResponseResult::Ok, &VERSION[0..length]);
+		}
+		(RequestType::Read, FIRMWARE_UPDATE_CONTROL) if length == 1 => {
+			respond(spi, ResponseResult::Ok, &[update.status() as u8]);
+		}
+		(RequestType::Read, PROTOCOL_VERSION | FIRMWARE_VERSION | FIRMWARE_UPDATE_CONTROL) => {
+			respond(spi, ResponseResult::BadLength, &[]);
+		}
+		(RequestType::ShortWrite, FIRMWARE_UPDATE_CONTROL) => {
+			respond(spi, ResponseResult::Ok, &[]);
+			match req[2] {
+				CONTROL_FINISH if !is_retry => update.finish(flash),
+				CONTROL_REBOOT => return true,
+				_ => {
+					// Ignore
+				}
+			}
+			*last_req = req;
+		}
+		(RequestType::LongWrite, FIRMWARE_UPDATE_HEADER | FIRMWARE_UPDATE_DATA) => {
+			let is_header = register == FIRMWARE_UPDATE_HEADER;
+			let acceptable = if is_header {
+				length == 8
+			} else {
+				is_retry || update.will_accept(length)
+			};
+			if !acceptable {
+				respond(spi, ResponseResult::BadLength, &[]);
+				return false;
+			}
+			if !respond(spi, ResponseResult::Ok, &[]) {
+				return false;
+			}
+			// Get the payload, and its CRC
+			// Safety: this is the only place we use the buffer
+			let payload = unsafe { &mut *core::ptr::addr_of_mut!(PAYLOAD) };
+			let payload = &mut payload[0..=length];
+			if !spi.receive(payload) {
+				return false;
+			}
+			if crc8(payload) != 0 {
+				respond(spi, ResponseResult::CrcFailure, &[]);
+				return false;
+			}
+			if !is_retry {
+				// This can take a while, but the host is happy to wait for
+				// our response.
+				let data = &payload[0..length];
+				if is_header {
+					update.start(data, flash);
+				} else {
+					update.write(data, flash);
+				}
+			}
+			*last_req = req;
+			respond(spi, ResponseResult::Ok, &[]);
+		}
+		_ => {
+			respond(spi, ResponseResult::BadRegister, &[]);
+		}
+	}
+	false
+}
+
+/// Send a response, with some optional data.
+fn respond(spi: &mut spi::SpiPeripheral, result: ResponseResult, data: &[u8]) -> bool {
+	let result = [result as u8];
+	let crc = [crc8_update(crc8(&result), data)];
+	spi.transmit(&result) && spi.transmit(data) && spi.transmit(&crc)
+}
+
+/// Calculate the CRC-8 used by the protocol.
+///
+/// The protocol crate uses a table, which we don't have room for.
+fn crc8(bytes: &[u8]) -> u8 {
+	crc8_update(0, bytes)
+}
+
+/// Add some bytes to a CRC-8.
+fn crc8_update(mut crc: u8, bytes: &[u8]) -> u8 {
+	for byte in bytes {
+		crc ^= *byte;
+		for _ in 0..8 {
+			crc = if crc & 0x80 != 0 {
+				(crc << 1) ^ 0x07
+			} else {
+				crc << 1
+			};
+		}
+	}
+	crc
+}
+
+/// Put the version string in a null-padded buffer.
+const fn pad_version(version: &str) -> [u8; 32] {
+	let version = version.as_bytes();
+	let mut padded = [0u8; 32];
+	let mut idx = 0;
+	while idx < version.len() {
+		padded[idx] = version[idx];
+		idx += 1;
+	}
+	padded
+}
+
+/// Called if we panic. There's nobody to tell, so just start again.
+#[panic_handler]
+fn panic(_info: &core::panic::PanicInfo) -> ! {
+	cortex_m::peripheral::SCB::sys_reset();
+}
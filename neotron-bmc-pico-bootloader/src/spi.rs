@@ -0,0 +1,181 @@
+//! # Polled SPI Peripheral Driver for STM32
+//!
+//! The bootloader doesn't use interrupts, so unlike the application's driver
+//! this one just spins on the status register.
+//!
+//! Every byte the host clocks in clocks one of our bytes out. We keep the
+//! transmit FIFO topped up with padding, and count the bytes we load into it
+//! and the bytes we take out of the receive FIFO. That tells us which of the
+//! bytes we receive were sent at the same time as our response (and so are
+//! junk), and which were sent after it (and so are a Long Write Payload).
+
+use stm32f0xx_hal::pac;
+
+/// What we send when we have nothing else to send
+const PADDING: u8 = 0xFF;
+
+/// The chip select line is on PA4
+const CS_PIN: u32 = 4;
+
+/// Talks to the host over SPI1.
+pub struct SpiPeripheral {
+	/// Our PAC object for register access
+	dev: pac::SPI1,
+	/// How many bytes we've loaded into the transmit FIFO
+	tx_count: u32,
+	/// How many bytes we've taken from the receive FIFO
+	rx_count: u32,
+	/// The received bytes before this one are junk
+	rx_skip: u32,
+}
+
+impl SpiPeripheral {
+	/// Set up SPI1 and its pins.
+	pub fn new(dev: pac::SPI1, rcc: &pac::RCC, gpioa: &pac::GPIOA) -> SpiPeripheral {
+		rcc.ahbenr.modify(|_r, w| w.iopaen().enabled());
+		rcc.apb2enr.modify(|_r, w| w.spi1en().enabled());
+		// CS is an input with a pull-down; SCK, CIPO and COPI are AF0, with
+		// CIPO in high speed mode.
+		gpioa.pupdr.modify(|_r, w| w.pupdr4().pull_down());
+		gpioa.ospeedr.modify(|_r, w| w.ospeedr6().high_speed());
+		gpioa.moder.modify(|_r, w| {
+			w.moder4().input();
+			w.moder5().alternate();
+			w.moder6().alternate();
+			w.moder7().alternate()
+		});
+		gpioa.afrl.modify(|_r, w| {
+			w.afrl5().af0();
+			w.afrl6().af0();
+			w.afrl7().af0()
+		});
+		let mut spi = SpiPeripheral {
+			dev,
+			tx_count: 0,
+			rx_count: 0,
+			rx_skip: 0,
+		};
+		spi.stop();
+		spi
+	}
+
+	/// Is the host selecting us?
+	pub fn is_selected(&self) -> bool {
+		// Safety: this is an atomic read of a register we don't write to
+		let gpioa = unsafe { &*pac::GPIOA::ptr() };
+		gpioa.idr.read().bits() & (1 << CS_PIN) == 0
+	}
+
+	/// Enable the SPI peripheral (i.e. when CS goes low).
+	pub fn start(&mut self) {
+		self.tx_count = 0;
+		self.rx_count = 0;
+		self.rx_skip = 0;
+		self.dev.cr1.modify(|_r, w| {
+			w.ssi().slave_selected();
+			w.spe().enabled()
+		});
+		self.pad();
+	}
+
+	/// Reset the SPI peripheral (i.e. when CS goes high).
+	///
+	/// This is the only way to empty the transmit FIFO.
+	pub fn stop(&mut self) {
+		// Safety: the RCC reset register is only touched here
+		let rcc = unsafe { &*pac::RCC::ptr() };
+		rcc.apb2rstr.modify(|_r, w| w.spi1rst().reset());
+		rcc.apb2rstr.modify(|_r, w| w.spi1rst().clear_bit());
+		// Same settings as the application - see its `spi.rs`
+		self.dev.cr1.write(|w| {
+			w.cpha().first_edge();
+			w.cpol().idle_low();
+			w.ssm().enabled();
+			w.ssi().slave_not_selected();
+			w.mstr().slave()
+		});
+		self.dev.cr2.write(|w| {
+			unsafe { w.ds().bits(0b111) };
+			w.frxth().quarter()
+		});
+	}
+
+	/// Receive some bytes, sending padding in return.
+	///
+	/// Returns `false` if the host gave up (raised CS) first.
+	pub fn receive(&mut self, buffer: &mut [u8]) -> bool {
+		let mut idx = 0;
+		while idx < buffer.len() {
+			if !self.is_selected() {
+				return false;
+			}
+			self.pad();
+			if let Some(byte) = self.raw_read() {
+				if self.rx_count > self.rx_skip {
+					buffer[idx] = byte;
+					idx += 1;
+				}
+			}
+		}
+		true
+	}
+
+	/// Send some bytes, throwing away whatever is received at the same time.
+	///
+	/// Returns `false` if the host gave up (raised CS) first.
+	pub fn transmit(&mut self, bytes: &[u8]) -> bool {
+		for byte in bytes {
+			loop {
+				if !self.is_selected() {
+					return false;
+				}
+				let _ = self.raw_read();
+				if self.dev.sr.read().txe().is_empty() {
+					break;
+				}
+			}
+			self.raw_write(*byte);
+		}
+		// Anything received up to and including the last byte of our
+		// message is junk.
+		self.rx_skip = self.tx_count;
+		true
+	}
+
+	/// Keep sending padding until the host raises CS.
+	pub fn wait_for_deselect(&mut self) {
+		while self.is_selected() {
+			self.pad();
+			let _ = self.raw_read();
+		}
+	}
+
+	/// Top up the transmit FIFO with padding.
+	fn pad(&mut self) {
+		while self.dev.sr.read().txe().is_empty() {
+			self.raw_write(PADDING);
+		}
+	}
+
+	/// Read a byte from the receive FIFO, if there is one.
+	fn raw_read(&mut self) -> Option<u8> {
+		if self.dev.sr.read().rxne().is_not_empty() {
+			// Force an 8-bit read, or we pop two bytes.
+			let spi_dr_ptr = self.dev.dr.as_ptr() as *const u8;
+			self.rx_count = self.rx_count.wrapping_add(1);
+			// Safety: we own the SPI device
+			Some(unsafe { spi_dr_ptr.read_volatile() })
+		} else {
+			None
+		}
+	}
+
+	/// Load a byte into the transmit FIFO.
+	fn raw_write(&mut self, data: u8) {
+		// Force an 8-bit write, or we push two bytes.
+		let spi_dr_ptr = self.dev.dr.as_ptr() as *mut u8;
+		self.tx_count = self.tx_count.wrapping_add(1);
+		// Safety: we own the SPI device
+		unsafe { spi_dr_ptr.write_volatile(data) };
+	}
+}
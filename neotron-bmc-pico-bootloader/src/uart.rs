@@ -0,0 +1,77 @@
+//! # Polled UART Driver for STM32
+//!
+//! Talks over USART1, which is wired to the FTDI header on the Neotron Pico.
+
+use stm32f0xx_hal::pac;
+
+/// The baud rate, which matches the application's default
+const BAUD_RATE: u32 = 115_200;
+
+/// Our peripheral clock, in Hz
+const PCLK: u32 = 48_000_000;
+
+/// Talks over USART1.
+pub struct Uart {
+	/// Our PAC object for register access
+	dev: pac::USART1,
+}
+
+impl Uart {
+	/// Set up USART1 and its pins, at 115200 8N1.
+	pub fn new(dev: pac::USART1, rcc: &pac::RCC, gpioa: &pac::GPIOA) -> Uart {
+		rcc.ahbenr.modify(|_r, w| w.iopaen().enabled());
+		rcc.apb2enr.modify(|_r, w| w.usart1en().enabled());
+		// TX (PA9) and RX (PA10) are AF1
+		gpioa.moder.modify(|_r, w| {
+			w.moder9().alternate();
+			w.moder10().alternate()
+		});
+		gpioa.afrh.modify(|_r, w| {
+			w.afrh9().af1();
+			w.afrh10().af1()
+		});
+		dev.brr.write(|w| unsafe { w.bits(PCLK / BAUD_RATE) });
+		// If we miss a byte the XMODEM block will be re-sent, so we don't want
+		// an overrun to stop reception.
+		dev.cr3.write(|w| w.ovrdis().disabled());
+		dev.cr1.write(|w| {
+			w.te().enabled();
+			w.re().enabled();
+			w.ue().enabled()
+		});
+		Uart { dev }
+	}
+
+	/// Get a byte, if one has arrived.
+	pub fn read(&mut self) -> Option<u8> {
+		let isr = self.dev.isr.read();
+		// Clear any framing or noise errors, so reception carries on
+		self.dev.icr.write(|w| w.fecf().clear().ncf().clear());
+		if isr.rxne().bit_is_set() {
+			Some(self.dev.rdr.read().rdr().bits() as u8)
+		} else {
+			None
+		}
+	}
+
+	/// Send some bytes, waiting for room as required.
+	pub fn write(&mut self, bytes: &[u8]) {
+		for byte in bytes {
+			while self.dev.isr.read().txe().bit_is_clear() {}
+			self.dev.tdr.write(|w| w.tdr().bits(u16::from(*byte)));
+		}
+	}
+
+	/// Wait until everything has been sent.
+	pub fn flush(&mut self) {
+		while self.dev.isr.read().tc().bit_is_clear() {}
+	}
+}
+
+/// Is someone holding the UART receive line low (i.e. sending a break)?
+///
+/// The pin gets a pull-up, so an unplugged cable reads as idle.
+pub fn is_break(gpioa: &pac::GPIOA) -> bool {
+	gpioa.pupdr.modify(|_r, w| w.pupdr10().pull_up());
+	gpioa.idr.read().idr10().is_low()
+}
@@ -0,0 +1,269 @@
+//! # Firmware Update
+//!
+//! Receives a new application image from the host and writes it to flash, one
+//! page at a time.
+//!
+//! The first page of the application (which holds its vector table) is kept
+//! in RAM and only written once the whole image has arrived and its CRC-32 has
+//! been checked. That page is erased as soon as an update starts, so if the
+//! update fails or is interrupted, we won't try to start a half-written
+//! application and the host can simply try again.
+//!
+//! The Cortex-M0 doesn't use some of the words in the vector table, so once an
+//! image has been checked we keep its length and CRC-32 there. That lets us
+//! check the whole image each time we boot, before we start it. An image
+//! flashed with a debug probe has zero in these words, so we can only check
+//! its vector table.
+
+use crate::flash::{self, Flash, PAGE_SIZE};
+
+/// Where the application starts
+pub const APP_START: usize = 0x0800_1000;
+
+/// Where the application ends (the application's settings start here)
+pub const APP_END: usize = 0x0800_7800;
+
+/// Where we keep the length of the image (as a `u32le`), in one of the
+/// reserved words of its vector table
+const LENGTH_OFFSET: usize = 0x1C;
+
+/// Where we keep the CRC-32 of the image (as a `u32le`), in the next reserved
+/// word of its vector table
+const CRC_OFFSET: usize = 0x20;
+
+/// The shortest image we take, which must at least cover the words above
+const MIN_LENGTH: usize = CRC_OFFSET + 4;
+
+/// Where RAM starts
+const RAM_START: u32 = 0x2000_0000;
+
+/// Where RAM ends
+const RAM_END: u32 = RAM_START + 4096;
+
+/// How far we've got with an update.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[repr(u8)]
+pub enum Status {
+	/// Waiting for the host to start an update
+	Ready = 1,
+	/// Receiving an image
+	Receiving = 2,
+	/// The image has been written and checked
+	Complete = 3,
+	/// The update failed
+	Failed = 4,
+}
+
+/// An update, in progress or otherwise.
+pub struct Update {
+	/// How far we've got
+	status: Status,
+	/// How long the host says the image is
+	length: usize,
+	/// What the host says the CRC-32 of the image is, if it told us
+	crc: Option<u32>,
+	/// How many bytes of the image we've been given
+	received: usize,
+	/// How far we'd got, and how many whole pages we had, when last asked
+	reported: (Status, usize),
+	/// The first page of the image, and then the page we're currently filling
+	pages: &'static mut [[u8; PAGE_SIZE]; 2],
+}
+
+impl Update {
+	/// Get ready for an update, using the given space for the pages.
+	pub fn new(pages: &'static mut [[u8; PAGE_SIZE]; 2]) -> Update {
+		Update {
+			status: Status::Ready,
+			length: 0,
+			crc: None,
+			received: 0,
+			reported: (Status::Ready, 0),
+			pages,
+		}
+	}
+
+	/// How far have we got?
+	pub fn status(&self) -> Status {
+		self.status
+	}
+
+	/// Has an update from the host got any further (or been given another
+	/// whole page) since we were last asked?
+	///
+	/// Updates over XMODEM don't count, as the sender shows their progress.
+	pub fn take_progress(&mut self) -> Option<Status> {
+		let progress = (self.status, self.received / PAGE_SIZE);
+		if self.crc.is_none() || progress == self.reported {
+			return None;
+		}
+		self.reported = progress;
+		Some(self.status)
+	}
+
+	/// Start receiving a new image, throwing away any update in progress.
+	///
+	/// The header is the length of the image and its CRC-32, both as
+	/// `u32le`.
+	pub fn start(&mut self, header: &[u8], flash: &mut Flash) {
+		let length = u32::from_le_bytes([header[0], header[1], header[2], header[3]]) as usize;
+		let crc = u32::from_le_bytes([header[4], header[5], header[6], header[7]]);
+		self.begin(length, Some(crc), flash);
+	}
+
+	/// Start receiving a new image of unknown length, with no CRC-32,
+	/// throwing away any update in progress.
+	///
+	/// This is for XMODEM, which checks each block as it arrives instead.
+	pub fn start_unchecked(&mut self, flash: &mut Flash) {
+		self.begin(APP_END - APP_START, None, flash);
+	}
+
+	/// Start receiving a new image.
+	fn begin(&mut self, length: usize, crc: Option<u32>, flash: &mut Flash) {
+		self.length = length;
+		self.crc = crc;
+		self.received = 0;
+		let bad_length = self.length < MIN_LENGTH || self.length > APP_END - APP_START;
+		self.status = if bad_length || flash.erase_page(APP_START).is_err() {
+			Status::Failed
+		} else {
+			Status::Receiving
+		};
+	}
+
+	/// Can we take this many more bytes of the image?
+	pub fn will_accept(&self, length: usize) -> bool {
+		self.status == Status::Receiving && length != 0 && self.received + length <= self.length
+	}
+
+	/// Take the next part of the image.
+	///
+	/// Check [`Self::will_accept`] first.
+	pub fn write(&mut self, data: &[u8], flash: &mut Flash) {
+		for byte in data {
+			let page = self.received / PAGE_SIZE;
+			self.pages[page.min(1)][self.received % PAGE_SIZE] = *byte;
+			self.received += 1;
+			if self.received.is_multiple_of(PAGE_SIZE) {
+				self.flush(flash);
+			}
+		}
+	}
+
+	/// The host has sent the whole image. Check it, and if it's good, write
+	/// the first page so it can be started.
+	pub fn finish(&mut self, flash: &mut Flash) {
+		if self.status != Status::Receiving {
+			return;
+		}
+		if self.crc.is_none() {
+			// We've been given all there is
+			self.length = self.received;
+		}
+		if self.received != self.length || self.length < MIN_LENGTH {
+			self.status = Status::Failed;
+			return;
+		}
+		if !self.received.is_multiple_of(PAGE_SIZE) {
+			self.flush(flash);
+			if self.status != Status::Receiving {
+				return;
+			}
+		}
+		let first_len = self.length.min(PAGE_SIZE);
+		let crc = image_crc(&self.pages[0], self.length);
+		// Keep the length and CRC-32, so we can check the image when we boot
+		self.pages[0][LENGTH_OFFSET..CRC_OFFSET]
+			.copy_from_slice(&(self.length as u32).to_le_bytes());
+		self.pages[0][CRC_OFFSET..MIN_LENGTH].copy_from_slice(&crc.to_le_bytes());
+		self.status = if (self.crc.is_none() || self.crc == Some(crc))
+			&& is_valid_application(&self.pages[0])
+			&& flash
+				.write_page(APP_START, &self.pages[0][0..even(first_len)])
+				.is_ok()
+		{
+			Status::Complete
+		} else {
+			Status::Failed
+		};
+	}
+
+	/// Write out the page we've just filled - unless it's the first page,
+	/// which we keep until the end.
+	fn flush(&mut self, flash: &mut Flash) {
+		let page = (self.received - 1) / PAGE_SIZE;
+		let used = ((self.received - 1) % PAGE_SIZE) + 1;
+		if page != 0
+			&& flash
+				.write_page(
+					APP_START + (page * PAGE_SIZE),
+					&self.pages[1][0..even(used)],
+				)
+				.is_err()
+		{
+			self.status = Status::Failed;
+		}
+	}
+}
+
+/// Round up to a whole number of 16-bit words, as that's what we program.
+///
+/// Any spare byte on the end is outside the image, so it doesn't matter what
+/// it is.
+fn even(length: usize) -> usize {
+	length + (length % 2)
+}
+
+/// Is there an application in flash that we can start?
+///
+/// If we wrote it, we check its CRC-32 too.
+pub fn can_start() -> bool {
+	let start = flash::read(APP_START, PAGE_SIZE);
+	let length = read_u32(&start[LENGTH_OFFSET..]) as usize;
+	let crc = read_u32(&start[CRC_OFFSET..]);
+	is_valid_application(start)
+		&& (length == 0
+			|| ((MIN_LENGTH..=APP_END - APP_START).contains(&length)
+				&& image_crc(start, length) == crc))
+}
+
+/// Does this look like the start of an application?
+///
+/// We check the initial stack pointer is in RAM and the reset vector is in
+/// the application area.
+fn is_valid_application(start: &[u8]) -> bool {
+	let stack_pointer = read_u32(start);
+	let reset_vector = read_u32(&start[4..]) as usize;
+	(RAM_START..=RAM_END).contains(&stack_pointer) && (APP_START..APP_END).contains(&reset_vector)
+}
+
+/// Get a `u32le` from the start of some bytes.
+fn read_u32(bytes: &[u8]) -> u32 {
+	u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]])
+}
+
+/// Work out the CRC-32 of an image, from its first page and the rest of it in
+/// flash.
+///
+/// The words where we keep the length and CRC-32 are taken as zero, which is
+/// what the host sent us.
+fn image_crc(first_page: &[u8], length: usize) -> u32 {
+	let first_len = length.min(PAGE_SIZE);
+	let crc = crc32(!0, &first_page[0..LENGTH_OFFSET]);
+	let crc = crc32(crc, &[0; MIN_LENGTH - LENGTH_OFFSET]);
+	let crc = crc32(crc, &first_page[MIN_LENGTH..first_len]);
+	!crc32(crc, flash::read(APP_START + PAGE_SIZE, length - first_len))
+}
+
+/// Add some bytes to a CRC-32 (the one used by zip, zlib, etc).
+fn crc32(mut crc: u32, bytes: &[u8]) -> u32 {
+	for byte in bytes {
+		crc ^= u32::from(*byte);
+		for _ in 0..8 {
+			let mask = (crc & 1).wrapping_neg();
+			crc = (crc >> 1) ^ (0xEDB8_8320 & mask);
+		}
+	}
+	crc
+}
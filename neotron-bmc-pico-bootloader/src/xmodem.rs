@@ -0,0 +1,203 @@
+//! # XMODEM-CRC Receiver
+//!
+//! Lets someone send a new application over the UART, using XMODEM-CRC from a
+//! terminal program (or `sx`). This is for when the host can't send us one
+//! over SPI - probably because the application it needs is broken.
+//!
+//! We only take 128 byte blocks. The sender pads the last block with `0x1A`,
+//! which ends up in flash after the application, where it does no harm.
+
+use crate::flash::Flash;
+use crate::uart::Uart;
+use crate::update::{Status, Update};
+
+/// Start of a 128 byte block
+const SOH: u8 = 0x01;
+/// End of the transfer
+const EOT: u8 = 0x04;
+/// Block received OK
+const ACK: u8 = 0x06;
+/// Block was bad, so send it again
+const NAK: u8 = 0x15;
+/// Give up on the transfer
+const CAN: u8 = 0x18;
+/// Asks the sender to start, using a CRC-16 rather than a checksum
+const START_CRC: u8 = b'C';
+
+/// How much data is in a block
+const BLOCK_LEN: usize = 128;
+
+/// A block, without the `SOH`: a block number, its complement, the data and a
+/// CRC-16
+pub const PACKET_LEN: usize = 2 + BLOCK_LEN + 2;
+
+/// How often we ask the sender to start, in milliseconds
+const START_INTERVAL_MS: u16 = 3000;
+
+/// How long we wait for the rest of a packet, in milliseconds
+const BYTE_TIMEOUT_MS: u16 = 1000;
+
+/// How long we wait for the next packet, in milliseconds
+const PACKET_TIMEOUT_MS: u16 = 10_000;
+
+/// What we're waiting for.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum State {
+	/// Waiting for a transfer to start
+	Idle,
+	/// Waiting for the next `SOH` (or an `EOT`)
+	Packet,
+	/// Part way through a packet
+	Data,
+}
+
+/// Receives an image over XMODEM-CRC.
+pub struct Receiver {
+	/// What we're waiting for
+	state: State,
+	/// The packet we're receiving
+	packet: &'static mut [u8; PACKET_LEN],
+	/// How much of the packet we have
+	idx: usize,
+	/// The block number we want next
+	block: u8,
+	/// How long since we last heard from the sender, in milliseconds
+	idle_ms: u16,
+}
+
+impl Receiver {
+	/// Make a new receiver, waiting for a transfer to start, using the given
+	/// space for the packet.
+	pub fn new(packet: &'static mut [u8; PACKET_LEN]) -> Receiver {
+		Receiver {
+			state: State::Idle,
+			packet,
+			idx: 0,
+			block: 0,
+			idle_ms: 0,
+		}
+	}
+
+	/// Handle a byte from the UART.
+	///
+	/// Returns `true` if a transfer has just ended. Check the update status
+	/// to see how it went.
+	pub fn byte(
+		&mut self,
+		byte: u8,
+		uart: &mut Uart,
+		update: &mut Update,
+		flash: &mut Flash,
+	) -> bool {
+		self.idle_ms = 0;
+		match (self.state, byte) {
+			(State::Idle, SOH) => {
+				update.start_unchecked(flash);
+				if update.status() != Status::Receiving {
+					return self.cancel(uart);
+				}
+				self.block = 1;
+				self.idx = 0;
+				self.state = State::Data;
+			}
+			(State::Packet, SOH) => {
+				self.idx = 0;
+				self.state = State::Data;
+			}
+			(State::Packet, EOT) => {
+				uart.write(&[ACK]);
+				update.finish(flash);
+				self.state = State::Idle;
+				return true;
+			}
+			(State::Packet, CAN) => {
+				self.state = State::Idle;
+				return true;
+			}
+			(State::Data, _) => {
+				self.packet[self.idx] = byte;
+				self.idx += 1;
+				if self.idx == PACKET_LEN {
+					self.state = State::Packet;
+					return self.check_packet(uart, update, flash);
+				}
+			}
+			_ => {
+				// Ignore anything else
+			}
+		}
+		false
+	}
+
+	/// Call this every millisecond, so we can time things out.
+	pub fn tick(&mut self, uart: &mut Uart) {
+		self.idle_ms = self.idle_ms.saturating_add(1);
+		match self.state {
+			State::Idle if self.idle_ms >= START_INTERVAL_MS => {
+				uart.write(&[START_CRC]);
+				self.idle_ms = 0;
+			}
+			State::Data if self.idle_ms >= BYTE_TIMEOUT_MS => {
+				// Ask for the packet again
+				uart.write(&[NAK]);
+				self.idle_ms = 0;
+				self.state = State::Packet;
+			}
+			State::Packet if self.idle_ms >= PACKET_TIMEOUT_MS => {
+				// The sender has gone away
+				self.idle_ms = 0;
+				self.state = State::Idle;
+			}
+			_ => {}
+		}
+	}
+
+	/// We have a whole packet. Check it and, if it's the next block, use it.
+	fn check_packet(&mut self, uart: &mut Uart, update: &mut Update, flash: &mut Flash) -> bool {
+		let number = self.packet[0];
+		if number != !self.packet[1] || crc16(&self.packet[2..]) != 0 {
+			uart.write(&[NAK]);
+		} else if number == self.block {
+			if !update.will_accept(BLOCK_LEN) {
+				return self.cancel(uart);
+			}
+			update.write(&self.packet[2..2 + BLOCK_LEN], flash);
+			if update.status() != Status::Receiving {
+				return self.cancel(uart);
+			}
+			self.block = self.block.wrapping_add(1);
+			uart.write(&[ACK]);
+		} else if number == self.block.wrapping_sub(1) {
+			// The sender missed our ACK, and sent it again
+			uart.write(&[ACK]);
+		} else {
+			return self.cancel(uart);
+		}
+		false
+	}
+
+	/// Tell the sender to give up.
+	fn cancel(&mut self, uart: &mut Uart) -> bool {
+		uart.write(&[CAN, CAN]);
+		self.state = State::Idle;
+		true
+	}
+}
+
+/// Calculate the CRC-16 used by XMODEM.
+///
+/// Run over a block and its CRC, this gives zero.
+fn crc16(bytes: &[u8]) -> u16 {
+	let mut crc = 0u16;
+	for byte in bytes {
+		crc ^= u16::from(*byte) << 8;
+		for _ in 0..8 {
+			crc = if crc & 0x8000 != 0 {
+				(crc << 1) ^ 0x1021
+			} else {
+				crc << 1
+			};
+		}
+	}
+	crc
+}
@@ -1,8 +1,14 @@
 MEMORY
 {
   /* NOTE K = KiBi = 1024 bytes */
-  FLASH : ORIGIN = 0x08000000, LENGTH = 32K
-  RAM : ORIGIN = 0x20000000, LENGTH = 4K
+  /* The first 4K of the 32K flash is the bootloader, and the last 2K is kept
+   * back for our settings */
+  FLASH : ORIGIN = 0x08001000, LENGTH = 26K
+  SETTINGS : ORIGIN = 0x08007800, LENGTH = 2K
+  /* The first 256 bytes of RAM are for our copy of the vector table (at
+   * 0x20000000), a crash report (at 0x200000C0, see `src/crash.rs`) and a note
+   * to the bootloader (at 0x200000FC, see `src/bootloader.rs`) */
+  RAM : ORIGIN = 0x20000100, LENGTH = 4K - 256
 }
 
 /* This is where the call stack will be allocated. */
@@ -0,0 +1,71 @@
+//! # Bootloader Support
+//!
+//! The first 4 KiB of flash holds a bootloader (see the
+//! `neotron-bmc-pico-bootloader` crate), which starts this application, or
+//! lets the host send it a new one over SPI.
+//!
+//! The Cortex-M0 can't move its vector table, so the bootloader's vector
+//! table is the one the CPU uses. To get our interrupts back, we copy our
+//! vector table into the start of RAM and then map RAM at address zero in
+//! place of flash. Both we and the bootloader keep the first 256 bytes of RAM
+//! back for this.
+
+use stm32f0xx_hal::pac;
+
+/// Where the bootloader starts
+const BOOTLOADER_START: usize = 0x0800_0000;
+
+/// How many words are in our vector table (16 exceptions and 32 interrupts)
+const VECTOR_TABLE_WORDS: usize = 48;
+
+/// Where our vector table is copied to
+const RAM_VECTOR_TABLE: *mut u32 = 0x2000_0000 as *mut u32;
+
+/// We leave a note here when we want the bootloader to take an update.
+///
+/// This must match the bootloader.
+const BOOT_REQUEST: *mut u32 = 0x2000_00FC as *mut u32;
+
+/// The note that asks the bootloader to take an update
+///
+/// This must match the bootloader.
+const BOOT_REQUEST_MAGIC: u32 = 0xB007_10AD;
+
+extern "C" {
+	/// Our vector table, which `cortex-m-rt` puts at the start of our flash
+	static __vector_table: [u32; VECTOR_TABLE_WORDS];
+}
+
+/// Copy our vector table into RAM, and map RAM at address zero, so our
+/// interrupt handlers are used instead of the bootloader's.
+///
+/// Call this at start-up, before interrupts are enabled.
+pub fn relocate_vector_table(rcc: &pac::RCC, syscfg: &pac::SYSCFG) {
+	// Safety: the first 256 bytes of RAM are kept back for the vector table,
+	// and the linker gives us our vector table.
+	unsafe {
+		let vector_table = core::ptr::addr_of!(__vector_table).cast::<u32>();
+		for idx in 0..VECTOR_TABLE_WORDS {
+			RAM_VECTOR_TABLE
+				.add(idx)
+				.write_volatile(vector_table.add(idx).read());
+		}
+	}
+	rcc.apb2enr.modify(|_r, w| w.syscfgen().enabled());
+	syscfg.cfgr1.modify(|_r, w| w.mem_mode().sram());
+}
+
+/// Jump to the bootloader, so it can take an update from the host.
+///
+/// Whatever we're driving on the pins stays as it is, so the host stays
+/// powered on. The bootloader reboots the BMC when it is done.
+pub fn enter() -> ! {
+	cortex_m::interrupt::disable();
+	defmt::info!("Entering the bootloader");
+	// Safety: the RAM is kept back for the note, and the bootloader expects to
+	// be started like this.
+	unsafe {
+		BOOT_REQUEST.write_volatile(BOOT_REQUEST_MAGIC);
+		cortex_m::asm::bootload(BOOTLOADER_START as *const u32)
+	}
+}
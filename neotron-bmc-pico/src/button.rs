@@ -0,0 +1,557 @@
+//! # Button Debouncer and Gestures
+//!
+//! Like the one in `debouncr`, but the number of polls required to register a
+//! change is a run-time value rather than a type parameter, so the button
+//! timings can be changed by the host.
+//!
+//! On top of the basic presses, we also recognise some gestures (like a
+//! double-press), each of which can be mapped to an [`Action`].
+
+/// A change in the debounced state of a button.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Edge {
+	/// The button has become pressed
+	Rising,
+	/// The button has become released
+	Falling,
+}
+
+/// A gesture made with the buttons, beyond a simple press.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, defmt::Format)]
+#[repr(u8)]
+pub enum Gesture {
+	/// The power button was pressed twice in quick succession
+	PowerDoublePress = 1,
+	/// The power and reset buttons were both held down as the BMC booted
+	BootChord = 2,
+	/// The reset button was held down for a long time
+	ResetLongHold = 3,
+	/// The power button was held down for a very long time
+	PowerVeryLongHold = 4,
+}
+
+/// Something the BMC can do when a [`Gesture`] is recognised.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, defmt::Format)]
+#[repr(u8)]
+pub enum Action {
+	/// Do nothing (beyond reporting the gesture to the host)
+	None = 0,
+	/// Turn the system off
+	PowerOff = 1,
+	/// Turn the system off, then on again
+	PowerCycle = 2,
+	/// Pulse the host's reset line
+	ResetHost = 3,
+	/// Put the BMC settings back to their defaults
+	FactoryReset = 4,
+	/// Put the BMC into safe mode, running on default settings
+	SafeMode = 5,
+}
+
+impl Action {
+	/// Convert from a register value, or `None` if the value is unknown.
+	pub fn from_u8(value: u8) -> Option<Action> {
+		match value {
+			0 => Some(Action::None),
+			1 => Some(Action::PowerOff),
+			2 => Some(Action::PowerCycle),
+			3 => Some(Action::ResetHost),
+			4 => Some(Action::FactoryReset),
+			5 => Some(Action::SafeMode),
+			_ => None,
+		}
+	}
+}
+
+/// The timing configuration for our buttons.
+///
+/// All of the press lengths are given as a number of consecutive polls, so
+/// the time in milliseconds is the count multiplied by `poll_interval_ms`.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Config {
+	/// How often we poll the power and reset buttons, in milliseconds.
+	pub poll_interval_ms: u8,
+	/// How many polls the power button must be held for to be a short press.
+	pub power_short_polls: u8,
+	/// How many polls the power button must be held for to be a long press.
+	pub power_long_polls: u8,
+	/// How many polls the reset button must be held for to be a press.
+	pub reset_short_polls: u8,
+	/// How many polls may pass between two power button presses for them to
+	/// be a double-press.
+	pub double_press_polls: u8,
+	/// How many polls the power button must be held for to be a very long
+	/// hold.
+	pub power_very_long_polls: u8,
+	/// How many polls the reset button must be held for to be a long hold.
+	pub reset_long_polls: u8,
+	/// What to do for each [`Gesture`], indexed by `Gesture as usize - 1`.
+	pub actions: [Action; 4],
+}
+
+impl Config {
+	/// The shortest poll interval we support. This is the SysTick period.
+	pub const MIN_POLL_INTERVAL_MS: u8 = 5;
+
+	/// Set how often we poll the buttons, in milliseconds.
+	///
+	/// Values shorter than the SysTick period are rounded up.
+	pub fn set_poll_interval_ms(&mut self, poll_interval_ms: u8) {
+		self.poll_interval_ms = poll_interval_ms.max(Self::MIN_POLL_INTERVAL_MS);
+	}
+
+	/// Set how many polls make a short press of the power button.
+	pub fn set_power_short_polls(&mut self, polls: u8) {
+		self.power_short_polls = polls.max(1);
+	}
+
+	/// Set how many polls make a long press of the power button.
+	pub fn set_power_long_polls(&mut self, polls: u8) {
+		self.power_long_polls = polls.max(1);
+	}
+
+	/// Set how many polls make a press of the reset button.
+	pub fn set_reset_short_polls(&mut self, polls: u8) {
+		self.reset_short_polls = polls.max(1);
+	}
+
+	/// Set how many polls may pass between the two presses of a double-press.
+	pub fn set_double_press_polls(&mut self, polls: u8) {
+		self.double_press_polls = polls;
+	}
+
+	/// Set how many polls make a very long hold of the power button.
+	pub fn set_power_very_long_polls(&mut self, polls: u8) {
+		self.power_very_long_polls = polls.max(1);
+	}
+
+	/// Set how many polls make a long hold of the reset button.
+	pub fn set_reset_long_polls(&mut self, polls: u8) {
+		self.reset_long_polls = polls.max(1);
+	}
+
+	/// What should we do when we see this gesture?
+	pub fn action(&self, gesture: Gesture) -> Action {
+		self.actions[gesture as usize - 1]
+	}
+
+	/// Change what we do when we see this gesture.
+	pub fn set_action(&mut self, gesture: Gesture, action: Action) {
+		self.actions[gesture as usize - 1] = action;
+	}
+}
+
+impl Default for Config {
+	fn default() -> Config {
+		Config {
+			// 75ms
+			poll_interval_ms: 75,
+			// 75ms x 2 = 150ms is a short press
+			power_short_polls: 2,
+			// 75ms x 16 = 1200ms is a long press
+			power_long_polls: 16,
+			// 75ms x 2 = 150ms is a short press
+			reset_short_polls: 2,
+			// 75ms x 5 = 375ms between presses
+			double_press_polls: 5,
+			// 75ms x 133 = ~10s is a very long hold
+			power_very_long_polls: 133,
+			// 75ms x 53 = ~4s is a long hold
+			reset_long_polls: 53,
+			actions: [
+				// Gesture::PowerDoublePress
+				Action::None,
+				// Gesture::BootChord
+				Action::SafeMode,
+				// Gesture::ResetLongHold
+				Action::PowerCycle,
+				// Gesture::PowerVeryLongHold
+				Action::FactoryReset,
+			],
+		}
+	}
+}
+
+/// Debounces one input by counting how many consecutive polls disagree with
+/// the current debounced state.
+#[derive(Debug, Default)]
+pub struct Debouncer {
+	/// The current debounced state (`true` = pressed)
+	pressed: bool,
+	/// How many consecutive polls have disagreed with `pressed`
+	count: u8,
+}
+
+impl Debouncer {
+	/// Create a new debouncer, in the given initial state.
+	pub const fn new(pressed: bool) -> Debouncer {
+		Debouncer { pressed, count: 0 }
+	}
+
+	/// Feed in the latest sample from the input.
+	///
+	/// If the input has been in a new state for `threshold` consecutive
+	/// polls, you get back the edge.
+	pub fn update(&mut self, pressed: bool, threshold: u8) -> Option<Edge> {
+		if pressed == self.pressed {
+			// Any bounce in the other direction is forgotten
+			self.count = 0;
+			return None;
+		}
+		self.count = self.count.saturating_add(1);
+		if self.count < threshold {
+			return None;
+		}
+		self.pressed = pressed;
+		self.count = 0;
+		if pressed {
+			Some(Edge::Rising)
+		} else {
+			Some(Edge::Falling)
+		}
+	}
+
+	/// Is the input currently pressed (after debouncing)?
+	pub fn is_pressed(&self) -> bool {
+		self.pressed
+	}
+}
+
+/// Recognises [`Gesture`]s from the debounced button states.
+#[derive(Debug)]
+pub struct GestureDetector {
+	/// Polls since the last power button press, if we're still inside the
+	/// double-press window.
+	since_power_press: Option<u8>,
+	/// Polls the power button has been held for, or `None` if we've already
+	/// reported this hold.
+	power_held: Option<u8>,
+	/// Polls the reset button has been held for, or `None` if we've already
+	/// reported this hold.
+	reset_held: Option<u8>,
+	/// Polls both buttons have been held for since boot, or `None` once
+	/// either has been released.
+	boot_chord: Option<u8>,
+}
+
+impl GestureDetector {
+	/// Create a new gesture detector. Call this at boot, so we can spot the
+	/// boot chord.
+	pub const fn new() -> GestureDetector {
+		GestureDetector {
+			since_power_press: None,
+			power_held: Some(0),
+			reset_held: Some(0),
+			boot_chord: Some(0),
+		}
+	}
+
+	/// Are both buttons still being held down from boot?
+	///
+	/// If so, the ordinary button presses should be ignored.
+	pub fn in_boot_chord(&self) -> bool {
+		self.boot_chord.is_some()
+	}
+
+	/// Call this every button poll.
+	///
+	/// * `power_raw` and `reset_raw` are the un-debounced button states.
+	/// * `power` and `reset` are the debounced button states.
+	/// * `power_pressed` is true if the power button was just pressed.
+	pub fn update(
+		&mut self,
+		power_raw: bool,
+		reset_raw: bool,
+		power: bool,
+		reset: bool,
+		power_pressed: bool,
+		config: &Config,
+	) -> Option<Gesture> {
+		if let Some(count) = self.boot_chord {
+			if power_raw && reset_raw {
+				let count = count.saturating_add(1);
+				if count < config.power_long_polls {
+					self.boot_chord = Some(count);
+					return None;
+				}
+				self.boot_chord = None;
+				// Don't report the holds that made up the chord
+				self.power_held = None;
+				self.reset_held = None;
+				return Some(Gesture::BootChord);
+			}
+			self.boot_chord = None;
+		}
+
+		if let Some(count) = self.since_power_press {
+			self.since_power_press = count
+				.checked_add(1)
+				.filter(|count| *count <= config.double_press_polls);
+		}
+		if power_pressed {
+			if self.since_power_press.take().is_some() {
+				return Some(Gesture::PowerDoublePress);
+			}
+			self.since_power_press = Some(0);
+		}
+
+		if Self::held(&mut self.power_held, power, config.power_very_long_polls) {
+			return Some(Gesture::PowerVeryLongHold);
+		}
+
+		if Self::held(&mut self.reset_held, reset, config.reset_long_polls) {
+			return Some(Gesture::ResetLongHold);
+		}
+
+		None
+	}
+
+	/// Count how long a button has been held. Returns true, once, when the
+	/// threshold is reached.
+	fn held(counter: &mut Option<u8>, pressed: bool, threshold: u8) -> bool {
+		if !pressed {
+			*counter = Some(0);
+			return false;
+		}
+		if let Some(count) = *counter {
+			let count = count.saturating_add(1);
+			if count >= threshold {
+				*counter = None;
+				return true;
+			}
+			*counter = Some(count);
+		}
+		false
+	}
+}
+
+impl Default for GestureDetector {
+	fn default() -> GestureDetector {
+		GestureDetector::new()
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+
+	/// Feed in each sample, and collect what comes back.
+	fn run(debouncer: &mut Debouncer, samples: &[bool], threshold: u8) -> Vec<Option<Edge>> {
+		samples
+			.iter()
+			.map(|pressed| debouncer.update(*pressed, threshold))
+			.collect()
+	}
+
+	#[test]
+	fn press_and_release() {
+		let mut debouncer = Debouncer::new(false);
+		assert_eq!(
+			run(&mut debouncer, &[true, true, true], 2),
+			[None, Some(Edge::Rising), None]
+		);
+		assert!(debouncer.is_pressed());
+		assert_eq!(
+			run(&mut debouncer, &[false, false, false], 2),
+			[None, Some(Edge::Falling), None]
+		);
+		assert!(!debouncer.is_pressed());
+	}
+
+	#[test]
+	fn bounces_are_ignored() {
+		let mut debouncer = Debouncer::new(false);
+		// A bounce back to released starts the count again
+		assert_eq!(
+			run(&mut debouncer, &[true, true, false, true, true], 3),
+			[None, None, None, None, None]
+		);
+		assert!(!debouncer.is_pressed());
+		assert_eq!(debouncer.update(true, 3), Some(Edge::Rising));
+		// Same for a bounce back to pressed
+		assert_eq!(
+			run(&mut debouncer, &[false, false, true, false, false], 3),
+			[None, None, None, None, None]
+		);
+		assert!(debouncer.is_pressed());
+		assert_eq!(debouncer.update(false, 3), Some(Edge::Falling));
+	}
+
+	#[test]
+	fn initial_state() {
+		let mut debouncer = Debouncer::new(true);
+		assert!(debouncer.is_pressed());
+		// Still being pressed isn't a new press
+		assert_eq!(run(&mut debouncer, &[true; 4], 1), [None; 4]);
+		assert_eq!(debouncer.update(false, 1), Some(Edge::Falling));
+	}
+
+	#[test]
+	fn threshold_can_change() {
+		let mut debouncer = Debouncer::new(false);
+		assert_eq!(run(&mut debouncer, &[true; 2], 5), [None; 2]);
+		// The polls we've already counted still count at the new threshold
+		assert_eq!(debouncer.update(true, 3), Some(Edge::Rising));
+		// A threshold of zero acts like one
+		assert_eq!(debouncer.update(false, 0), Some(Edge::Falling));
+	}
+
+	/// What the buttons do on one poll: the raw power and reset states, the
+	/// debounced power and reset states, and whether the power button was
+	/// just pressed.
+	type Poll = (bool, bool, bool, bool, bool);
+
+	const IDLE: Poll = (false, false, false, false, false);
+	const POWER_PRESS: Poll = (true, false, true, false, true);
+	const POWER_HELD: Poll = (true, false, true, false, false);
+	const RESET_HELD: Poll = (false, true, false, true, false);
+	const BOTH_RAW: Poll = (true, true, false, false, false);
+	const BOTH_HELD: Poll = (true, true, true, true, false);
+
+	/// Short timings, so the tables stay small.
+	fn config() -> Config {
+		Config {
+			power_long_polls: 4,
+			double_press_polls: 3,
+			power_very_long_polls: 6,
+			reset_long_polls: 5,
+			..Config::default()
+		}
+	}
+
+	/// Feed in each poll, and collect the gestures that come back.
+	fn gestures(detector: &mut GestureDetector, polls: &[Poll]) -> Vec<Option<Gesture>> {
+		let config = config();
+		polls
+			.iter()
+			.map(|p| detector.update(p.0, p.1, p.2, p.3, p.4, &config))
+			.collect()
+	}
+
+	/// Where in `polls` we saw each gesture.
+	fn seen(polls: &[Poll]) -> Vec<(usize, Gesture)> {
+		let mut detector = GestureDetector::new();
+		gestures(&mut detector, polls)
+			.into_iter()
+			.enumerate()
+			.filter_map(|(idx, gesture)| Some((idx, gesture?)))
+			.collect()
+	}
+
+	#[test]
+	fn double_press_window() {
+		// How many polls after the first press the second one comes, and
+		// whether that's a double-press. The window is 3 polls.
+		let table = [(1, true), (2, true), (3, true), (4, false), (10, false)];
+		for (gap, is_double) in table {
+			let mut polls = vec![POWER_PRESS];
+			polls.resize(gap, IDLE);
+			polls.push(POWER_PRESS);
+			let expected = if is_double {
+				vec![(gap, Gesture::PowerDoublePress)]
+			} else {
+				vec![]
+			};
+			assert_eq!(seen(&polls), expected, "gap of {}", gap);
+		}
+	}
+
+	#[test]
+	fn double_press_starts_again() {
+		// The third press starts a new pair, so it takes a fourth for
+		// another double-press
+		assert_eq!(
+			seen(&[IDLE, POWER_PRESS, POWER_PRESS, POWER_PRESS, POWER_PRESS]),
+			[
+				(2, Gesture::PowerDoublePress),
+				(4, Gesture::PowerDoublePress)
+			]
+		);
+	}
+
+	#[test]
+	fn no_double_press_window() {
+		let mut detector = GestureDetector::new();
+		let config = Config {
+			double_press_polls: 0,
+			..config()
+		};
+		for _ in 0..4 {
+			assert_eq!(
+				detector.update(true, false, true, false, true, &config),
+				None
+			);
+		}
+	}
+
+	#[test]
+	fn holds() {
+		// The power button's very long hold is 6 polls, and the reset
+		// button's long hold is 5
+		let table = [
+			(POWER_HELD, 6, Gesture::PowerVeryLongHold),
+			(RESET_HELD, 5, Gesture::ResetLongHold),
+		];
+		for (poll, length, gesture) in table {
+			assert_eq!(seen(&vec![poll; length - 1]), []);
+			assert_eq!(seen(&vec![poll; length]), [(length - 1, gesture)]);
+			// Only once per hold
+			assert_eq!(seen(&[poll; 20]), [(length - 1, gesture)]);
+		}
+	}
+
+	#[test]
+	fn release_starts_the_hold_again() {
+		let mut polls = vec![POWER_HELD; 5];
+		polls.push(IDLE);
+		polls.extend([POWER_HELD; 6]);
+		assert_eq!(seen(&polls), [(11, Gesture::PowerVeryLongHold)]);
+	}
+
+	#[test]
+	fn boot_chord() {
+		let mut detector = GestureDetector::new();
+		assert!(detector.in_boot_chord());
+		// Both buttons have to be down for the power button's long press
+		// (4 polls), before the debouncer has even seen them
+		assert_eq!(gestures(&mut detector, &[BOTH_RAW; 3]), [None; 3]);
+		assert!(detector.in_boot_chord());
+		assert_eq!(
+			gestures(&mut detector, &[BOTH_RAW]),
+			[Some(Gesture::BootChord)]
+		);
+		assert!(!detector.in_boot_chord());
+		// Holding on doesn't count as a long hold of either button
+		assert_eq!(gestures(&mut detector, &[BOTH_HELD; 20]), [None; 20]);
+		// Until they let go, and start again
+		assert_eq!(
+			gestures(
+				&mut detector,
+				&[IDLE, RESET_HELD, RESET_HELD, RESET_HELD, RESET_HELD, RESET_HELD]
+			),
+			[None, None, None, None, None, Some(Gesture::ResetLongHold)]
+		);
+	}
+
+	#[test]
+	fn boot_chord_only_at_boot() {
+		// Letting go of either button ends the chance of a boot chord
+		let mut polls = vec![BOTH_RAW, BOTH_RAW, POWER_HELD];
+		polls.extend([BOTH_RAW; 10]);
+		assert_eq!(seen(&polls), []);
+		// And it has to start on the very first poll
+		let mut polls = vec![IDLE];
+		polls.extend([BOTH_RAW; 10]);
+		assert_eq!(seen(&polls), []);
+	}
+
+	#[test]
+	fn longest_threshold() {
+		let mut debouncer = Debouncer::new(false);
+		assert_eq!(
+			run(&mut debouncer, &[true; 300], u8::MAX)[254],
+			Some(Edge::Rising)
+		);
+		assert!(debouncer.is_pressed());
+	}
+}
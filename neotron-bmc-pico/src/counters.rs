@@ -0,0 +1,90 @@
+//! # Diagnostic Counters
+//!
+//! Counts the errors we see, so the host can get an idea of how healthy the
+//! links to it (and to the peripherals) are. Every counter is a saturating
+//! 16-bit value.
+//!
+//! The counters are statics, so they can be bumped from any task or
+//! interrupt without having to pass them around.
+
+use core::sync::atomic::{AtomicU16, Ordering};
+
+/// The things we count.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, defmt::Format)]
+#[repr(u8)]
+pub enum Counter {
+	/// SPI requests with a bad CRC
+	SpiCrcFailure = 0,
+	/// SPI requests with an unknown request type
+	SpiBadRequestType = 1,
+	/// SPI requests which were a retry of the previous request
+	SpiRetry = 2,
+	/// SPI requests for a register (or operation) we don't support
+	SpiBadRegister = 3,
+	/// Bad words (parity or framing errors) from the PS/2 keyboard port
+	Ps2KbError = 4,
+	/// Bad words (parity or framing errors) from the PS/2 mouse port
+	Ps2MsError = 5,
+	/// Bytes dropped because the PS/2 keyboard FIFO was full
+	Ps2KbOverflow = 6,
+	/// Gestures dropped because the gesture FIFO was full
+	GestureOverflow = 7,
+	/// Events dropped because the event log was full
+	EventLogOverflow = 8,
+	/// Bytes lost because the UART receiver overran
+	UartOverrun = 9,
+	/// Messages dropped because the message queue was full
+	MessageQueueDrop = 10,
+	/// SPI requests of the wrong length
+	SpiBadLength = 11,
+	/// SPI requests too big for the buffer they were decoded into
+	SpiBufferTooSmall = 12,
+	/// SPI messages with an unknown response result
+	SpiBadResponseResult = 13,
+}
+
+/// How many counters we have.
+pub const NUM_COUNTERS: usize = 14;
+
+/// Our counters, indexed by `Counter as usize`.
+static COUNTERS: [AtomicU16; NUM_COUNTERS] = [
+	AtomicU16::new(0),
+	AtomicU16::new(0),
+	AtomicU16::new(0),
+	AtomicU16::new(0),
+	AtomicU16::new(0),
+	AtomicU16::new(0),
+	AtomicU16::new(0),
+	AtomicU16::new(0),
+	AtomicU16::new(0),
+	AtomicU16::new(0),
+	AtomicU16::new(0),
+	AtomicU16::new(0),
+	AtomicU16::new(0),
+	AtomicU16::new(0),
+];
+
+/// Add one to a counter (unless it's already at the maximum).
+pub fn increment(counter: Counter) {
+	// We don't have atomic read-modify-write on the Cortex-M0, so stop
+	// anything else getting in between our read and our write.
+	cortex_m::interrupt::free(|_cs| {
+		let counter = &COUNTERS[counter as usize];
+		let value = counter.load(Ordering::Relaxed);
+		counter.store(value.saturating_add(1), Ordering::Relaxed);
+	});
+}
+
+/// Copy every counter into a buffer, as `u16le` values.
+pub fn read_all(buffer: &mut [u8; NUM_COUNTERS * 2]) {
+	for (chunk, counter) in buffer.chunks_exact_mut(2).zip(COUNTERS.iter()) {
+		chunk.copy_from_slice(&counter.load(Ordering::Relaxed).to_le_bytes());
+	}
+}
+
+/// Set every counter back to zero.
+pub fn reset_all() {
+	for counter in COUNTERS.iter() {
+		counter.store(0, Ordering::Relaxed);
+	}
+}
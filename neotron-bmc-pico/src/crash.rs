@@ -0,0 +1,172 @@
+//! # Crash Reports
+//!
+//! When we panic, we write a short report into a part of RAM that neither we
+//! nor the bootloader touch at start-up, and then reset. When we next boot, we look for that
+//! report so we can tell the host what went wrong, without needing a probe
+//! attached.
+//!
+//! The report the host sees looks like:
+//!
+//! | Offset | Length | Contains                                          |
+//! | ------ | ------ | ------------------------------------------------- |
+//! | 0      | 1      | 1 if there is a report, 0 if not                  |
+//! | 1      | 1      | Reserved (0)                                      |
+//! | 2      | 2      | Line number (`u16le`)                             |
+//! | 4      | 16     | The end of the file name (null padded)            |
+//! | 20     | 28     | The start of the panic message (null padded)      |
+//!
+//! The message is empty if the panic message had to be formatted, such as
+//! for an index out of bounds.
+
+use core::convert::TryFrom;
+use core::panic::PanicInfo;
+
+/// The length of a crash report, in bytes.
+pub const REPORT_LEN: usize = 48;
+
+/// Where the line number goes in the report
+const LINE_OFFSET: usize = 2;
+
+/// Where the file name goes in the report
+const FILE_OFFSET: usize = 4;
+
+/// How much of the file name we keep
+const FILE_LEN: usize = 16;
+
+/// Where the panic message goes in the report
+const MESSAGE_OFFSET: usize = FILE_OFFSET + FILE_LEN;
+
+/// Marks a record as (probably) valid
+const MAGIC: u32 = 0xC4A5_4ED0;
+
+/// What we keep in RAM across a reset.
+#[repr(C)]
+struct Record {
+	magic: u32,
+	report: [u8; REPORT_LEN],
+	crc: u8,
+}
+
+/// Our record. It sits between our copy of the vector table and the note to
+/// the bootloader (see `bootloader.rs`), in the 256 bytes of RAM that neither
+/// of us zero at start-up. It must end before 0x2000_00FC.
+const CRASH_RECORD: *mut Record = 0x2000_00C0 as *mut Record;
+
+/// A crash report, recovered from a previous boot.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Report([u8; REPORT_LEN]);
+
+impl Report {
+	/// What the host sees when there is no crash report.
+	pub const EMPTY: Report = Report([0u8; REPORT_LEN]);
+
+	/// Get the report, in the form we give to the host.
+	pub fn as_bytes(&self) -> &[u8; REPORT_LEN] {
+		&self.0
+	}
+
+	/// The line number where we panicked.
+	pub fn line(&self) -> u16 {
+		u16::from_le_bytes([self.0[LINE_OFFSET], self.0[LINE_OFFSET + 1]])
+	}
+
+	/// The (end of the) name of the file where we panicked.
+	pub fn file(&self) -> &[u8] {
+		trim_nulls(&self.0[FILE_OFFSET..MESSAGE_OFFSET])
+	}
+
+	/// The (start of the) panic message.
+	pub fn message(&self) -> &[u8] {
+		trim_nulls(&self.0[MESSAGE_OFFSET..])
+	}
+}
+
+/// Look for a crash report from the previous boot.
+///
+/// The report is removed from RAM, so you only get it once. Call this once at
+/// start-up, before interrupts are enabled.
+pub fn take() -> Option<Report> {
+	// Safety: we only run at start-up, with nothing else touching the record.
+	// The RAM may hold any old junk, but any bit pattern is a valid `Record`,
+	// and we check the magic number and CRC before trusting it.
+	unsafe {
+		let magic = core::ptr::addr_of!((*CRASH_RECORD).magic).read_volatile();
+		let report = core::ptr::addr_of!((*CRASH_RECORD).report).read();
+		let crc = core::ptr::addr_of!((*CRASH_RECORD).crc).read_volatile();
+		core::ptr::addr_of_mut!((*CRASH_RECORD).magic).write_volatile(0);
+		if magic == MAGIC && neotron_bmc_protocol::calculate_crc(&report) == crc {
+			Some(Report(report))
+		} else {
+			None
+		}
+	}
+}
+
+/// Record a crash report in RAM, then reset.
+fn save_and_reset(report: [u8; REPORT_LEN]) -> ! {
+	let crc = neotron_bmc_protocol::calculate_crc(&report);
+	// Safety: we have interrupts disabled and we're never coming back, so
+	// nothing else is using the record.
+	unsafe {
+		CRASH_RECORD.write_volatile(Record {
+			magic: MAGIC,
+			report,
+			crc,
+		});
+	}
+	cortex_m::peripheral::SCB::sys_reset();
+}
+
+/// Called when the firmware panics.
+///
+/// The unit tests run on the host, which has its own panic handler.
+#[cfg_attr(not(test), panic_handler)]
+#[cfg_attr(test, allow(dead_code))]
+fn panic(info: &PanicInfo) -> ! {
+	cortex_m::interrupt::disable();
+	let mut report = [0u8; REPORT_LEN];
+	report[0] = 1;
+	if let Some(location) = info.location() {
+		let line = u16::try_from(location.line()).unwrap_or(u16::MAX);
+		report[LINE_OFFSET..FILE_OFFSET].copy_from_slice(&line.to_le_bytes());
+		// The end of the path is the most useful bit
+		let file = location.file().as_bytes();
+		let file = &file[file.len().saturating_sub(FILE_LEN)..];
+		report[FILE_OFFSET..FILE_OFFSET + file.len()].copy_from_slice(file);
+	}
+	// Only plain string messages are kept (like `Option::unwrap` on `None`, or
+	// our own `or_panic`). Formatting the others would pull in `core::fmt`,
+	// which costs about half a kilobyte of flash, and most of them would be cut
+	// short before their numbers anyway. The file and line are always kept.
+	let message = info.message().as_str().unwrap_or("").as_bytes();
+	let message = &message[..message.len().min(REPORT_LEN - MESSAGE_OFFSET)];
+	report[MESSAGE_OFFSET..MESSAGE_OFFSET + message.len()].copy_from_slice(message);
+	// Logging the report costs far less flash than logging `info` with
+	// `Display2Format`, although the message gets cut short.
+	let report = Report(report);
+	defmt::error!(
+		"Panicked at {=[u8]:a}:{}: {=[u8]:a}",
+		report.file(),
+		report.line(),
+		report.message()
+	);
+	save_and_reset(report.0);
+}
+
+/// Called when something like `defmt::panic!` or `defmt::unwrap!` fails.
+///
+/// The message has already been logged, and we don't get to see it.
+pub fn defmt_panic() -> ! {
+	cortex_m::interrupt::disable();
+	let mut report = [0u8; REPORT_LEN];
+	report[0] = 1;
+	let message = b"defmt panic";
+	report[MESSAGE_OFFSET..MESSAGE_OFFSET + message.len()].copy_from_slice(message);
+	save_and_reset(report);
+}
+
+/// Drop any trailing null padding.
+fn trim_nulls(bytes: &[u8]) -> &[u8] {
+	let len = bytes.iter().position(|b| *b == 0).unwrap_or(bytes.len());
+	&bytes[0..len]
+}
@@ -0,0 +1,225 @@
+//! # Event Log
+//!
+//! Records the interesting things that happen to the system (power
+//! transitions, resets, errors, etc), so the host can find out afterwards
+//! what happened. The log is a ring buffer in RAM - when it is full, the
+//! oldest event is dropped.
+//!
+//! Each event is six bytes:
+//!
+//! | Offset | Length | Contains                                          |
+//! | ------ | ------ | ------------------------------------------------- |
+//! | 0      | 4      | Milliseconds since the BMC booted (`u32le`)       |
+//! | 4      | 1      | The [`Kind`] of event, plus [`PREVIOUS_BOOT`]     |
+//! | 5      | 1      | Extra data, which depends on the kind of event    |
+//!
+//! The timestamps come from SysTick, which stops whilst the MCU is in STOP
+//! mode (see [`crate::standby`]). With the host off they only count the time
+//! we were awake, so they give the order of events but not the time between
+//! them. The RTC keeps running, but we don't have the flash to spare for RTC
+//! timestamps.
+
+use crate::counters::{self, Counter};
+
+/// The length of one event, in bytes.
+pub const EVENT_LEN: usize = 6;
+
+/// How many events we keep in RAM.
+const LOG_LEN: usize = 16;
+
+/// How many of the most recent events we keep in flash.
+pub const SAVED_EVENTS: usize = 3;
+
+/// Set in the kind of an event that we saved to flash and put back in the log
+/// when standby power returned. It happened before we last booted, so its
+/// timestamp counts from an earlier boot.
+pub const PREVIOUS_BOOT: u8 = 0x80;
+
+/// The kinds of thing we log.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, defmt::Format)]
+#[repr(u8)]
+pub enum Kind {
+	/// The BMC booted. The data is the BMC Reset Cause flags.
+	BmcBoot = 1,
+	/// The system was turned on. The data is a [`PowerOnSource`].
+	PowerOn = 2,
+	/// The system was turned off. The data is a [`PowerOffSource`].
+	PowerOff = 3,
+	/// The host was reset. The data is a [`ResetSource`].
+	HostReset = 4,
+	/// A button gesture was recognised. The data is the gesture.
+	Gesture = 5,
+	/// The host watchdog fired. The data is the watchdog reason.
+	HostWatchdog = 6,
+	// 7 is kept for power rail faults. We don't monitor the rails yet.
+	/// Bad data from a PS/2 port. The data is the port (0 or 1).
+	Ps2Error = 8,
+	/// A bad request arrived over SPI. The data is a [`SpiError`].
+	SpiError = 9,
+	/// The BMC had crashed before this boot.
+	BmcCrash = 10,
+	/// The host wrote a POST code. The data is the code.
+	///
+	/// These only go in the POST code log, along with the power on and host
+	/// reset events which show where each boot starts.
+	PostCode = 11,
+}
+
+/// Why the system was turned on.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, defmt::Format)]
+#[repr(u8)]
+pub enum PowerOnSource {
+	/// The power button was pressed
+	Button = 0,
+	/// Standby power returned
+	PowerRestore = 1,
+	/// The end of a power cycle, from a button gesture
+	PowerCycle = 2,
+	/// A key on the PS/2 keyboard
+	Keyboard = 3,
+	/// A character or break on the UART
+	Uart = 4,
+	/// The host's RTC alarm
+	RtcAlarm = 5,
+	/// The end of a power cycle, from the host watchdog
+	HostWatchdog = 6,
+}
+
+/// Why the system was turned off.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, defmt::Format)]
+#[repr(u8)]
+pub enum PowerOffSource {
+	/// The power button was held down
+	Button = 0,
+	/// A button gesture
+	Gesture = 1,
+	/// The host watchdog
+	HostWatchdog = 2,
+	/// The host's RTC alarm
+	RtcAlarm = 3,
+}
+
+/// Why the host was reset.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, defmt::Format)]
+#[repr(u8)]
+pub enum ResetSource {
+	/// The reset button was pressed
+	Button = 0,
+	/// A button gesture
+	Gesture = 1,
+	/// The host watchdog
+	HostWatchdog = 2,
+	/// The host, with the Host Reset Control register
+	Host = 3,
+}
+
+/// What was wrong with a bad SPI request.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, defmt::Format)]
+#[repr(u8)]
+pub enum SpiError {
+	/// The CRC didn't match
+	BadCrc = 0,
+	/// The request type wasn't one we know
+	BadRequestType = 1,
+	/// The request was the wrong length
+	BadLength = 2,
+	/// The request didn't fit in our buffer
+	BufferTooSmall = 3,
+	/// The request had a bad response result
+	BadResponseResult = 4,
+}
+
+/// One entry in the log.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Event {
+	/// Milliseconds the BMC has been awake since it booted
+	pub timestamp_ms: u32,
+	/// What kind of event this is (see [`Kind`])
+	pub kind: u8,
+	/// Extra data about the event
+	pub data: u8,
+}
+
+impl Event {
+	/// An empty slot.
+	pub const EMPTY: Event = Event {
+		timestamp_ms: 0,
+		kind: 0,
+		data: 0,
+	};
+
+	/// Convert to the form we give to the host.
+	pub fn to_bytes(self) -> [u8; EVENT_LEN] {
+		let t = self.timestamp_ms.to_le_bytes();
+		[t[0], t[1], t[2], t[3], self.kind, self.data]
+	}
+
+	/// Is this an empty slot?
+	pub fn is_empty(&self) -> bool {
+		self.kind == 0
+	}
+}
+
+/// A log of events.
+#[derive(Debug, Default)]
+pub struct EventLog {
+	/// The events, oldest first
+	events: heapless::Deque<Event, LOG_LEN>,
+}
+
+impl EventLog {
+	/// Add an event to the log, dropping (and counting) the oldest event if the
+	/// log is full.
+	pub fn push(&mut self, timestamp_ms: u32, kind: Kind, data: u8) {
+		defmt::trace!("Event {:?} 0x{:02x}", kind, data);
+		if self.events.is_full() {
+			counters::increment(Counter::EventLogOverflow);
+		}
+		self.push_event(Event {
+			timestamp_ms,
+			kind: kind as u8,
+			data,
+		});
+	}
+
+	/// Add an event we already have, dropping the oldest event if the log is
+	/// full.
+	pub fn push_event(&mut self, event: Event) {
+		if self.events.is_full() {
+			self.events.pop_front();
+		}
+		let _ = self.events.push_back(event);
+	}
+
+	/// Take the oldest event out of the log.
+	pub fn pop(&mut self) -> Option<Event> {
+		self.events.pop_front()
+	}
+
+	/// How many events are in the log?
+	pub fn len(&self) -> usize {
+		self.events.len()
+	}
+
+	/// Is the log empty?
+	pub fn is_empty(&self) -> bool {
+		self.events.is_empty()
+	}
+
+	/// Throw away every event.
+	pub fn clear(&mut self) {
+		self.events.clear();
+	}
+
+	/// Get the most recent events, oldest first, for saving to flash.
+	///
+	/// Unused slots are left empty.
+	pub fn recent(&self) -> [Event; SAVED_EVENTS] {
+		let mut recent = [Event::EMPTY; SAVED_EVENTS];
+		let skip = self.events.len().saturating_sub(SAVED_EVENTS);
+		for (slot, event) in recent.iter_mut().zip(self.events.iter().skip(skip)) {
+			*slot = *event;
+		}
+		recent
+	}
+}
@@ -0,0 +1,142 @@
+//! # Flash Programming Driver for STM32F0
+//!
+//! The HAL doesn't let us erase or program the flash, so this does it with
+//! direct register access. Note that the CPU stalls whilst the flash is busy,
+//! as we execute from the same flash bank.
+
+use stm32f0xx_hal::pac;
+
+/// The size of one erasable page of flash on the STM32F030x6.
+pub const PAGE_SIZE: usize = 1024;
+
+/// The ways flash programming can fail
+#[derive(Debug, Copy, Clone, PartialEq, Eq, defmt::Format)]
+pub enum Error {
+	/// The address was not in flash, or was not suitably aligned
+	BadAddress,
+	/// The flash controller reported a programming error (the location was
+	/// not erased first)
+	Programming,
+	/// The flash controller reported the page was write-protected
+	WriteProtected,
+	/// The data read back did not match the data written
+	Verify,
+}
+
+/// Lets us erase and program the internal flash.
+pub struct Flash {
+	/// Our PAC object for register access
+	dev: pac::FLASH,
+}
+
+impl Flash {
+	/// Unlock key 1, from the Reference Manual
+	const KEY1: u32 = 0x4567_0123;
+	/// Unlock key 2, from the Reference Manual
+	const KEY2: u32 = 0xCDEF_89AB;
+	/// The start of flash
+	const FLASH_START: usize = 0x0800_0000;
+	/// The end of flash (the STM32F030x6 has 32 KiB)
+	const FLASH_END: usize = Self::FLASH_START + (32 * 1024);
+
+	/// Construct a new driver
+	pub fn new(dev: pac::FLASH) -> Flash {
+		Flash { dev }
+	}
+
+	/// Erase the page which starts at the given address.
+	pub fn erase_page(&mut self, address: usize) -> Result<(), Error> {
+		if !address.is_multiple_of(PAGE_SIZE) || !Self::in_flash(address, PAGE_SIZE) {
+			return Err(Error::BadAddress);
+		}
+		self.unlock();
+		self.dev.cr.modify(|_r, w| w.per().set_bit());
+		self.dev.ar.write(|w| w.far().bits(address as u32));
+		self.dev.cr.modify(|_r, w| w.strt().set_bit());
+		let result = self.wait();
+		self.dev.cr.modify(|_r, w| w.per().clear_bit());
+		self.lock();
+		result?;
+		// Check it all reads back as erased
+		let page = unsafe { core::slice::from_raw_parts(address as *const u8, PAGE_SIZE) };
+		if page.iter().all(|b| *b == 0xFF) {
+			Ok(())
+		} else {
+			Err(Error::Verify)
+		}
+	}
+
+	/// Program some bytes into flash, which must have been erased first.
+	///
+	/// The flash is programmed 16-bits at a time, so the address and the
+	/// length must both be even.
+	pub fn program(&mut self, address: usize, data: &[u8]) -> Result<(), Error> {
+		if !address.is_multiple_of(2)
+			|| !data.len().is_multiple_of(2)
+			|| !Self::in_flash(address, data.len())
+		{
+			return Err(Error::BadAddress);
+		}
+		self.unlock();
+		self.dev.cr.modify(|_r, w| w.pg().set_bit());
+		let mut result = Ok(());
+		for (idx, pair) in data.chunks_exact(2).enumerate() {
+			let half_word = u16::from_le_bytes([pair[0], pair[1]]);
+			let ptr = (address + (idx * 2)) as *mut u16;
+			// Safety: we checked the address is in flash, and the flash
+			// controller is in programming mode.
+			unsafe { ptr.write_volatile(half_word) };
+			result = self.wait();
+			if result.is_err() {
+				break;
+			}
+		}
+		self.dev.cr.modify(|_r, w| w.pg().clear_bit());
+		self.lock();
+		result?;
+		// Check it all reads back correctly
+		let written = unsafe { core::slice::from_raw_parts(address as *const u8, data.len()) };
+		if written == data {
+			Ok(())
+		} else {
+			Err(Error::Verify)
+		}
+	}
+
+	/// Is this range entirely within flash?
+	fn in_flash(address: usize, length: usize) -> bool {
+		address >= Self::FLASH_START && (address + length) <= Self::FLASH_END
+	}
+
+	/// Unlock the flash controller so we can erase and program.
+	fn unlock(&mut self) {
+		if self.dev.cr.read().lock().is_locked() {
+			self.dev.keyr.write(|w| w.fkeyr().bits(Self::KEY1));
+			self.dev.keyr.write(|w| w.fkeyr().bits(Self::KEY2));
+		}
+	}
+
+	/// Lock the flash controller again.
+	fn lock(&mut self) {
+		self.dev.cr.modify(|_r, w| w.lock().set_bit());
+	}
+
+	/// Wait for the current operation to finish, and check how it went.
+	fn wait(&mut self) -> Result<(), Error> {
+		while self.dev.sr.read().bsy().bit_is_set() {
+			// Spin
+		}
+		let sr = self.dev.sr.read();
+		// Clear all the flags (they are write-1-to-clear)
+		self.dev
+			.sr
+			.write(|w| w.eop().set_bit().pgerr().set_bit().wrprt().set_bit());
+		if sr.pgerr().bit_is_set() {
+			Err(Error::Programming)
+		} else if sr.wrprt().bit_is_set() {
+			Err(Error::WriteProtected)
+		} else {
+			Ok(())
+		}
+	}
+}
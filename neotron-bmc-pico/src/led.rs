@@ -0,0 +1,400 @@
+//! # Power LED Patterns
+//!
+//! The power LED plays a pattern of 32 steps, over and over. Bit 0 of the
+//! pattern is the first step, and a one turns the LED on for that step. Each
+//! step is an eighth of a second, doubled as many times as the pattern says.
+//!
+//! Instead of the steps, a pattern can 'breathe' - fading the LED up and down
+//! again every five seconds or so, doubled as many times as the pattern says.
+//!
+//! Normally we pick the pattern - on whilst the host is on, and slowly blinking
+//! whilst it's off - but a fault makes us flash out its beep code (or the last
+//! POST code, if the host hangs), and the host can pick any pattern it likes.
+//! The host's pattern carries on after the host turns off, until the power
+//! button is pressed.
+//!
+//! We time the steps from the RTC, as that keeps counting in STOP mode, so the
+//! pattern carries on in standby. We only wake up every 0.4 seconds or so in
+//! STOP mode though, so short steps come out rather uneven. That's fine for
+//! the standby blink, but it would lose some of the quarter-second flashes of
+//! a fault, so we stay out of STOP mode whilst there's a fault to show. With
+//! the host off, we give up on the fault after [`FAULT_WHILST_OFF_EIGHTHS`],
+//! so that nobody pays for it in standby current for ever.
+//!
+//! Breathing needs PWM, and all the timers stop in STOP mode, so we do it in
+//! software from the `idle` loop and stay out of STOP mode whilst breathing.
+//! That costs a lot more standby current than blinking, so our own standby
+//! pattern blinks, and only the host can ask for breathing.
+
+use crate::{rtc, speaker::BeepCode};
+
+/// Set in the first byte of a pattern if we picked it, rather than the host.
+///
+/// The host writes this to hand the LED back to us.
+pub const AUTOMATIC: u8 = 0x80;
+
+/// Set in the first byte of a pattern to make the LED breathe, instead of
+/// playing the bits.
+pub const BREATHE: u8 = 0x40;
+
+/// The most times a step can be doubled (which makes it four seconds long).
+///
+/// A day is a whole number of patterns with steps up to this long, so the
+/// pattern doesn't jump at midnight.
+pub const MAX_SHIFT: u8 = 5;
+
+/// How long we flash out a fault for whilst the host is off, in eighths of a
+/// second (so, five minutes).
+pub const FAULT_WHILST_OFF_EIGHTHS: u32 = 5 * 60 * 8;
+
+/// Something for the power LED to play.
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub struct Pattern {
+	/// Each step is an eighth of a second, doubled this many times
+	pub shift: u8,
+	/// Whether the LED is on for each step, starting at bit 0
+	pub bits: u32,
+	/// Fade the LED up and down, instead of playing the bits
+	pub breathe: bool,
+}
+
+impl Pattern {
+	/// How long the register is, in bytes
+	pub const LEN: usize = 5;
+
+	/// On, whilst the host is on
+	const ON: Pattern = Pattern {
+		shift: 0,
+		bits: u32::MAX,
+		breathe: false,
+	};
+
+	/// On for a second, and off for a second, whilst the host is off
+	const STANDBY: Pattern = Pattern {
+		shift: 0,
+		bits: 0x00FF_00FF,
+		breathe: false,
+	};
+
+	/// Convert from the step length (plus [`BREATHE`]) and the bits (as a
+	/// `u32le`).
+	///
+	/// Returns `None` if [`AUTOMATIC`] is set, as the host is handing the LED
+	/// back to us.
+	pub fn from_bytes(bytes: &[u8]) -> Option<Pattern> {
+		match *bytes {
+			[shift, b0, b1, b2, b3] if shift & AUTOMATIC == 0 => Some(Pattern {
+				shift: (shift & !BREATHE).min(MAX_SHIFT),
+				bits: u32::from_le_bytes([b0, b1, b2, b3]),
+				breathe: shift & BREATHE != 0,
+			}),
+			_ => None,
+		}
+	}
+
+	/// Convert to the step length (plus [`BREATHE`]) and the bits (as a
+	/// `u32le`).
+	pub fn to_bytes(&self) -> [u8; Self::LEN] {
+		let bits = self.bits.to_le_bytes();
+		let shift = if self.breathe {
+			self.shift | BREATHE
+		} else {
+			self.shift
+		};
+		[shift, bits[0], bits[1], bits[2], bits[3]]
+	}
+}
+
+/// Works out whether the power LED should be on.
+#[derive(Debug, Default)]
+pub struct PowerLed {
+	/// The pattern the host asked for
+	host: Option<Pattern>,
+	/// The bits for the fault (or POST code) we're flashing out (0 = none)
+	fault: u32,
+	/// When we started flashing out the fault with the host off, in eighths
+	/// of a second
+	fault_off_since: Option<u32>,
+	/// The pattern we're playing
+	current: Pattern,
+}
+
+impl PowerLed {
+	/// Play the host's pattern, or let us pick one again if it's `None`.
+	///
+	/// This also clears any fault, as the host has seen it by now.
+	pub fn set_host(&mut self, pattern: Option<Pattern>) {
+		self.host = pattern;
+		self.fault = 0;
+	}
+
+	/// Flash out a beep code, until the host changes the pattern or the power
+	/// button is pressed, or the host has been off for
+	/// [`FAULT_WHILST_OFF_EIGHTHS`].
+	///
+	/// Each beep is a quarter of a second on and a quarter of a second off,
+	/// and then we wait for the rest of the eight seconds.
+	pub fn set_fault(&mut self, code: BeepCode) {
+		self.fault = 0x5555_5555 & !(u32::MAX << (2 * code as u8));
+		self.fault_off_since = None;
+	}
+
+	/// Flash out a POST code, most significant bit first, like a beep code
+	/// (see [`set_fault`](Self::set_fault)).
+	///
+	/// A one is a long flash and a zero is a short flash, with three quarters
+	/// of a second for each bit, and then we wait for the rest of the eight
+	/// seconds.
+	pub fn set_post_code(&mut self, code: u8) {
+		// The first step is bit 0, so we start with the last bit
+		let mut bits = 0;
+		for bit in 0..8 {
+			bits = bits << 3 | u32::from(code >> bit & 1) << 1 | 1;
+		}
+		self.fault = bits;
+		self.fault_off_since = None;
+	}
+
+	/// Are we flashing out a fault (or POST code)?
+	///
+	/// These have steps too short to time from the RTC tick alone.
+	pub fn has_fault(&self) -> bool {
+		self.fault != 0
+	}
+
+	/// Is the LED breathing? That needs [`update`](Self::update) calling
+	/// over and over.
+	pub fn is_breathing(&self) -> bool {
+		self.current.breathe
+	}
+
+	/// Get the pattern we're playing, as the host reads it.
+	pub fn to_bytes(&self) -> [u8; Pattern::LEN] {
+		let mut bytes = self.current.to_bytes();
+		if self.host.is_none() {
+			bytes[0] |= AUTOMATIC;
+		}
+		bytes
+	}
+
+	/// Should the LED be on, at this time of day (in eighths of a second)?
+	///
+	/// Whilst breathing, we use `ticks` (of our 200 Hz clock) instead, and
+	/// the LED is only on for part of each PWM cycle. `pwm` says how far we
+	/// are through the cycle, from 0 to 255.
+	///
+	/// A fault shows over the host's pattern, but the host's pattern carries
+	/// on whether the host is on or not.
+	pub fn update(&mut self, is_on: bool, eighths: u32, ticks: u32, pwm: u8) -> bool {
+		if self.fault != 0 && !is_on {
+			let since = *self.fault_off_since.get_or_insert(eighths);
+			if rtc::eighths_between(since, eighths) >= FAULT_WHILST_OFF_EIGHTHS {
+				self.fault = 0;
+			}
+		} else {
+			self.fault_off_since = None;
+		}
+		let pattern = if self.fault != 0 {
+			Pattern {
+				shift: 1,
+				bits: self.fault,
+				breathe: false,
+			}
+		} else if let Some(pattern) = self.host {
+			pattern
+		} else if is_on {
+			Pattern::ON
+		} else {
+			Pattern::STANDBY
+		};
+		self.current = pattern;
+		if pattern.breathe {
+			breath(ticks >> pattern.shift) > pwm
+		} else {
+			pattern.bits >> ((eighths >> pattern.shift) % 32) & 1 != 0
+		}
+	}
+}
+
+/// How bright the LED is whilst breathing, from 0 to 255. Each breath takes
+/// 1024 ticks (about five seconds).
+fn breath(ticks: u32) -> u8 {
+	// Up for the first half, and back down again for the second
+	let level = if ticks & 512 == 0 {
+		ticks & 511
+	} else {
+		511 - (ticks & 511)
+	};
+	// Our eyes are better at seeing changes when it's dim, so square it
+	((level * level) >> 10) as u8
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+
+	/// See whether the LED is on for each of these eighths of a second.
+	fn run(led: &mut PowerLed, is_on: bool, eighths: core::ops::Range<u32>) -> Vec<bool> {
+		eighths
+			.map(|eighth| led.update(is_on, eighth, 0, 0))
+			.collect()
+	}
+
+	/// Turn some ones and zeros into what we expect from [`run`].
+	fn steps(pattern: &[u8]) -> Vec<bool> {
+		pattern.iter().map(|step| *step != 0).collect()
+	}
+
+	#[test]
+	fn pattern_bytes() {
+		let pattern = Pattern::from_bytes(&[0x02, 0x78, 0x56, 0x34, 0x12]).unwrap();
+		assert_eq!(
+			pattern,
+			Pattern {
+				shift: 2,
+				bits: 0x1234_5678,
+				breathe: false,
+			}
+		);
+		assert_eq!(pattern.to_bytes(), [0x02, 0x78, 0x56, 0x34, 0x12]);
+		// Long steps are cut down to size
+		assert_eq!(
+			Pattern::from_bytes(&[0x3F, 0, 0, 0, 0]).unwrap().shift,
+			MAX_SHIFT
+		);
+		// Breathing
+		let pattern = Pattern::from_bytes(&[BREATHE | 1, 0, 0, 0, 0]).unwrap();
+		assert!(pattern.breathe);
+		assert_eq!(pattern.shift, 1);
+		assert_eq!(pattern.to_bytes(), [BREATHE | 1, 0, 0, 0, 0]);
+		// Handing the LED back, and the wrong length
+		assert_eq!(Pattern::from_bytes(&[AUTOMATIC, 0, 0, 0, 0]), None);
+		assert_eq!(Pattern::from_bytes(&[0, 0, 0, 0]), None);
+	}
+
+	#[test]
+	fn automatic() {
+		let mut led = PowerLed::default();
+		assert!(run(&mut led, true, 0..32).iter().all(|on| *on));
+		assert_eq!(led.to_bytes(), [AUTOMATIC, 0xFF, 0xFF, 0xFF, 0xFF]);
+		// On for a second, and off for a second
+		let mut expected = vec![true; 8];
+		expected.extend([false; 8]);
+		assert_eq!(run(&mut led, false, 0..16), expected);
+		assert_eq!(run(&mut led, false, 32..48), expected);
+		assert_eq!(led.to_bytes(), [AUTOMATIC, 0xFF, 0x00, 0xFF, 0x00]);
+	}
+
+	#[test]
+	fn fault() {
+		let mut led = PowerLed::default();
+		led.set_fault(BeepCode::HostWatchdog);
+		assert!(led.has_fault());
+		// Two quarter-second flashes, then nothing for the rest of the eight
+		// seconds
+		let mut expected = steps(&[1, 1, 0, 0, 1, 1]);
+		expected.extend([false; 58]);
+		assert_eq!(run(&mut led, false, 0..64), expected);
+		assert_eq!(run(&mut led, true, 64..128), expected);
+	}
+
+	#[test]
+	fn fault_gives_up_whilst_off() {
+		let mut led = PowerLed::default();
+		led.set_fault(BeepCode::HostWatchdog);
+		// Time spent on doesn't count
+		led.update(true, 0, 0, 0);
+		led.update(true, FAULT_WHILST_OFF_EIGHTHS, 0, 0);
+		assert!(led.has_fault());
+		// Nor does turning off again
+		led.update(false, 100, 0, 0);
+		led.update(false, 99 + FAULT_WHILST_OFF_EIGHTHS, 0, 0);
+		assert!(led.has_fault());
+		led.update(false, 100 + FAULT_WHILST_OFF_EIGHTHS, 0, 0);
+		assert!(!led.has_fault());
+		// Across midnight, back to blinking for standby
+		led.set_fault(BeepCode::BmcCrash);
+		led.update(false, rtc::EIGHTHS_PER_DAY - 8, 0, 0);
+		led.update(false, FAULT_WHILST_OFF_EIGHTHS - 9, 0, 0);
+		assert!(led.has_fault());
+		led.update(false, FAULT_WHILST_OFF_EIGHTHS - 8, 0, 0);
+		assert!(!led.has_fault());
+		assert_eq!(led.to_bytes(), [AUTOMATIC, 0xFF, 0x00, 0xFF, 0x00]);
+	}
+
+	#[test]
+	fn post_code() {
+		let mut led = PowerLed::default();
+		led.set_post_code(0xA0);
+		assert!(led.has_fault());
+		// Each bit is three quarter-second steps, most significant bit first.
+		// A one is a long flash, and a zero is a short one.
+		let one = [1, 1, 1, 1, 0, 0];
+		let zero = [1, 1, 0, 0, 0, 0];
+		let mut expected = Vec::new();
+		for bit in [one, zero, one, zero, zero, zero, zero, zero] {
+			expected.extend(steps(&bit));
+		}
+		expected.extend([false; 16]);
+		assert_eq!(run(&mut led, false, 0..64), expected);
+	}
+
+	#[test]
+	fn host_pattern() {
+		let mut led = PowerLed::default();
+		led.set_fault(BeepCode::BmcCrash);
+		let pattern = Pattern {
+			shift: 0,
+			bits: 0x0000_0003,
+			breathe: false,
+		};
+		// Picking a pattern clears the fault
+		led.set_host(Some(pattern));
+		assert!(!led.has_fault());
+		let mut expected = steps(&[1, 1]);
+		expected.extend([false; 30]);
+		assert_eq!(run(&mut led, true, 0..32), expected);
+		assert_eq!(led.to_bytes(), pattern.to_bytes());
+		// It carries on after the host turns off
+		assert_eq!(run(&mut led, false, 0..32), expected);
+		// A new fault shows over the top of it
+		led.set_fault(BeepCode::HostWatchdog);
+		assert_eq!(run(&mut led, false, 0..4), steps(&[1, 1, 0, 0]));
+		// Until the host hands the LED back
+		led.set_host(None);
+		assert!(run(&mut led, true, 0..32).iter().all(|on| *on));
+	}
+
+	#[test]
+	fn breathing() {
+		let mut led = PowerLed::default();
+		led.set_host(Some(Pattern {
+			shift: 0,
+			bits: 0,
+			breathe: true,
+		}));
+		// Off at the start of a breath, whatever the time of day
+		assert!(!led.update(false, 0, 0, 0));
+		assert!(led.is_breathing());
+		// Half way up, it's on for a quarter of each PWM cycle, as our eyes
+		// see that as about half as bright
+		assert!(led.update(false, 0, 256, 63));
+		assert!(!led.update(false, 0, 256, 64));
+		// At the top of the breath, and back down again
+		assert!(led.update(false, 0, 511, 254));
+		assert!(led.update(false, 0, 512, 254));
+		assert!(!led.update(false, 0, 1023, 0));
+		// Longer steps make for slower breaths
+		led.set_host(Some(Pattern {
+			shift: 1,
+			bits: 0,
+			breathe: true,
+		}));
+		assert!(led.update(false, 0, 512, 63));
+		assert!(!led.update(false, 0, 512, 64));
+		// A fault stops the breathing
+		led.set_fault(BeepCode::BmcCrash);
+		led.update(false, 0, 0, 0);
+		assert!(!led.is_breathing());
+	}
+}
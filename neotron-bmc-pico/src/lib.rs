@@ -1,20 +1,31 @@
-#![no_std]
+#![cfg_attr(not(test), no_std)]
 
 use core::sync::atomic::{AtomicUsize, Ordering};
 
 use defmt_rtt as _; // global logger
-use panic_probe as _;
-use stm32f0xx_hal as _; // memory layout // panic handler
+use stm32f0xx_hal as _; // memory layout
 
+pub mod bootloader;
+pub mod button;
+pub mod counters;
+pub mod crash; // panic handler
+pub mod event_log;
+pub mod flash;
+pub mod led;
 pub mod ps2;
+pub mod rtc;
+pub mod settings;
 pub mod speaker;
 pub mod spi;
+pub mod standby;
+pub mod wake;
+pub mod watchdog;
 
-// same panicking *behavior* as `panic-probe` but doesn't print a panic message
+// record the crash and reset, but don't print a panic message
 // this prevents the panic message being printed *twice* when `defmt::panic` is invoked
 #[defmt::panic_handler]
 fn panic() -> ! {
-	cortex_m::asm::udf()
+	crash::defmt_panic()
 }
 
 static COUNT: AtomicUsize = AtomicUsize::new(0);
@@ -23,24 +23,64 @@ use stm32f0xx_hal::{
 	pac,
 	prelude::*,
 	rcc, serial,
+	watchdog::Watchdog as IndependentWatchdog,
 };
 
 use neotron_bmc_commands::Command;
-use neotron_bmc_pico::{self as _, speaker};
+use neotron_bmc_pico::{
+	self as _, bootloader, button,
+	counters::{self, Counter},
+	crash,
+	event_log::{self, EventLog, Kind, PowerOffSource, PowerOnSource, ResetSource, SpiError},
+	flash, led, rtc, settings, speaker, standby, wake, watchdog,
+};
 use neotron_bmc_protocol as proto;
 
 /// Version string auto-generated by git.
 static VERSION: [u8; 32] = *include_bytes!(concat!(env!("OUT_DIR"), "/version.txt"));
 
-/// At what rate do we blink the status LED when we're running?
-const LED_PERIOD_MS: u64 = 1000;
+/// The longest Long Write Payload we accept, in bytes
+const MAX_LONG_WRITE: usize = 60;
 
-/// How often we poll the power and reset buttons in milliseconds.
-const DEBOUNCE_POLL_INTERVAL_MS: u64 = 75;
+/// Our SPI peripheral, with room for the longest Long Write Payload and its CRC
+type Spi = neotron_bmc_pico::spi::SpiPeripheral<{ MAX_LONG_WRITE + 1 }, 64>;
 
 /// Length of a reset pulse, in milliseconds
 const RESET_DURATION_MS: u64 = 250;
 
+/// Written to the Host Reset Control register to take the host out of reset.
+/// Values above [`RESET_HOLD`] pulse the reset line for that many lots of
+/// 10 ms.
+const RESET_RELEASE: u8 = 0;
+
+/// Written to the Host Reset Control register to hold the host in reset
+const RESET_HOLD: u8 = 1;
+
+/// Either half of the Boot Reason register, for a power on or reset that
+/// hasn't happened
+const NO_REASON: u8 = 0x0F;
+
+/// How long the power stays off during a power cycle, in milliseconds
+const POWER_CYCLE_OFF_MS: u64 = 2000;
+
+/// How often our own watchdog must be fed, in Hz (so, about once a second)
+const IWDG_FREQUENCY_HZ: u32 = 1;
+
+/// How often the host watchdog counts down, in milliseconds
+const WATCHDOG_TICK_MS: u64 = 1000;
+
+/// How long we stay awake after something happens, before going back into
+/// STOP mode, in milliseconds
+const STAY_AWAKE_MS: u64 = 2000;
+
+/// The soonest we write the power state or the recent events to flash again
+/// after doing so, in eighths of a second. This bounds the flash wear (see
+/// `settings.rs`) however often the system is turned on and off.
+const MIN_SAVE_INTERVAL_EIGHTHS: u32 = 60 * 8;
+
+/// Where the STM32F030's 96-bit Unique Device ID lives
+const UNIQUE_ID_ADDRESS: usize = 0x1FFF_F7AC;
+
 /// The states we can be in controlling the DC power
 #[derive(Copy, Clone, PartialEq, Eq)]
 #[repr(u8)]
@@ -53,6 +93,18 @@ pub enum DcPowerState {
 	Off = 0,
 }
 
+/// The things the host can ask us to do with the settings in flash
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[repr(u8)]
+pub enum SettingsAction {
+	/// Save the current settings to flash
+	Save = 1,
+	/// Go back to the default settings, and erase the settings in flash
+	RestoreDefaults = 2,
+	/// Throw away any changes and re-load the settings from flash
+	Reload = 3,
+}
+
 /// This is our system state, as accessible via SPI reads and writes.
 #[derive(Debug, Default)]
 pub struct RegisterState {
@@ -68,11 +120,102 @@ pub struct RegisterState {
 	last_req: Option<proto::Request>,
 	/// The config of the speaker
 	speaker: speaker::RegisterState,
+	/// Our current settings
+	settings: settings::Settings,
+	/// The settings as they are in flash (or the defaults, if there are none)
+	saved_settings: settings::Settings,
+	/// Did we find some settings in flash?
+	settings_in_flash: bool,
+	/// Did the last attempt to write to flash fail?
+	settings_error: bool,
+	/// The baud rate the UART is running at
+	uart_baud: u32,
+	/// Something to do with the settings, once we've sent our response
+	settings_action: Option<SettingsAction>,
+	/// A write to the Host Reset Control register, to carry out once the
+	/// host has let go of chip select
+	reset_control: Option<u8>,
+	/// Are we holding the host in reset, until we're told to let go?
+	reset_held: bool,
+	/// When to take the host out of reset, at the end of the longest reset
+	/// pulse we've been asked for
+	reset_until: Option<systick_monotonic::fugit::TimerInstantU64<200>>,
+	/// When to turn the system back on at the end of a power cycle, and why
+	power_on_at: Option<(
+		systick_monotonic::fugit::TimerInstantU64<200>,
+		PowerOnSource,
+	)>,
+	/// Why the host was last turned on (bits 0-3), and why it was last reset
+	/// since then (bits 4-7)
+	boot_reason: u8,
+	/// Button gestures we've seen, ready for sending to the host
+	gesture_events: heapless::Deque<u8, 4>,
+	/// Are we running in safe mode (on default settings)?
+	safe_mode: bool,
+	/// Resets the host if it hangs
+	host_watchdog: watchdog::HostWatchdog,
+	/// Why the BMC last reset (the flags from RCC_CSR)
+	bmc_reset_cause: u8,
+	/// Has the BMC reset since the host last acknowledged it?
+	bmc_reset_unacknowledged: bool,
+	/// Why the BMC crashed, if it did so just before this boot
+	crash_report: Option<crash::Report>,
+	/// The things that have happened
+	events: EventLog,
+	/// Should the most recent events be saved to flash?
+	save_events: bool,
+	/// The last POST code the host wrote, since it was turned on or reset
+	post_code: Option<u8>,
+	/// The last POST code the host wrote before it was last turned on or reset
+	previous_post_code: u8,
+	/// The POST codes from recent boots, with a power on or host reset event
+	/// at the start of each boot
+	post_codes: EventLog,
+	/// Holds a snapshot of the diagnostic counters, as we send them
+	counters: [u8; counters::NUM_COUNTERS * 2],
+	/// Should we jump to the bootloader, once the host has let go of us?
+	enter_bootloader: bool,
+	/// Watches the keyboard for key presses, so we can click for them
+	key_presses: neotron_bmc_pico::ps2::PressDetector,
+	/// Picks what the power LED shows
+	power_led: led::PowerLed,
+	/// A Long Write we've accepted, and whether it's a retry, waiting for its
+	/// payload
+	long_write: Option<(proto::Request, bool)>,
+}
+
+impl RegisterState {
+	/// Make our state as it is at start-up.
+	///
+	/// This is kept out of line, because `idle` is so big that LLVM builds
+	/// the defaults and then copies them, if this is inlined into it.
+	#[inline(never)]
+	fn new(
+		loaded_settings: Option<settings::Settings>,
+		bmc_reset_cause: u8,
+		crash_report: Option<crash::Report>,
+	) -> RegisterState {
+		let settings = loaded_settings.unwrap_or_default();
+		// TODO: Get this from the VERSION static variable or from PKG_VERSION
+		RegisterState {
+			firmware_version: VERSION,
+			settings,
+			saved_settings: settings,
+			settings_in_flash: loaded_settings.is_some(),
+			uart_baud: settings.uart_baud,
+			bmc_reset_cause,
+			bmc_reset_unacknowledged: true,
+			crash_report,
+			boot_reason: NO_REASON << 4 | NO_REASON,
+			..Default::default()
+		}
+	}
 }
 
 #[app(device = crate::pac, peripherals = true, dispatchers = [USB, USART3_4_5_6, TIM14, TIM15, TIM16, TIM17, PVD])]
 mod app {
 	use super::*;
+	use rtic::Mutex as _; // Lets our helper functions lock shared resources
 	use systick_monotonic::*; // Implements the `Monotonic` trait
 
 	pub enum Message {
@@ -94,17 +237,25 @@ mod app {
 		PowerButtonRelease,
 		/// The reset button was given a tap
 		ResetButtonShortPress,
+		/// The buttons were used to make a gesture
+		ButtonGesture(button::Gesture),
+		/// Standby power has returned and the power restore delay has passed
+		PowerRestore,
 		/// The UART got some data
 		UartByte(u8),
-		/// The speaker's config should be reset
-		SpeakerDisable,
+		/// The UART receive line went low, whilst the host was off
+		UartEdge,
+		/// The UART receive line went low a while ago, whilst the host was off,
+		/// so it may be a break
+		UartBreak,
+		/// The host's RTC alarm went off
+		RtcAlarm(rtc::AlarmAction),
+		/// Time for the speaker to move on to its next step
+		SpeakerStep,
 	}
 
 	#[shared]
 	struct Shared {
-		/// The power LED (D1101)
-		#[lock_free]
-		led_power: PB0<Output<PushPull>>,
 		/// The speaker (J1006)
 		speaker: speaker::Hardware,
 		/// The FTDI UART header (J105)
@@ -150,26 +301,50 @@ mod app {
 		msg_q_out: Consumer<'static, Message, 8>,
 		/// Write messages here
 		msg_q_in: Producer<'static, Message, 8>,
-		/// SPI Peripheral
-		spi: neotron_bmc_pico::spi::SpiPeripheral<5, 64>,
+		/// SPI Peripheral. It receives a request, or a Long Write Payload and
+		/// its CRC.
+		spi: Spi,
 		/// CS pin
 		pin_cs: PA4<Input<PullDown>>,
 		/// Keyboard PS/2 decoder
 		kb_decoder: neotron_bmc_pico::ps2::Ps2Decoder,
+		/// The button timings (a copy of the one in the `RegisterState`)
+		button_config: button::Config,
 	}
 
 	#[local]
 	struct Local {
-		/// Tracks power button state for short presses. 75ms x 2 = 150ms is a short press
-		press_button_power_short: debouncr::Debouncer<u8, debouncr::Repeat2>,
-		/// Tracks power button state for long presses. 75ms x 16 = 1200ms is a long press
-		press_button_power_long: debouncr::Debouncer<u16, debouncr::Repeat16>,
-		/// Tracks reset button state for short presses. 75ms x 2 = 150ms is a long press
-		press_button_reset_short: debouncr::Debouncer<u8, debouncr::Repeat2>,
+		/// Tracks power button state for short presses.
+		press_button_power_short: button::Debouncer,
+		/// Tracks power button state for long presses.
+		press_button_power_long: button::Debouncer,
+		/// Tracks reset button state for short presses.
+		press_button_reset_short: button::Debouncer,
+		/// Spots double-presses, long holds, etc
+		gestures: button::GestureDetector,
+		/// Where our settings are kept
+		settings_store: settings::Store,
+		/// The settings we loaded at boot (if any)
+		settings: Option<settings::Settings>,
 		/// Run-time Clock Control (required for resetting peripheral blocks)
 		rcc: Option<rcc::Rcc>,
 		/// IRQ pin
 		pin_irq: PA8<Output<PushPull>>,
+		/// The power LED (D1101)
+		led_power: PB0<Output<PushPull>>,
+		/// Resets us if we get stuck
+		iwdg: IndependentWatchdog,
+		/// Why we last reset
+		bmc_reset_cause: u8,
+		/// Why we crashed (if we did)
+		crash_report: Option<crash::Report>,
+		/// Lets us go into STOP mode
+		scb: cortex_m::peripheral::SCB,
+		/// How long to stay awake after boot (which is long enough to restore
+		/// power, if we're doing that)
+		boot_awake: fugit::TimerDurationU64<200>,
+		/// Gives us a tick, even in STOP mode
+		rtc: pac::RTC,
 	}
 
 	#[monotonic(binds = SysTick, default = true)]
@@ -179,7 +354,6 @@ mod app {
 	///
 	/// Sets up the hardware and spawns the regular tasks.
 	///
-	/// * Task `led_power_blink` - blinks the LED
 	/// * Task `button_poll` - checks the power and reset buttons
 	#[init(local = [ queue: Queue<Message, 8> = Queue::new()])]
 	fn init(ctx: init::Context) -> (Shared, Local, init::Monotonics) {
@@ -191,6 +365,35 @@ mod app {
 		let dp: pac::Peripherals = ctx.device;
 		let cp: cortex_m::Peripherals = ctx.core;
 
+		// The bootloader's vector table is still in use, so swap in ours
+		// before any interrupts are enabled.
+		bootloader::relocate_vector_table(&dp.RCC, &dp.SYSCFG);
+
+		// Get ready to save power whilst the host is off
+		standby::setup(&dp.RCC, &dp.PWR);
+		rtc::init(&dp.RCC, &dp.PWR, &dp.RTC, &dp.EXTI);
+
+		let bmc_reset_cause = read_reset_cause(&dp.RCC);
+		defmt::info!("Reset cause: 0x{:02x}", bmc_reset_cause);
+		let crash_report = crash::take();
+		if let Some(report) = &crash_report {
+			defmt::error!(
+				"Recovered from crash at {=[u8]:a}:{}: {=[u8]:a}",
+				report.file(),
+				report.line(),
+				report.message()
+			);
+		}
+
+		// Stop our watchdog whilst the debugger has us halted
+		dp.RCC.apb2enr.modify(|_r, w| w.dbgmcuen().set_bit());
+		dp.DBGMCU
+			.apb1_fz
+			.modify(|_r, w| w.dbg_iwdg_stop().set_bit());
+		// Keep the debugger connected in STOP mode, at the cost of more power
+		#[cfg(feature = "debug-stop")]
+		dp.DBGMCU.cr.modify(|_r, w| w.dbg_stop().set_bit());
+
 		let mut flash = dp.FLASH;
 		let mut rcc = dp
 			.RCC
@@ -200,11 +403,17 @@ mod app {
 			.sysclk(48.mhz())
 			.freeze(&mut flash);
 
-		defmt::info!("Configuring SysTick...");
+		let mut settings_store = settings::Store::new(flash::Flash::new(flash));
+		let loaded_settings = settings_store.load();
+		if loaded_settings.is_none() {
+			defmt::info!("No settings found - using defaults");
+		}
+		let boot_settings = loaded_settings.unwrap_or_default();
+		rtc::set_frequency(boot_settings.rtc_frequency_mhz());
+
 		// Initialize the monotonic timer using the Cortex-M SysTick peripheral
 		let mono = Systick::new(cp.SYST, rcc.clocks.sysclk().0);
 
-		defmt::info!("Creating pins...");
 		let gpioa = dp.GPIOA.split(&mut rcc);
 		let gpiob = dp.GPIOB.split(&mut rcc);
 		let gpiof = dp.GPIOF.split(&mut rcc);
@@ -293,10 +502,12 @@ mod app {
 		// Power LED is off
 		led_power.set_low().unwrap();
 
-		defmt::info!("Creating UART...");
-
-		let mut serial =
-			serial::Serial::usart1(dp.USART1, (uart_tx, uart_rx), 115_200.bps(), &mut rcc);
+		let mut serial = serial::Serial::usart1(
+			dp.USART1,
+			(uart_tx, uart_rx),
+			boot_settings.uart_baud.bps(),
+			&mut rcc,
+		);
 
 		serial.listen(serial::Event::Rxne);
 
@@ -311,6 +522,21 @@ mod app {
 
 		speaker::RegisterState::default().setup(&mut rcc, &dp.TIM14);
 
+		// Set EXTI0 to use PORT F (PF0) - power button, and EXTI1 to use
+		// PORT F (PF1) - reset button
+		dp.SYSCFG
+			.exticr1
+			.modify(|_r, w| w.exti0().pf0().exti1().pf1());
+
+		// Enable EXTI0 and EXTI1 interrupts as external falling edge, so the
+		// buttons wake us up
+		dp.EXTI
+			.imr
+			.modify(|_r, w| w.mr0().set_bit().mr1().set_bit());
+		dp.EXTI
+			.ftsr
+			.modify(|_r, w| w.tr0().set_bit().tr1().set_bit());
+
 		// Set EXTI15 to use PORT A (PA15) - button input
 		dp.SYSCFG.exticr4.modify(|_r, w| w.exti15().pa15());
 
@@ -319,6 +545,11 @@ mod app {
 		dp.EXTI.emr.modify(|_r, w| w.mr15().set_bit());
 		dp.EXTI.ftsr.modify(|_r, w| w.tr15().set_bit());
 
+		// Set EXTI10 to use PORT A (PA10) - UART RX. This is only unmasked
+		// whilst the host is off, so the UART can wake us up.
+		dp.SYSCFG.exticr3.modify(|_r, w| w.exti10().pa10());
+		dp.EXTI.ftsr.modify(|_r, w| w.tr10().set_bit());
+
 		// Set EXTI4 to use PORT A (PA4) - SPI CS
 		dp.SYSCFG.exticr2.modify(|_r, w| w.exti4().pa4());
 
@@ -329,8 +560,26 @@ mod app {
 		dp.EXTI.rtsr.modify(|_r, w| w.tr4().set_bit());
 
 		// Spawn the tasks that run all the time
-		led_power_blink::spawn().unwrap();
-		button_poll::spawn().unwrap();
+		button_poll::spawn().or_panic();
+
+		let mut boot_awake =
+			fugit::MillisDurationU64::from_ticks(STAY_AWAKE_MS).convert::<1, 200>();
+		if boot_settings.should_restore_power() {
+			// Always wait long enough to spot the boot chord, in case they
+			// want safe mode instead.
+			let buttons = &boot_settings.buttons;
+			let chord_ms =
+				u32::from(buttons.poll_interval_ms) * u32::from(buttons.power_long_polls);
+			let delay_ms = boot_settings
+				.power_restore_delay_ms(board_seed())
+				.max(chord_ms);
+			defmt::info!("Restoring power in {} ms", delay_ms);
+			// Convert in 32-bit, as 64-bit division is expensive
+			let delay = fugit::MillisDurationU32::from_ticks(delay_ms).convert::<1, 200>();
+			let delay = fugit::TimerDurationU64::<200>::from_ticks(u64::from(delay.ticks()));
+			send_later::spawn_after(delay, Message::PowerRestore).or_panic();
+			boot_awake += delay;
+		}
 
 		defmt::info!("Init complete!");
 
@@ -340,7 +589,6 @@ mod app {
 			serial,
 			_pin_uart_cts,
 			_pin_uart_rts,
-			led_power,
 			speaker: speaker::Hardware::new(dp.TIM14),
 			button_power,
 			button_reset,
@@ -357,13 +605,27 @@ mod app {
 			spi,
 			pin_cs,
 			kb_decoder: neotron_bmc_pico::ps2::Ps2Decoder::new(),
+			button_config: boot_settings.buttons,
 		};
+		let mut iwdg = IndependentWatchdog::new(dp.IWDG);
+		iwdg.start(IWDG_FREQUENCY_HZ.hz());
+
 		let local_resources = Local {
-			press_button_power_short: debouncr::debounce_2(false),
-			press_button_power_long: debouncr::debounce_16(false),
-			press_button_reset_short: debouncr::debounce_2(false),
+			press_button_power_short: button::Debouncer::new(false),
+			press_button_power_long: button::Debouncer::new(false),
+			press_button_reset_short: button::Debouncer::new(false),
+			gestures: button::GestureDetector::new(),
+			settings_store,
+			settings: loaded_settings,
 			rcc: Some(rcc),
 			pin_irq,
+			led_power,
+			iwdg,
+			bmc_reset_cause,
+			crash_report,
+			scb: cp.SCB,
+			boot_awake,
+			rtc: dp.RTC,
 		};
 		let init = init::Monotonics(mono);
 		(shared_resources, local_resources, init)
@@ -372,20 +634,63 @@ mod app {
 	/// Our idle task.
 	///
 	/// This task is called when there is nothing else to do.
-	#[idle(shared = [msg_q_out, msg_q_in, spi, state_dc_power_enabled, pin_dc_on, pin_sys_reset, speaker], local = [pin_irq, rcc, speaker_task_handle: Option<speaker_pwm_stop::MyMono::SpawnHandle> = None])]
+	#[idle(shared = [msg_q_out, msg_q_in, spi, state_dc_power_enabled, pin_dc_on, pin_sys_reset, speaker, button_config], local = [pin_irq, led_power, rcc, settings_store, settings, iwdg, bmc_reset_cause, crash_report, scb, boot_awake])]
 	fn idle(mut ctx: idle::Context) -> ! {
-		// TODO: Get this from the VERSION static variable or from PKG_VERSION
-		let mut register_state = RegisterState {
-			firmware_version: VERSION,
-			..Default::default()
-		};
+		let mut register_state = RegisterState::new(
+			*ctx.local.settings,
+			*ctx.local.bmc_reset_cause,
+			*ctx.local.crash_report,
+		);
+		// Start with whatever we saved before standby power was lost, marked
+		// so nobody mistakes it for something from this boot
+		for event in register_state.saved_settings.saved_events.iter() {
+			if !event.is_empty() {
+				register_state.events.push_event(event_log::Event {
+					kind: event.kind | event_log::PREVIOUS_BOOT,
+					..*event
+				});
+			}
+		}
+		let bmc_reset_cause = register_state.bmc_reset_cause;
+		log_event(&mut register_state, Kind::BmcBoot, bmc_reset_cause);
+		if register_state.crash_report.is_some() {
+			log_event(&mut register_state, Kind::BmcCrash, 0);
+			// Let whoever is nearby know something went wrong
+			register_state.speaker.beep(speaker::BeepCode::BmcCrash);
+			register_state
+				.power_led
+				.set_fault(speaker::BeepCode::BmcCrash);
+		}
 		// Take this out of the `local` object to avoid sharing issues.
 		let mut rcc = ctx.local.rcc.take().unwrap();
-		defmt::info!("Idle is running...");
 		let mut irq_forced_low = true;
 		let mut is_high = false;
+		// When we can go back into STOP mode
+		let mut awake_until = monotonics::now() + *ctx.local.boot_awake;
+		// Is the UART receive line allowed to wake us up?
+		let mut uart_listening = false;
+		// Is there a `SpeakerStep` on its way?
+		let mut speaker_stepping = false;
+		// Is there a `UartBreak` on its way?
+		let mut uart_break_pending = false;
+		// When the host watchdog next counts down
+		let mut watchdog_tick_at = monotonics::now() + WATCHDOG_TICK_MS.millis();
+		// When we last wrote the power state or events to flash. We haven't
+		// yet, so don't make the first one wait.
+		let mut last_save: Option<u32> = None;
 		loop {
-			if irq_forced_low || !register_state.ps2_kb_bytes.is_empty() {
+			// Something other than the power button wants the system on
+			let mut wake_source = None;
+
+			// We're still running, so stop the watchdog from resetting us
+			ctx.local.iwdg.feed();
+
+			if irq_forced_low
+				|| !register_state.ps2_kb_bytes.is_empty()
+				|| !register_state.gesture_events.is_empty()
+				|| rtc::is_shutdown_requested()
+				|| register_state.speaker.queue_empty()
+			{
 				// We need service
 				ctx.local.pin_irq.set_low().unwrap();
 				if is_high {
@@ -401,69 +706,97 @@ mod app {
 				}
 			}
 
+			// Clippy would have us move the power state checks into match
+			// guards, but then we'd need a catch-all arm, and we want the
+			// compiler to tell us about any message we forget to handle.
+			#[allow(clippy::collapsible_match)]
 			match ctx.shared.msg_q_out.dequeue() {
 				Some(Message::Ps2Data0(word)) => {
 					if let Some(byte) = neotron_bmc_pico::ps2::Ps2Decoder::check_word(word) {
-						defmt::info!("< KB 0x{:x}", byte);
+						defmt::trace!("< KB 0x{:x}", byte);
+						if register_state.key_presses.key(byte) {
+							if register_state.settings.wake.sources() & wake::SOURCE_ANY_KEY != 0
+								&& dc_power_state(&mut ctx.shared) == DcPowerState::Off
+							{
+								wake_source = Some(PowerOnSource::Keyboard);
+							}
+							let key_click = &register_state.settings.key_click;
+							register_state.speaker.click(key_click);
+						}
 						if let Err(_x) = register_state.ps2_kb_bytes.push_back(byte) {
 							defmt::warn!("KB overflow!");
+							counters::increment(Counter::Ps2KbOverflow);
 						}
 					} else {
 						defmt::warn!("< Bad KB 0x{:x}", word);
+						counters::increment(Counter::Ps2KbError);
+						log_event(&mut register_state, Kind::Ps2Error, 0);
 					}
 				}
 				Some(Message::Ps2Data1(word)) => {
 					if let Some(byte) = neotron_bmc_pico::ps2::Ps2Decoder::check_word(word) {
-						defmt::info!("< MS 0x{:x}", byte);
+						defmt::trace!("< MS 0x{:x}", byte);
 					} else {
 						defmt::warn!("< Bad MS 0x{:x}", word);
+						counters::increment(Counter::Ps2MsError);
+						log_event(&mut register_state, Kind::Ps2Error, 1);
 					}
 				}
 				Some(Message::PowerButtonLongPress) => {
-					if ctx.shared.state_dc_power_enabled.lock(|r| *r) == DcPowerState::On {
+					if dc_power_state(&mut ctx.shared) == DcPowerState::On {
 						defmt::info!("Power off requested!");
-						ctx.shared
-							.state_dc_power_enabled
-							.lock(|r| *r = DcPowerState::Off);
-						// Stop any SPI stuff that's currently going on (the host is about to be powered off)
-						ctx.shared.spi.lock(|s| s.reset(&mut rcc));
-						// Put the host into reset
-						ctx.shared.pin_sys_reset.lock(|pin| pin.set_low().unwrap());
-						// Shut off the 5V power
-						ctx.shared.pin_dc_on.set_low().unwrap();
+						// Someone's here, so give them the standby pattern
+						// rather than whatever the host last picked
+						register_state.power_led.set_host(None);
+						power_off(
+							&mut ctx.shared,
+							&mut register_state,
+							&mut rcc,
+							PowerOffSource::Button,
+						);
 						// Mask the IRQ to avoid back-powering the host
 						irq_forced_low = true;
-						// Start LED blinking again
-						led_power_blink::spawn().unwrap();
 					}
 				}
 				Some(Message::PowerButtonShortPress) => {
-					if ctx.shared.state_dc_power_enabled.lock(|r| *r) == DcPowerState::Off {
-						defmt::info!("Power up requested!");
-						// Button pressed - power on system.
-						// Step 1 - enable speaker and play power-up tune
-						ctx.shared.speaker.lock(|speaker| speaker.enable());
-						speaker_init_tune::spawn().unwrap();
-						// Step 2 - Note our new power state
-						ctx.shared
-							.state_dc_power_enabled
-							.lock(|r| *r = DcPowerState::Starting);
-						// Step 3 - Hold reset line (active) low
-						ctx.shared.pin_sys_reset.lock(|pin| pin.set_low().unwrap());
-						// Step 4 - Turn on PSU
-						ctx.shared.pin_dc_on.set_high().unwrap();
-						// Step 5 - Leave it in reset for a while.
-						// TODO: Start monitoring 3.3V and 5.0V rails here
-						// TODO: Take system out of reset when 3.3V and 5.0V are good
-						// Returns an error if it's already scheduled (but we don't care)
-						let _ = exit_reset::spawn_after(RESET_DURATION_MS.millis());
-						// Set 6 - unmask the IRQ
+					if dc_power_state(&mut ctx.shared) == DcPowerState::Off {
+						// Button pressed - power on system, but ignore the
+						// button until it is released. Someone's here, so they
+						// don't need any fault flashing at them.
+						register_state.power_led.set_host(None);
+						power_on(
+							&mut ctx.shared,
+							&mut register_state,
+							DcPowerState::Starting,
+							PowerOnSource::Button,
+						);
+						register_state
+							.host_watchdog
+							.start(&register_state.settings.watchdog);
+						// Unmask the IRQ
+						irq_forced_low = false;
+					}
+				}
+				Some(Message::PowerRestore) => {
+					if register_state.safe_mode {
+						defmt::info!("Not restoring power in safe mode");
+					} else if dc_power_state(&mut ctx.shared) == DcPowerState::Off {
+						power_on(
+							&mut ctx.shared,
+							&mut register_state,
+							DcPowerState::On,
+							PowerOnSource::PowerRestore,
+						);
+						register_state
+							.host_watchdog
+							.start(&register_state.settings.watchdog);
+						// Unmask the IRQ
 						irq_forced_low = false;
 					}
 				}
 				Some(Message::PowerButtonRelease) => {
-					if ctx.shared.state_dc_power_enabled.lock(|r| *r) == DcPowerState::Starting {
-						defmt::info!("Power button released.");
+					if dc_power_state(&mut ctx.shared) == DcPowerState::Starting {
+						defmt::debug!("Power button released.");
 						// Button released after power on. Change the power
 						// state machine t "On". We were in 'Starting' to ignore
 						// any further button events until the button had been
@@ -475,42 +808,139 @@ mod app {
 				}
 				Some(Message::ResetButtonShortPress) => {
 					// Is the board powered on? Don't do a reset if it's powered off.
-					if ctx.shared.state_dc_power_enabled.lock(|r| *r) == DcPowerState::On {
-						defmt::info!("Reset!");
-						ctx.shared.pin_sys_reset.lock(|pin| pin.set_low().unwrap());
-
-						// play power-up tune
-						ctx.shared.speaker.lock(|speaker| speaker.enable());
-						speaker_init_tune::spawn().unwrap();
-
-						ctx.shared.pin_sys_reset.lock(|pin| pin.set_low().unwrap());
-						ctx.shared.spi.lock(|s| s.reset(&mut rcc));
-						// Step 2 - Hold reset line (active) low
-						ctx.shared.pin_sys_reset.lock(|pin| pin.set_low().unwrap());
-						// Step 3 - Take it out of reset in a short while
-						// Returns an error if it's already scheduled (but we don't care)
-						let _ = exit_reset::spawn_after(RESET_DURATION_MS.millis());
+					if dc_power_state(&mut ctx.shared) == DcPowerState::On {
+						reset_host(
+							&mut ctx.shared,
+							&mut register_state,
+							&mut rcc,
+							ResetSource::Button,
+							RESET_DURATION_MS.millis(),
+						);
+						register_state
+							.host_watchdog
+							.start(&register_state.settings.watchdog);
 					}
 				}
+				Some(Message::ButtonGesture(gesture)) => {
+					let action = register_state.settings.buttons.action(gesture);
+					defmt::debug!("Gesture {:?} => {:?}", gesture, action);
+					log_event(&mut register_state, Kind::Gesture, gesture as u8);
+					if register_state
+						.gesture_events
+						.push_back(gesture as u8)
+						.is_err()
+					{
+						defmt::trace!("Gesture overflow!");
+						counters::increment(Counter::GestureOverflow);
+					}
+					let dc_power_state = dc_power_state(&mut ctx.shared);
+					match action {
+						button::Action::None => {}
+						button::Action::PowerOff => {
+							if dc_power_state != DcPowerState::Off {
+								power_off(
+									&mut ctx.shared,
+									&mut register_state,
+									&mut rcc,
+									PowerOffSource::Gesture,
+								);
+								irq_forced_low = true;
+							}
+						}
+						button::Action::PowerCycle => {
+							// There's nothing to cycle if the system is off
+							if dc_power_state != DcPowerState::Off {
+								power_off(
+									&mut ctx.shared,
+									&mut register_state,
+									&mut rcc,
+									PowerOffSource::Gesture,
+								);
+								irq_forced_low = true;
+								register_state.power_on_at = Some((
+									monotonics::now() + POWER_CYCLE_OFF_MS.millis(),
+									PowerOnSource::PowerCycle,
+								));
+								register_state
+									.host_watchdog
+									.start(&register_state.settings.watchdog);
+							}
+						}
+						button::Action::ResetHost => {
+							if dc_power_state != DcPowerState::Off {
+								reset_host(
+									&mut ctx.shared,
+									&mut register_state,
+									&mut rcc,
+									ResetSource::Gesture,
+									RESET_DURATION_MS.millis(),
+								);
+								register_state
+									.host_watchdog
+									.start(&register_state.settings.watchdog);
+							}
+						}
+						button::Action::FactoryReset => {
+							handle_settings_action(
+								SettingsAction::RestoreDefaults,
+								ctx.local.settings_store,
+								&mut register_state,
+							);
+						}
+						button::Action::SafeMode => {
+							// Run on the defaults, but leave what's in flash alone
+							register_state.safe_mode = true;
+							register_state.settings = settings::Settings::default();
+						}
+					}
+					ctx.shared
+						.button_config
+						.lock(|c| *c = register_state.settings.buttons);
+				}
 				Some(Message::SpiEnable) => {
-					if ctx.shared.state_dc_power_enabled.lock(|r| *r) != DcPowerState::Off {
+					if dc_power_state(&mut ctx.shared) != DcPowerState::Off {
 						// Turn on the SPI peripheral and expect four bytes (the
 						// length of a Request).
 						ctx.shared.spi.lock(|s| s.start(4));
 					} else {
 						// Ignore message - it'll be the CS line being pulled low when the host is powered off
-						defmt::info!("Ignoring spurious CS low");
+						defmt::trace!("Ignoring spurious CS low");
 					}
 				}
 				Some(Message::SpiDisable) => {
 					// Turn off the SPI peripheral. Don't need to check power state for this.
 					ctx.shared.spi.lock(|s| s.stop());
+					// If we were waiting for a Long Write Payload, it isn't coming
+					register_state.long_write = None;
 					defmt::trace!("SPI Disable");
+					// Resetting the host resets our SPI peripheral too, so
+					// wait until our response has gone
+					if let Some(control) = register_state.reset_control.take() {
+						control_reset(&mut ctx.shared, &mut register_state, &mut rcc, control);
+					}
+					if register_state.enter_bootloader {
+						bootloader::enter();
+					}
+				}
+				Some(Message::SpiRx) if register_state.long_write.is_some() => {
+					defmt::trace!("SpiRx payload");
+					ctx.shared.spi.lock(|spi| {
+						let result = match spi.get_received() {
+							Some((data, 0)) => process_long_write(data, &mut register_state),
+							_ => {
+								counters::increment(Counter::SpiCrcFailure);
+								proto::ResponseResult::CrcFailure
+							}
+						};
+						let rsp = proto::Response::new_without_data(result);
+						spi.set_transmit_sendable(&rsp).or_panic();
+					});
 				}
 				Some(Message::SpiRx) => {
 					defmt::trace!("SpiRx");
 					// Look for something in the SPI bytes received buffer:
 					let mut req = None;
+					let mut spi_error = None;
 					ctx.shared.spi.lock(|spi| {
 						if let Some((data, crc)) = spi.get_received() {
 							use proto::Receivable;
@@ -526,70 +956,318 @@ mod app {
 									panic!("Wanted 4, got {}", data.len());
 								}
 								Err(e) => {
-									defmt::warn!("Bad Req {:?} ({=[u8]:x}", e, data);
+									defmt::debug!("Bad Req {:?} ({=[u8]:x}", e, data);
+									let (counter, error) = match e {
+										proto::Error::BadCrc => {
+											(Counter::SpiCrcFailure, SpiError::BadCrc)
+										}
+										proto::Error::BadRequestType => {
+											(Counter::SpiBadRequestType, SpiError::BadRequestType)
+										}
+										proto::Error::BadLength => {
+											(Counter::SpiBadLength, SpiError::BadLength)
+										}
+										proto::Error::BufferTooSmall => {
+											(Counter::SpiBufferTooSmall, SpiError::BufferTooSmall)
+										}
+										proto::Error::BadResponseResult => (
+											Counter::SpiBadResponseResult,
+											SpiError::BadResponseResult,
+										),
+									};
+									counters::increment(counter);
+									spi_error = Some(error);
 								}
 							}
 						}
 					});
+					if let Some(error) = spi_error {
+						log_event(&mut register_state, Kind::SpiError, error as u8);
+					}
 
 					// If we got a valid message, queue it so we can look at it next time around
 					if let Some(req) = req {
 						process_command(req, &mut register_state, |rsp| {
 							ctx.shared.spi.lock(|spi| {
-								spi.set_transmit_sendable(rsp).unwrap();
+								spi.set_transmit_sendable(rsp).or_panic();
 							});
 						});
+						if let Some((req, _)) = &register_state.long_write {
+							// The host won't send the payload (and its CRC)
+							// until it has our response
+							let length = usize::from(req.length_or_data) + 1;
+							ctx.shared.spi.lock(|spi| spi.start_payload(length));
+						}
+						// Now the response is on its way, we can take our time with the flash
+						if let Some(action) = register_state.settings_action.take() {
+							handle_settings_action(
+								action,
+								ctx.local.settings_store,
+								&mut register_state,
+							);
+						}
+						// The button timings may have been changed
+						ctx.shared
+							.button_config
+							.lock(|c| *c = register_state.settings.buttons);
+						// So may the UART baud rate
+						if register_state.settings.uart_baud != register_state.uart_baud {
+							register_state.uart_baud = register_state.settings.uart_baud;
+							set_uart_baud(&rcc, register_state.uart_baud);
+						}
 					}
 				}
 				Some(Message::UartByte(rx_byte)) => {
-					defmt::info!("UART RX {:?}", rx_byte);
+					defmt::trace!("UART RX {:?}", rx_byte);
 					// TODO: Copy byte to software buffer and turn UART RX
 					// interrupt off if buffer is full
 				}
-				Some(Message::SpeakerDisable) => {
-					defmt::trace!("Speaker disabled");
-					ctx.shared.speaker.lock(|speaker| speaker.disable());
-					register_state.speaker.set_duration(0);
+				Some(Message::UartEdge) => {
+					// The interrupt turns itself off, so we turn it back on
+					// below
+					uart_listening = false;
+					let sources = register_state.settings.wake.sources();
+					if sources & wake::SOURCE_UART_CHARACTER != 0 {
+						wake_source = Some(PowerOnSource::Uart);
+					} else if sources & wake::SOURCE_UART_BREAK != 0 && !uart_break_pending {
+						// There's room for this in the queue, as there's never
+						// more than one of each kind of message waiting
+						uart_break_pending =
+							send_later::spawn_after(wake::BREAK_MS.millis(), Message::UartBreak)
+								.is_ok();
+					}
+				}
+				Some(Message::UartBreak) => {
+					uart_break_pending = false;
+					// We only look for a break if it's a wake source, and it's
+					// only a break if the line is still low
+					wake_source = wake::is_uart_low().then_some(PowerOnSource::Uart);
+				}
+				Some(Message::RtcAlarm(action)) => {
+					defmt::trace!("RTC alarm: {:?}", action);
+					match action {
+						rtc::AlarmAction::PowerOn => {
+							wake_source = Some(PowerOnSource::RtcAlarm);
+						}
+						rtc::AlarmAction::PowerOff => {
+							if dc_power_state(&mut ctx.shared) == DcPowerState::On {
+								power_off(
+									&mut ctx.shared,
+									&mut register_state,
+									&mut rcc,
+									PowerOffSource::RtcAlarm,
+								);
+								// Mask the IRQ to avoid back-powering the host
+								irq_forced_low = true;
+							}
+						}
+						rtc::AlarmAction::RequestShutdown | rtc::AlarmAction::None => {
+							// The host gets an interrupt for a shutdown request
+						}
+					}
+				}
+				Some(Message::SpeakerStep) => {
+					speaker_stepping = false;
+					register_state.speaker.step();
 				}
 				None => {
 					// No messages
 				}
 			}
 
+			// Nothing below can be lost to a full queue, as we check the
+			// time for each of them on every trip around this loop
+			let now = monotonics::now();
+			// Count down the host watchdog, once a second
+			if now >= watchdog_tick_at {
+				watchdog_tick_at += WATCHDOG_TICK_MS.millis();
+				// The watchdog only runs whilst the host is powered on
+				// (and not held in reset)
+				if dc_power_state(&mut ctx.shared) != DcPowerState::Off
+					&& !register_state.reset_held
+				{
+					let config = register_state.settings.watchdog;
+					if let Some(reason) = register_state.host_watchdog.tick(&config) {
+						// Resetting the host forgets the POST code
+						let post_code = register_state.post_code;
+						log_event(&mut register_state, Kind::HostWatchdog, reason as u8);
+						match (reason, config.action) {
+							(watchdog::Reason::BootRetriesExhausted, _) => {
+								// Leave the host alone
+							}
+							(_, watchdog::Action::Reset) => {
+								reset_host(
+									&mut ctx.shared,
+									&mut register_state,
+									&mut rcc,
+									ResetSource::HostWatchdog,
+									RESET_DURATION_MS.millis(),
+								);
+							}
+							(_, watchdog::Action::PowerCycle) => {
+								power_off(
+									&mut ctx.shared,
+									&mut register_state,
+									&mut rcc,
+									PowerOffSource::HostWatchdog,
+								);
+								irq_forced_low = true;
+								register_state.power_on_at = Some((
+									now + POWER_CYCLE_OFF_MS.millis(),
+									PowerOnSource::HostWatchdog,
+								));
+							}
+						}
+						// Let whoever is nearby know why, and how far the host got
+						register_state.speaker.beep(speaker::BeepCode::HostWatchdog);
+						if let Some(code) = post_code {
+							register_state.power_led.set_post_code(code);
+						} else {
+							register_state
+								.power_led
+								.set_fault(speaker::BeepCode::HostWatchdog);
+						}
+					}
+				}
+			}
+			// Turn the system back on, at the end of a power cycle
+			if let Some((at, source)) = register_state.power_on_at {
+				if now >= at {
+					register_state.power_on_at = None;
+					if dc_power_state(&mut ctx.shared) == DcPowerState::Off {
+						// The host watchdog keeps counting boot attempts
+						// across the power cycle, so don't start it again
+						power_on(
+							&mut ctx.shared,
+							&mut register_state,
+							DcPowerState::On,
+							source,
+						);
+						// Unmask the IRQ
+						irq_forced_low = false;
+					}
+				}
+			}
+			// Take the host out of reset, at the end of the longest pulse
+			if register_state.reset_until.is_some_and(|until| now >= until) {
+				defmt::debug!("End reset");
+				register_state.reset_until = None;
+				// Only if we're still powered on
+				if dc_power_state(&mut ctx.shared) != DcPowerState::Off {
+					// Raising the reset line takes the rest of the system out of reset
+					ctx.shared.pin_sys_reset.lock(|pin| pin.set_high().unwrap());
+				}
+			}
+
+			if let Some(source) = wake_source {
+				if dc_power_state(&mut ctx.shared) == DcPowerState::Off {
+					defmt::info!("Woken by {:?}", source);
+					power_on(
+						&mut ctx.shared,
+						&mut register_state,
+						DcPowerState::On,
+						source,
+					);
+					register_state
+						.host_watchdog
+						.start(&register_state.settings.watchdog);
+					// Unmask the IRQ
+					irq_forced_low = false;
+				}
+			}
+
 			// The speaker PWM needs to be updated (register was updated)
 			if register_state.speaker.needs_update() {
-				defmt::info!("speaker PWM update");
 				register_state.speaker.set_needs_update(false);
+				let note = register_state.speaker.current();
+				ctx.shared
+					.speaker
+					.lock(|speaker| speaker.update(note.as_ref()));
+			}
+			// Keep stepping through the notes until there's nothing to play
+			if register_state.speaker.is_playing() && !speaker_stepping {
+				speaker_stepping = send_later::spawn_after(
+					u64::from(speaker::STEP_MS).millis(),
+					Message::SpeakerStep,
+				)
+				.is_ok();
+			}
+			let is_on = dc_power_state(&mut ctx.shared) != DcPowerState::Off;
+			// We get here at least on every RTC tick, even in STOP mode. SysTick
+			// counts down at 48 MHz, so the PWM cycle for breathing goes round
+			// every 1.4 ms or so, which is too fast to see.
+			let pwm = (cortex_m::peripheral::SYST::get_current() >> 8) as u8;
+			let ticks = monotonics::now().ticks() as u32;
+			if register_state
+				.power_led
+				.update(is_on, rtc::eighths(), ticks, pwm)
+			{
+				ctx.local.led_power.set_high().unwrap();
+			} else {
+				ctx.local.led_power.set_low().unwrap();
+			}
+			// Remember whether we're on or off, so we can restore it if standby power is lost
+			let save_power_state = register_state.settings.power_restore
+				== settings::PowerRestore::LastState
+				&& is_on != register_state.saved_settings.last_power_on;
+			// Remember the most recent events, so we can find out why we turned
+			// off. If the log has been cleared, clear them in flash too.
+			let recent_events = register_state.events.recent();
+			let save_events = register_state.save_events
+				&& (register_state.settings.event_log_persist
+					|| recent_events.iter().all(|e| e.is_empty()))
+				&& recent_events != register_state.saved_settings.saved_events;
+			register_state.save_events = save_events;
+			// Either can wait until we're allowed to write again, as the loop
+			// runs on every RTC tick, even in STOP mode
+			let may_save =
+				last_save.is_none_or(|t| rtc::eighths_since(t) >= MIN_SAVE_INTERVAL_EIGHTHS);
+			if (save_power_state || save_events) && may_save {
+				last_save = Some(rtc::eighths());
+				register_state.save_events = false;
+				save_in_background(ctx.local.settings_store, &mut register_state, |s| {
+					if save_power_state {
+						s.last_power_on = is_on;
+					}
+					if save_events {
+						s.saved_events = recent_events;
+					}
+				});
+			}
 
-				let register = &mut register_state.speaker;
-				let task_handle = ctx.local.speaker_task_handle.take();
+			// TODO: Read ADC for 3.3V and 5.0V rails and check good
 
-				let keep_playing = ctx.shared.speaker.lock(|speaker| {
-					speaker.update(register, || {
-						if let Some(h) = task_handle {
-							// if there's a running "stop" task, reschedule it
-							defmt::trace!("Speaker task cancelled!");
-							h.cancel().unwrap_or_default();
-						}
-					})
-				});
+			// Only listen to the UART receive line whilst the host is off, as
+			// it interrupts us on every bit
+			let listen_uart =
+				!is_on && register_state.settings.wake.sources() & wake::UART_SOURCES != 0;
+			if listen_uart != uart_listening {
+				wake::listen_uart(listen_uart);
+				uart_listening = listen_uart;
+			}
 
-				if keep_playing {
-					// otherwise, spawn a new one
-					defmt::trace!("Speaker task spawned!");
-					ctx.local.speaker_task_handle.replace(
-						speaker_pwm_stop::spawn_after((register.duration() as u64).millis())
-							.unwrap(),
-					);
-				}
+			// With the host off, there's usually nothing to do, so save power
+			// until something happens.
+			if standby::take_activity() {
+				awake_until = monotonics::now() + STAY_AWAKE_MS.millis();
+			}
+			if !is_on
+				&& !register_state.speaker.is_playing()
+				&& !register_state.power_led.has_fault()
+				&& !register_state.power_led.is_breathing()
+				&& register_state.power_on_at.is_none()
+				&& monotonics::now() >= awake_until
+			{
+				let msg_q_out = &ctx.shared.msg_q_out;
+				standby::stop(ctx.local.scb, || !msg_q_out.ready());
 			}
-			// TODO: Read ADC for 3.3V and 5.0V rails and check good
 		}
 	}
 
 	/// This is the external GPIO interrupt task.
 	///
-	/// It handles PS/2 clock edges, and SPI chip select edges.
+	/// It handles PS/2 clock edges, SPI chip select edges, and the UART
+	/// receive line going low whilst the host is off.
 	///
 	/// It is very high priority, as we can't afford to miss a PS/2 clock edge.
 	#[task(
@@ -598,6 +1276,7 @@ mod app {
 		shared = [ps2_clk0, msg_q_in, ps2_dat0, exti, pin_cs, kb_decoder],
 	)]
 	fn exti4_15_interrupt(mut ctx: exti4_15_interrupt::Context) {
+		standby::activity();
 		let pr = ctx.shared.exti.pr.read();
 		// Is this EXT15 (PS/2 Port 0 clock input)
 		if pr.pr15().bit_is_set() {
@@ -605,14 +1284,9 @@ mod app {
 			// Do we have a complete word?
 			if let Some(data) = ctx.shared.kb_decoder.lock(|r| r.add_bit(data_bit)) {
 				// Don't dump in the ISR - we're busy. Add it to this nice lockless queue instead.
-				if ctx
-					.shared
+				ctx.shared
 					.msg_q_in
-					.lock(|q| q.enqueue(Message::Ps2Data0(data)))
-					.is_err()
-				{
-					panic!("queue full");
-				};
+					.lock(|q| send(q, Message::Ps2Data0(data)));
 			}
 			// Clear the pending flag for this pin
 			ctx.shared.exti.pr.write(|w| w.pr15().set_bit());
@@ -626,12 +1300,48 @@ mod app {
 				// If incoming Chip Select is high, tell the main thread to turn off the SPI engine
 				Message::SpiDisable
 			};
-			if ctx.shared.msg_q_in.lock(|q| q.enqueue(msg)).is_err() {
-				panic!("queue full");
-			}
+			ctx.shared.msg_q_in.lock(|q| send(q, msg));
 			// Clear the pending flag for this pin
 			ctx.shared.exti.pr.write(|w| w.pr4().set_bit());
 		}
+
+		if pr.pr10().bit_is_set() {
+			// We only need to hear about the first edge, so stop listening
+			// until `idle` has dealt with it
+			ctx.shared.exti.imr.modify(|_r, w| w.mr10().clear_bit());
+			ctx.shared.msg_q_in.lock(|q| send(q, Message::UartEdge));
+			// Clear the pending flag for this pin
+			ctx.shared.exti.pr.write(|w| w.pr10().set_bit());
+		}
+	}
+
+	/// This is the buttons' external interrupt task.
+	///
+	/// The buttons are polled, but this wakes us up from STOP mode when the
+	/// power or reset button is pressed.
+	#[task(binds = EXTI0_1, priority = 4, shared = [exti])]
+	fn exti0_1_interrupt(ctx: exti0_1_interrupt::Context) {
+		standby::activity();
+		// Clear the pending flags for these pins
+		ctx.shared
+			.exti
+			.pr
+			.write(|w| w.pr0().set_bit().pr1().set_bit());
+	}
+
+	/// This is the RTC task.
+	///
+	/// It fires on every RTC tick, even in STOP mode, and checks the host's
+	/// alarm. Waking up `idle` also moves the power LED's pattern along.
+	#[task(binds = RTC, shared = [msg_q_in], local = [rtc])]
+	fn rtc_interrupt(mut ctx: rtc_interrupt::Context) {
+		rtc::clear_tick(ctx.local.rtc);
+		let now = rtc::now();
+		if let Some(action) = rtc::check_alarm(&now) {
+			ctx.shared
+				.msg_q_in
+				.lock(|q| send(q, Message::RtcAlarm(action)));
+		}
 	}
 
 	/// This is the USART1 task.
@@ -641,36 +1351,17 @@ mod app {
 	#[task(binds = USART1, shared = [serial, msg_q_in])]
 	fn usart1_interrupt(mut ctx: usart1_interrupt::Context) {
 		// Reading the register clears the RX-Not-Empty-Interrupt flag.
-		if let Ok(b) = ctx.shared.serial.read() {
-			let _ = ctx
-				.shared
-				.msg_q_in
-				.lock(|q| q.enqueue(Message::UartByte(b)));
+		match ctx.shared.serial.read() {
+			Ok(b) => {
+				ctx.shared.msg_q_in.lock(|q| send(q, Message::UartByte(b)));
+			}
+			Err(nb::Error::Other(serial::Error::Overrun)) => {
+				counters::increment(Counter::UartOverrun);
+			}
+			Err(_) => {}
 		}
 	}
 
-	/// Initialization melody, played directly by the BMC
-	#[task(shared = [speaker, msg_q_in])]
-	fn speaker_init_tune(mut ctx: speaker_init_tune::Context) {
-		defmt::trace!("Playing startup tone");
-
-		ctx.shared.speaker.lock(|speaker| {
-			// F4
-			speaker.set_note(100, 137, 10);
-		});
-
-		speaker_pwm_stop::spawn_after(100.millis()).unwrap();
-	}
-	/// Task which stops the speaker from playing
-	#[task(shared = [msg_q_in])]
-	fn speaker_pwm_stop(mut ctx: speaker_pwm_stop::Context) {
-		defmt::trace!("Speaker stopped");
-		let _ = ctx
-			.shared
-			.msg_q_in
-			.lock(|q| q.enqueue(Message::SpeakerDisable));
-	}
-
 	/// This is the SPI1 task.
 	///
 	/// It fires whenever there is new data received on SPI1. We should flag to the host
@@ -679,31 +1370,7 @@ mod app {
 	fn spi1_interrupt(mut ctx: spi1_interrupt::Context) {
 		let has_message = ctx.shared.spi.lock(|spi| spi.handle_isr());
 		if has_message {
-			let _ = ctx.shared.msg_q_in.lock(|q| q.enqueue(Message::SpiRx));
-		}
-	}
-
-	/// This is the LED blink task.
-	///
-	/// This task is called periodically. We check whether the status LED is currently on or off,
-	/// and set it to the opposite. This makes the LED blink.
-	#[task(shared = [led_power, state_dc_power_enabled], local = [ led_state: bool = false ])]
-	fn led_power_blink(mut ctx: led_power_blink::Context) {
-		let dc_power_state = ctx.shared.state_dc_power_enabled.lock(|r| *r);
-		match dc_power_state {
-			DcPowerState::Off => {
-				if *ctx.local.led_state {
-					ctx.shared.led_power.set_low().unwrap();
-					*ctx.local.led_state = false;
-				} else {
-					ctx.shared.led_power.set_high().unwrap();
-					*ctx.local.led_state = true;
-				}
-				led_power_blink::spawn_after(LED_PERIOD_MS.millis()).unwrap();
-			}
-			DcPowerState::On | DcPowerState::Starting => {
-				ctx.shared.led_power.set_high().unwrap();
-			}
+			ctx.shared.msg_q_in.lock(|q| send(q, Message::SpiRx));
 		}
 	}
 
@@ -715,105 +1382,404 @@ mod app {
 	/// interrupt.
 	#[task(
 		shared = [
-			led_power, button_power, button_reset, msg_q_in, kb_decoder
+			button_power, button_reset, msg_q_in, kb_decoder, button_config
 		],
-		local = [ press_button_power_short, press_button_power_long, press_button_reset_short ]
+		local = [ press_button_power_short, press_button_power_long, press_button_reset_short, gestures ]
 	)]
 	fn button_poll(mut ctx: button_poll::Context) {
 		// Poll buttons
 		let pwr_pressed: bool = ctx.shared.button_power.is_low().unwrap();
 		let rst_pressed: bool = ctx.shared.button_reset.is_low().unwrap();
 
+		// Stay awake whilst a button is held down
+		if pwr_pressed || rst_pressed {
+			standby::activity();
+		}
+
 		// Poll PS2
 		ctx.shared.kb_decoder.lock(|r| r.poll());
 
+		let config = ctx.shared.button_config.lock(|c| *c);
+
 		// Update state
-		let pwr_short_edge = ctx.local.press_button_power_short.update(pwr_pressed);
-		let pwr_long_edge = ctx.local.press_button_power_long.update(pwr_pressed);
-		let rst_long_edge = ctx.local.press_button_reset_short.update(rst_pressed);
+		let mut pwr_short_edge = ctx
+			.local
+			.press_button_power_short
+			.update(pwr_pressed, config.power_short_polls);
+		let mut pwr_long_edge = ctx
+			.local
+			.press_button_power_long
+			.update(pwr_pressed, config.power_long_polls);
+		let mut rst_long_edge = ctx
+			.local
+			.press_button_reset_short
+			.update(rst_pressed, config.reset_short_polls);
+
+		// Look for gestures
+		if let Some(gesture) = ctx.local.gestures.update(
+			pwr_pressed,
+			rst_pressed,
+			ctx.local.press_button_power_short.is_pressed(),
+			ctx.local.press_button_reset_short.is_pressed(),
+			pwr_short_edge == Some(button::Edge::Rising),
+			&config,
+		) {
+			ctx.shared
+				.msg_q_in
+				.lock(|q| send(q, Message::ButtonGesture(gesture)));
+		}
+
+		if ctx.local.gestures.in_boot_chord() {
+			// Both buttons have been held down since boot, so these edges are
+			// part of the chord and not presses in their own right.
+			pwr_short_edge = None;
+			pwr_long_edge = None;
+			rst_long_edge = None;
+		}
 
 		defmt::trace!(
 			"pwr/rst {}/{} {}/{}/{}",
 			pwr_pressed,
 			rst_pressed,
 			match pwr_short_edge {
-				Some(debouncr::Edge::Rising) => "r",
-				Some(debouncr::Edge::Falling) => "f",
+				Some(button::Edge::Rising) => "r",
+				Some(button::Edge::Falling) => "f",
 				None => "-",
 			},
 			match pwr_long_edge {
-				Some(debouncr::Edge::Rising) => "r",
-				Some(debouncr::Edge::Falling) => "f",
+				Some(button::Edge::Rising) => "r",
+				Some(button::Edge::Falling) => "f",
 				None => "-",
 			},
 			match rst_long_edge {
-				Some(debouncr::Edge::Rising) => "r",
-				Some(debouncr::Edge::Falling) => "f",
+				Some(button::Edge::Rising) => "r",
+				Some(button::Edge::Falling) => "f",
 				None => "-",
 			}
 		);
 
-		if pwr_long_edge == Some(debouncr::Edge::Rising) {
+		if pwr_long_edge == Some(button::Edge::Rising) {
 			// They pressed it a really long time
-			let _ = ctx
-				.shared
+			ctx.shared
 				.msg_q_in
-				.lock(|q| q.enqueue(Message::PowerButtonLongPress));
+				.lock(|q| send(q, Message::PowerButtonLongPress));
 		}
 
 		match pwr_short_edge {
-			Some(debouncr::Edge::Rising) => {
+			Some(button::Edge::Rising) => {
 				// They pressed the power button (could be a short press, could be a long press)
-				let _ = ctx
-					.shared
+				ctx.shared
 					.msg_q_in
-					.lock(|q| q.enqueue(Message::PowerButtonShortPress));
+					.lock(|q| send(q, Message::PowerButtonShortPress));
 			}
-			Some(debouncr::Edge::Falling) => {
+			Some(button::Edge::Falling) => {
 				// They released the power button
-				let _ = ctx
-					.shared
+				ctx.shared
 					.msg_q_in
-					.lock(|q| q.enqueue(Message::PowerButtonRelease));
+					.lock(|q| send(q, Message::PowerButtonRelease));
 			}
 			_ => {
 				// Ignore
 			}
 		}
 
-		if rst_long_edge == Some(debouncr::Edge::Rising) {
+		if rst_long_edge == Some(button::Edge::Rising) {
 			// They pressed the reset button.
-			let _ = ctx
-				.shared
+			ctx.shared
 				.msg_q_in
-				.lock(|q| q.enqueue(Message::ResetButtonShortPress));
+				.lock(|q| send(q, Message::ResetButtonShortPress));
 		}
 
 		// Re-schedule the timer interrupt
-		button_poll::spawn_after(DEBOUNCE_POLL_INTERVAL_MS.millis()).unwrap();
+		button_poll::spawn_after((config.poll_interval_ms as u64).millis()).or_panic();
+	}
+
+	/// Send a message to `idle` later on, like when it's time to restore
+	/// power or to end a power cycle.
+	///
+	/// Every software task costs us flash, so they all share this one. There's
+	/// room for each kind of message we send later to be waiting at once, and
+	/// we never send one whilst another of the same kind is waiting.
+	#[task(shared = [msg_q_in], capacity = 3)]
+	fn send_later(mut ctx: send_later::Context, message: Message) {
+		ctx.shared.msg_q_in.lock(|q| send(q, message));
 	}
 
-	/// Return the reset line high (inactive), but only if we're still powered on.
-	#[task(shared = [pin_sys_reset, state_dc_power_enabled])]
-	fn exit_reset(mut ctx: exit_reset::Context) {
-		defmt::debug!("End reset");
-		if ctx.shared.state_dc_power_enabled.lock(|r| *r) != DcPowerState::Off {
-			// Raising the reset line takes the rest of the system out of reset
-			ctx.shared.pin_sys_reset.lock(|pin| pin.set_high().unwrap());
+	/// Send a message to `idle`, counting it if the queue is full.
+	fn send(q: &mut Producer<'static, Message, 8>, message: Message) {
+		if q.enqueue(message).is_err() {
+			counters::increment(Counter::MessageQueueDrop);
 		}
 	}
+
+	/// Add an event to the event log, timestamped with the current time.
+	///
+	/// POST codes, and the events that start a boot, go in the POST code log.
+	/// The events that start a boot also go in the Boot Reason register.
+	pub(crate) fn log_event(register_state: &mut RegisterState, kind: Kind, data: u8) {
+		let timestamp_ms = monotonics::now().duration_since_epoch().to_millis() as u32;
+		if matches!(kind, Kind::PostCode | Kind::PowerOn | Kind::HostReset) {
+			register_state.post_codes.push_event(event_log::Event {
+				timestamp_ms,
+				kind: kind as u8,
+				data,
+			});
+			if kind != Kind::PostCode {
+				register_state.previous_post_code = register_state.post_code.unwrap_or(0);
+			}
+			register_state.post_code = (kind == Kind::PostCode).then_some(data);
+			if kind == Kind::PostCode {
+				return;
+			}
+			// A power on forgets why we last reset
+			register_state.boot_reason = if kind == Kind::PowerOn {
+				NO_REASON << 4 | data
+			} else {
+				data << 4 | register_state.boot_reason & 0x0F
+			};
+		}
+		register_state.events.push(timestamp_ms, kind, data);
+		if kind == Kind::PowerOff {
+			register_state.save_events = true;
+		}
+	}
+
+	/// Get the DC power state.
+	///
+	/// Every lock costs us flash on the Cortex-M0, so we only take this one
+	/// here.
+	#[inline(never)]
+	fn dc_power_state(shared: &mut idle::SharedResources) -> DcPowerState {
+		shared.state_dc_power_enabled.lock(|r| *r)
+	}
+
+	/// Turn on the DC power and start bringing the host out of reset, and log
+	/// why.
+	///
+	/// Use `DcPowerState::Starting` if the power button is still held down,
+	/// so that further button events are ignored until it is released.
+	fn power_on(
+		shared: &mut idle::SharedResources,
+		register_state: &mut RegisterState,
+		new_state: DcPowerState,
+		source: PowerOnSource,
+	) {
+		defmt::info!("Power up!");
+		log_event(register_state, Kind::PowerOn, source as u8);
+		// Step 1 - play power-up tune
+		let chime = &register_state.settings.boot_chime;
+		register_state.speaker.play_chime(chime);
+		// Step 2 - Note our new power state
+		shared.state_dc_power_enabled.lock(|r| *r = new_state);
+		register_state.reset_held = false;
+		// Don't let the end of an earlier power cycle turn us on again later
+		register_state.power_on_at = None;
+		// Step 3 - Hold reset line (active) low
+		shared.pin_sys_reset.lock(|pin| pin.set_low().unwrap());
+		// Step 4 - Turn on PSU
+		shared.pin_dc_on.set_high().unwrap();
+		// Step 5 - Leave it in reset for a while.
+		// TODO: Start monitoring 3.3V and 5.0V rails here
+		// TODO: Take system out of reset when 3.3V and 5.0V are good
+		register_state.reset_until = Some(monotonics::now() + RESET_DURATION_MS.millis());
+	}
+
+	/// Put the host in reset and turn off the DC power, and log why.
+	fn power_off(
+		shared: &mut idle::SharedResources,
+		register_state: &mut RegisterState,
+		rcc: &mut rcc::Rcc,
+		source: PowerOffSource,
+	) {
+		log_event(register_state, Kind::PowerOff, source as u8);
+		shared
+			.state_dc_power_enabled
+			.lock(|r| *r = DcPowerState::Off);
+		// Stop any SPI stuff that's currently going on (the host is about to be powered off)
+		shared.spi.lock(|s| s.reset(rcc));
+		// Put the host into reset
+		shared.pin_sys_reset.lock(|pin| pin.set_low().unwrap());
+		// Shut off the 5V power
+		shared.pin_dc_on.set_low().unwrap();
+	}
+
+	/// Carry out a write to the Host Reset Control register.
+	fn control_reset(
+		shared: &mut idle::SharedResources,
+		register_state: &mut RegisterState,
+		rcc: &mut rcc::Rcc,
+		control: u8,
+	) {
+		let is_on = dc_power_state(shared) != DcPowerState::Off;
+		if control == RESET_RELEASE {
+			// Let go now, even if we're part way through a pulse
+			register_state.reset_held = false;
+			register_state.reset_until = Some(monotonics::now());
+		} else if is_on {
+			// Our timer ticks every 5 ms, so 10 ms is two ticks
+			let duration = fugit::TimerDurationU64::<200>::from_ticks(u64::from(control) * 2);
+			reset_host(shared, register_state, rcc, ResetSource::Host, duration);
+			// A hold has no end, until we're told to let go
+			register_state.reset_held = control == RESET_HOLD;
+			if register_state.reset_held {
+				register_state.reset_until = None;
+			}
+		}
+		// The host is starting again (or soon will be)
+		register_state
+			.host_watchdog
+			.start(&register_state.settings.watchdog);
+	}
+
+	/// Pulse the host's reset line for `duration`, and log why.
+	///
+	/// This lets go of the host, if we were holding it in reset.
+	fn reset_host(
+		shared: &mut idle::SharedResources,
+		register_state: &mut RegisterState,
+		rcc: &mut rcc::Rcc,
+		source: ResetSource,
+		duration: fugit::TimerDurationU64<200>,
+	) {
+		defmt::info!("Reset!");
+		log_event(register_state, Kind::HostReset, source as u8);
+		register_state.reset_held = false;
+		// Step 1 - play power-up tune
+		let chime = &register_state.settings.boot_chime;
+		register_state.speaker.play_chime(chime);
+		// Step 2 - Hold reset line (active) low
+		shared.pin_sys_reset.lock(|pin| pin.set_low().unwrap());
+		shared.spi.lock(|s| s.reset(rcc));
+		// Step 3 - Take it out of reset in a short while, unless we're part way
+		// through a longer pulse
+		let until = monotonics::now() + duration;
+		register_state.reset_until =
+			Some(register_state.reset_until.map_or(until, |t| t.max(until)));
+	}
+}
+
+/// Change the UART baud rate.
+fn set_uart_baud(rcc: &rcc::Rcc, baud: u32) {
+	defmt::debug!("UART baud rate {}", baud);
+	// The HAL can only set the baud rate when the UART is created, so we do it
+	// ourselves. It can only be changed with the UART disabled.
+	//
+	// Safety: the UART interrupt can't get in whilst we do this
+	let usart = unsafe { &*pac::USART1::ptr() };
+	cortex_m::interrupt::free(|_cs| {
+		usart.cr1.modify(|_r, w| w.ue().clear_bit());
+		usart
+			.brr
+			.write(|w| unsafe { w.bits(rcc.clocks.pclk().0 / baud) });
+		usart.cr1.modify(|_r, w| w.ue().set_bit());
+	});
+}
+
+/// Find out why we last reset, and clear the flags ready for next time.
+///
+/// Returns the reset flags from RCC_CSR, shifted down to start at bit 0.
+fn read_reset_cause(rcc: &pac::RCC) -> u8 {
+	let flags = (rcc.csr.read().bits() >> 25) as u8 & 0x7F;
+	rcc.csr.modify(|_r, w| w.rmvf().set_bit());
+	flags
+}
+
+/// Get a number that is different on every board, but the same every time
+/// the same board boots.
+fn board_seed() -> u32 {
+	let id = UNIQUE_ID_ADDRESS as *const u32;
+	// Safety: the Unique Device ID is always readable and is 96 bits long
+	let words = unsafe { [id.read(), id.add(1).read(), id.add(2).read()] };
+	// Mix the bits up a bit, as the ID is mostly wafer co-ordinates and lot numbers
+	words
+		.iter()
+		.fold(0x811C_9DC5, |acc, w| (acc ^ w).wrapping_mul(0x0100_0193))
+}
+
+/// Update some of the settings in flash, without the host asking us to.
+///
+/// Only the changes made by `update` are saved - any other settings changes
+/// the host hasn't saved are left unsaved.
+fn save_in_background<F>(store: &mut settings::Store, register_state: &mut RegisterState, update: F)
+where
+	F: Fn(&mut settings::Settings),
+{
+	let mut saved = register_state.saved_settings;
+	update(&mut saved);
+	match store.save(&saved) {
+		Ok(()) => {
+			update(&mut register_state.settings);
+			register_state.settings_in_flash = true;
+			register_state.settings_error = false;
+		}
+		Err(e) => {
+			defmt::warn!("Failed to save settings {:?}", e);
+			register_state.settings_error = true;
+		}
+	}
+	// Even if it failed, don't keep trying
+	register_state.saved_settings = saved;
+}
+
+/// Save, restore or reload our settings.
+fn handle_settings_action(
+	action: SettingsAction,
+	store: &mut settings::Store,
+	register_state: &mut RegisterState,
+) {
+	let result = match action {
+		SettingsAction::Save => {
+			defmt::trace!("Saving settings");
+			let result = store.save(&register_state.settings);
+			if result.is_ok() {
+				register_state.saved_settings = register_state.settings;
+				register_state.settings_in_flash = true;
+			}
+			result
+		}
+		SettingsAction::RestoreDefaults => {
+			defmt::trace!("Restoring default settings");
+			register_state.settings = settings::Settings::default();
+			register_state.saved_settings = register_state.settings;
+			register_state.settings_in_flash = false;
+			store.erase()
+		}
+		SettingsAction::Reload => {
+			defmt::trace!("Reloading settings");
+			let loaded_settings = store.load();
+			register_state.settings = loaded_settings.unwrap_or_default();
+			register_state.saved_settings = register_state.settings;
+			register_state.settings_in_flash = loaded_settings.is_some();
+			Ok(())
+		}
+	};
+	if let Err(e) = result {
+		defmt::warn!("Settings error {:?}", e);
+	}
+	register_state.settings_error = result.is_err();
 }
 
 /// Process an incoming command, converting a request into a response.
+///
+/// This is only called from `idle`, but keeping it out of line there saves a
+/// fair bit of flash.
+#[inline(never)]
 fn process_command<F>(req: proto::Request, register_state: &mut RegisterState, rsp_handler: F)
 where
 	F: FnOnce(&proto::Response),
 {
 	if register_state.last_req.as_ref() == Some(&req) {
-		// A duplicate! Resend what we sent last time (so we don't affect FIFOs with a duplicate read).
-		let length = req.length_or_data as usize;
-		let rsp = proto::Response::new_ok_with_data(&register_state.scratch[0..length]);
-		defmt::debug!("Detected a retry");
+		defmt::trace!("Detected a retry");
+		counters::increment(Counter::SpiRetry);
+		let rsp = if req.request_type.flatten() == proto::RequestType::LongWrite {
+			// Take the payload again, but don't apply it twice.
+			register_state.long_write = Some((req, true));
+			proto::Response::new_without_data(proto::ResponseResult::Ok)
+		} else {
+			// A duplicate! Resend what we sent last time (so we don't affect FIFOs with a duplicate read).
+			let length = req.length_or_data as usize;
+			proto::Response::new_ok_with_data(&register_state.scratch[0..length])
+		};
 		rsp_handler(&rsp);
 		return;
 	}
@@ -873,12 +1839,12 @@ where
 			}
 		}
 		(proto::RequestType::Read, Ok(Command::SpeakerDuration)) => {
-			defmt::debug!("Reading speaker duration");
+			defmt::trace!("Reading speaker duration");
 			data[0] = (register_state.speaker.duration() / 10) as u8;
 			proto::Response::new_ok_with_data(&data)
 		}
 		(proto::RequestType::ShortWrite, Ok(Command::SpeakerDuration)) => {
-			defmt::debug!("Writing speaker duration ({})", req.length_or_data);
+			defmt::trace!("Writing speaker duration ({})", req.length_or_data);
 			// This update actually causes the speaker to beep
 			register_state
 				.speaker
@@ -886,35 +1852,590 @@ where
 			proto::Response::new_without_data(proto::ResponseResult::Ok)
 		}
 		(proto::RequestType::Read, Ok(Command::SpeakerPeriodHigh)) => {
-			defmt::debug!("Reading speaker period (high)");
+			defmt::trace!("Reading speaker period (high)");
 			data[0] = register_state.speaker.period_high();
 			proto::Response::new_ok_with_data(&data)
 		}
 		(proto::RequestType::ShortWrite, Ok(Command::SpeakerPeriodHigh)) => {
-			defmt::debug!("Writing speaker period (high = {})", req.length_or_data);
+			defmt::trace!("Writing speaker period (high = {})", req.length_or_data);
 			register_state.speaker.set_period_high(req.length_or_data);
 			proto::Response::new_without_data(proto::ResponseResult::Ok)
 		}
 		(proto::RequestType::Read, Ok(Command::SpeakerPeriodLow)) => {
-			defmt::debug!("Reading speaker period (low)");
+			defmt::trace!("Reading speaker period (low)");
 			data[0] = register_state.speaker.period_low();
 			proto::Response::new_ok_with_data(&data)
 		}
 		(proto::RequestType::ShortWrite, Ok(Command::SpeakerPeriodLow)) => {
-			defmt::debug!("Writing speaker period (low = {})", req.length_or_data);
+			defmt::trace!("Writing speaker period (low = {})", req.length_or_data);
 			register_state.speaker.set_period_low(req.length_or_data);
 			proto::Response::new_without_data(proto::ResponseResult::Ok)
 		}
 		(proto::RequestType::Read, Ok(Command::SpeakerDutyCycle)) => {
-			defmt::debug!("Reading speaker duty cycle");
+			defmt::trace!("Reading speaker duty cycle");
 			data[0] = register_state.speaker.duty_cycle();
 			proto::Response::new_ok_with_data(&data)
 		}
 		(proto::RequestType::ShortWrite, Ok(Command::SpeakerDutyCycle)) => {
-			defmt::debug!("Writing speaker duty cycle ({})", req.length_or_data);
+			defmt::trace!("Writing speaker duty cycle ({})", req.length_or_data);
 			register_state.speaker.set_duty_cycle(req.length_or_data);
 			proto::Response::new_without_data(proto::ResponseResult::Ok)
 		}
+		(proto::RequestType::Read, Ok(Command::SpeakerFrequency)) => {
+			defmt::trace!("Reading speaker frequency");
+			let length = req.length_or_data as usize;
+			if length == 2 {
+				let bytes = register_state.speaker.frequency().to_le_bytes();
+				register_state.scratch[0..2].copy_from_slice(&bytes);
+				proto::Response::new_ok_with_data(&register_state.scratch[0..2])
+			} else {
+				proto::Response::new_without_data(proto::ResponseResult::BadLength)
+			}
+		}
+		(proto::RequestType::Read, Ok(Command::SpeakerMidiNote)) => {
+			defmt::trace!("Reading speaker MIDI note");
+			data[0] = register_state.speaker.midi_note;
+			proto::Response::new_ok_with_data(&data)
+		}
+		(proto::RequestType::ShortWrite, Ok(Command::SpeakerMidiNote)) => {
+			defmt::trace!("Writing speaker MIDI note ({})", req.length_or_data);
+			register_state.speaker.set_midi_note(req.length_or_data);
+			proto::Response::new_without_data(proto::ResponseResult::Ok)
+		}
+		(proto::RequestType::LongWrite, Ok(Command::SpeakerNoteQueue)) => {
+			let length_ok = usize::from(req.length_or_data) <= MAX_LONG_WRITE
+				&& register_state
+					.speaker
+					.can_queue(usize::from(req.length_or_data));
+			start_long_write(req, length_ok, register_state)
+		}
+		(proto::RequestType::Read, Ok(Command::SpeakerQueueControl)) => {
+			defmt::trace!("Reading speaker queue control");
+			let speaker = &register_state.speaker;
+			data[0] = speaker.queue_len() as u8
+				| u8::from(speaker.is_playing()) << 6
+				| u8::from(speaker.queue_empty()) << 7;
+			proto::Response::new_ok_with_data(&data)
+		}
+		(proto::RequestType::ShortWrite, Ok(Command::SpeakerQueueControl)) => {
+			defmt::trace!("Writing speaker queue control ({})", req.length_or_data);
+			register_state.speaker.clear_queue_empty();
+			if req.length_or_data == 1 {
+				register_state.speaker.stop();
+			}
+			proto::Response::new_without_data(proto::ResponseResult::Ok)
+		}
+		(proto::RequestType::Read, Ok(Command::SpeakerBootChime)) => {
+			defmt::trace!("Reading speaker boot chime");
+			let length = req.length_or_data as usize;
+			if length == speaker::CHIME_LEN {
+				let chime = &register_state.settings.boot_chime;
+				register_state.scratch[0..length].copy_from_slice(chime);
+				proto::Response::new_ok_with_data(&register_state.scratch[0..length])
+			} else {
+				proto::Response::new_without_data(proto::ResponseResult::BadLength)
+			}
+		}
+		(proto::RequestType::Read, Ok(Command::SpeakerKeyClick)) => {
+			defmt::trace!("Reading speaker key click");
+			let length = req.length_or_data as usize;
+			if length == speaker::KeyClick::LEN {
+				let bytes = register_state.settings.key_click.to_bytes();
+				register_state.scratch[0..length].copy_from_slice(&bytes);
+				proto::Response::new_ok_with_data(&register_state.scratch[0..length])
+			} else {
+				proto::Response::new_without_data(proto::ResponseResult::BadLength)
+			}
+		}
+		(proto::RequestType::Read, Ok(Command::PowerLedPattern)) => {
+			defmt::trace!("Reading power LED pattern");
+			let length = req.length_or_data as usize;
+			if length == led::Pattern::LEN {
+				let bytes = register_state.power_led.to_bytes();
+				register_state.scratch[0..length].copy_from_slice(&bytes);
+				proto::Response::new_ok_with_data(&register_state.scratch[0..length])
+			} else {
+				proto::Response::new_without_data(proto::ResponseResult::BadLength)
+			}
+		}
+		(proto::RequestType::Read, Ok(Command::ButtonPollInterval)) => {
+			defmt::trace!("Reading button poll interval");
+			data[0] = register_state.settings.buttons.poll_interval_ms;
+			proto::Response::new_ok_with_data(&data)
+		}
+		(proto::RequestType::ShortWrite, Ok(Command::ButtonPollInterval)) => {
+			defmt::trace!("Writing button poll interval ({})", req.length_or_data);
+			register_state
+				.settings
+				.buttons
+				.set_poll_interval_ms(req.length_or_data);
+			proto::Response::new_without_data(proto::ResponseResult::Ok)
+		}
+		(proto::RequestType::Read, Ok(Command::ButtonPowerShortPress)) => {
+			defmt::trace!("Reading power button short press");
+			data[0] = register_state.settings.buttons.power_short_polls;
+			proto::Response::new_ok_with_data(&data)
+		}
+		(proto::RequestType::ShortWrite, Ok(Command::ButtonPowerShortPress)) => {
+			defmt::trace!("Writing power button short press ({})", req.length_or_data);
+			register_state
+				.settings
+				.buttons
+				.set_power_short_polls(req.length_or_data);
+			proto::Response::new_without_data(proto::ResponseResult::Ok)
+		}
+		(proto::RequestType::Read, Ok(Command::ButtonPowerLongPress)) => {
+			defmt::trace!("Reading power button long press");
+			data[0] = register_state.settings.buttons.power_long_polls;
+			proto::Response::new_ok_with_data(&data)
+		}
+		(proto::RequestType::ShortWrite, Ok(Command::ButtonPowerLongPress)) => {
+			defmt::trace!("Writing power button long press ({})", req.length_or_data);
+			register_state
+				.settings
+				.buttons
+				.set_power_long_polls(req.length_or_data);
+			proto::Response::new_without_data(proto::ResponseResult::Ok)
+		}
+		(proto::RequestType::Read, Ok(Command::ButtonResetPress)) => {
+			defmt::trace!("Reading reset button press");
+			data[0] = register_state.settings.buttons.reset_short_polls;
+			proto::Response::new_ok_with_data(&data)
+		}
+		(proto::RequestType::ShortWrite, Ok(Command::ButtonResetPress)) => {
+			defmt::trace!("Writing reset button press ({})", req.length_or_data);
+			register_state
+				.settings
+				.buttons
+				.set_reset_short_polls(req.length_or_data);
+			proto::Response::new_without_data(proto::ResponseResult::Ok)
+		}
+		(proto::RequestType::Read, Ok(Command::ButtonDoublePress)) => {
+			defmt::trace!("Reading button double press");
+			data[0] = register_state.settings.buttons.double_press_polls;
+			proto::Response::new_ok_with_data(&data)
+		}
+		(proto::RequestType::ShortWrite, Ok(Command::ButtonDoublePress)) => {
+			defmt::trace!("Writing button double press ({})", req.length_or_data);
+			register_state
+				.settings
+				.buttons
+				.set_double_press_polls(req.length_or_data);
+			proto::Response::new_without_data(proto::ResponseResult::Ok)
+		}
+		(proto::RequestType::Read, Ok(Command::ButtonPowerVeryLongHold)) => {
+			defmt::trace!("Reading power button very long hold");
+			data[0] = register_state.settings.buttons.power_very_long_polls;
+			proto::Response::new_ok_with_data(&data)
+		}
+		(proto::RequestType::ShortWrite, Ok(Command::ButtonPowerVeryLongHold)) => {
+			defmt::trace!(
+				"Writing power button very long hold ({})",
+				req.length_or_data
+			);
+			register_state
+				.settings
+				.buttons
+				.set_power_very_long_polls(req.length_or_data);
+			proto::Response::new_without_data(proto::ResponseResult::Ok)
+		}
+		(proto::RequestType::Read, Ok(Command::ButtonResetLongHold)) => {
+			defmt::trace!("Reading reset button long hold");
+			data[0] = register_state.settings.buttons.reset_long_polls;
+			proto::Response::new_ok_with_data(&data)
+		}
+		(proto::RequestType::ShortWrite, Ok(Command::ButtonResetLongHold)) => {
+			defmt::trace!("Writing reset button long hold ({})", req.length_or_data);
+			register_state
+				.settings
+				.buttons
+				.set_reset_long_polls(req.length_or_data);
+			proto::Response::new_without_data(proto::ResponseResult::Ok)
+		}
+		(proto::RequestType::Read, Ok(Command::ButtonGestureBuffer)) => {
+			defmt::trace!("Reading ButtonGestureBuffer");
+			let length = req.length_or_data as usize;
+			if length > 0 && length <= register_state.scratch.len() {
+				// First byte is the # gestures in the FIFO
+				register_state.scratch[0] = register_state.gesture_events.len() as u8;
+				// Then as many of those gestures as were asked for
+				for slot in &mut register_state.scratch[1..length] {
+					*slot = register_state.gesture_events.pop_front().unwrap_or(0);
+				}
+				// OK, cache this one because FIFO reads are damaging.
+				register_state.last_req = Some(req);
+				// Send the response
+				proto::Response::new_ok_with_data(&register_state.scratch[0..length])
+			} else {
+				// Can't help you - you want a weird number of bytes
+				proto::Response::new_without_data(proto::ResponseResult::BadLength)
+			}
+		}
+		(proto::RequestType::Read, Ok(Command::GestureActionDoublePress)) => {
+			defmt::trace!("Reading double press action");
+			data[0] = register_state
+				.settings
+				.buttons
+				.action(button::Gesture::PowerDoublePress) as u8;
+			proto::Response::new_ok_with_data(&data)
+		}
+		(proto::RequestType::ShortWrite, Ok(Command::GestureActionDoublePress)) => {
+			defmt::trace!("Writing double press action ({})", req.length_or_data);
+			write_gesture_action(
+				register_state,
+				button::Gesture::PowerDoublePress,
+				req.length_or_data,
+			)
+		}
+		(proto::RequestType::Read, Ok(Command::GestureActionBootChord)) => {
+			defmt::trace!("Reading boot chord action");
+			data[0] = register_state
+				.settings
+				.buttons
+				.action(button::Gesture::BootChord) as u8;
+			proto::Response::new_ok_with_data(&data)
+		}
+		(proto::RequestType::ShortWrite, Ok(Command::GestureActionBootChord)) => {
+			defmt::trace!("Writing boot chord action ({})", req.length_or_data);
+			write_gesture_action(
+				register_state,
+				button::Gesture::BootChord,
+				req.length_or_data,
+			)
+		}
+		(proto::RequestType::Read, Ok(Command::GestureActionResetLongHold)) => {
+			defmt::trace!("Reading reset long hold action");
+			data[0] = register_state
+				.settings
+				.buttons
+				.action(button::Gesture::ResetLongHold) as u8;
+			proto::Response::new_ok_with_data(&data)
+		}
+		(proto::RequestType::ShortWrite, Ok(Command::GestureActionResetLongHold)) => {
+			defmt::trace!("Writing reset long hold action ({})", req.length_or_data);
+			write_gesture_action(
+				register_state,
+				button::Gesture::ResetLongHold,
+				req.length_or_data,
+			)
+		}
+		(proto::RequestType::Read, Ok(Command::GestureActionPowerVeryLongHold)) => {
+			defmt::trace!("Reading power very long hold action");
+			data[0] = register_state
+				.settings
+				.buttons
+				.action(button::Gesture::PowerVeryLongHold) as u8;
+			proto::Response::new_ok_with_data(&data)
+		}
+		(proto::RequestType::ShortWrite, Ok(Command::GestureActionPowerVeryLongHold)) => {
+			defmt::trace!(
+				"Writing power very long hold action ({})",
+				req.length_or_data
+			);
+			write_gesture_action(
+				register_state,
+				button::Gesture::PowerVeryLongHold,
+				req.length_or_data,
+			)
+		}
+		(proto::RequestType::Read, Ok(Command::BmcResetCause)) => {
+			defmt::trace!("Reading BMC reset cause");
+			data[0] = register_state.bmc_reset_cause
+				| u8::from(register_state.bmc_reset_unacknowledged) << 7;
+			proto::Response::new_ok_with_data(&data)
+		}
+		(proto::RequestType::ShortWrite, Ok(Command::BmcResetCause)) => {
+			defmt::trace!("Writing BMC reset cause ({})", req.length_or_data);
+			if req.length_or_data & 0x80 != 0 {
+				register_state.bmc_reset_unacknowledged = false;
+			}
+			proto::Response::new_without_data(proto::ResponseResult::Ok)
+		}
+		(proto::RequestType::Read, Ok(Command::CrashReport)) => {
+			defmt::trace!("Reading crash report");
+			let length = req.length_or_data as usize;
+			if length <= crash::REPORT_LEN {
+				let report = register_state
+					.crash_report
+					.as_ref()
+					.unwrap_or(&crash::Report::EMPTY);
+				proto::Response::new_ok_with_data(&report.as_bytes()[0..length])
+			} else {
+				proto::Response::new_without_data(proto::ResponseResult::BadLength)
+			}
+		}
+		(proto::RequestType::ShortWrite, Ok(Command::CrashReport)) => {
+			defmt::trace!("Clearing crash report");
+			register_state.crash_report = None;
+			proto::Response::new_without_data(proto::ResponseResult::Ok)
+		}
+		(proto::RequestType::Read, Ok(command @ (Command::EventLog | Command::PostCodeLog))) => {
+			defmt::trace!("Reading EventLog or PostCodeLog");
+			let length = req.length_or_data as usize;
+			// Reading takes the event out of the log, so a short read would lose
+			// the rest of it
+			if length == 1 + event_log::EVENT_LEN {
+				let log = if matches!(command, Command::EventLog) {
+					&mut register_state.events
+				} else {
+					&mut register_state.post_codes
+				};
+				// First byte is the # events in the log
+				register_state.scratch[0] = log.len() as u8;
+				// Then the oldest event
+				let event = log.pop().unwrap_or(event_log::Event::EMPTY);
+				register_state.scratch[1..=event_log::EVENT_LEN].copy_from_slice(&event.to_bytes());
+				// OK, cache this one because FIFO reads are damaging.
+				register_state.last_req = Some(req);
+				proto::Response::new_ok_with_data(&register_state.scratch[0..length])
+			} else {
+				proto::Response::new_without_data(proto::ResponseResult::BadLength)
+			}
+		}
+		(proto::RequestType::ShortWrite, Ok(Command::EventLog)) => {
+			defmt::trace!("Clearing event log");
+			register_state.events.clear();
+			register_state.save_events = true;
+			proto::Response::new_without_data(proto::ResponseResult::Ok)
+		}
+		(proto::RequestType::Read, Ok(Command::EventLogPersist)) => {
+			defmt::trace!("Reading event log persist");
+			data[0] = u8::from(register_state.settings.event_log_persist);
+			proto::Response::new_ok_with_data(&data)
+		}
+		(proto::RequestType::ShortWrite, Ok(Command::EventLogPersist)) => {
+			defmt::trace!("Writing event log persist ({})", req.length_or_data);
+			register_state.settings.event_log_persist = req.length_or_data != 0;
+			proto::Response::new_without_data(proto::ResponseResult::Ok)
+		}
+		(proto::RequestType::Read, Ok(Command::DiagnosticCounters)) => {
+			defmt::trace!("Reading diagnostic counters");
+			let length = req.length_or_data as usize;
+			if length <= register_state.counters.len() {
+				counters::read_all(&mut register_state.counters);
+				proto::Response::new_ok_with_data(&register_state.counters[0..length])
+			} else {
+				proto::Response::new_without_data(proto::ResponseResult::BadLength)
+			}
+		}
+		(proto::RequestType::ShortWrite, Ok(Command::DiagnosticCounters)) => {
+			defmt::trace!("Resetting diagnostic counters");
+			counters::reset_all();
+			proto::Response::new_without_data(proto::ResponseResult::Ok)
+		}
+		(proto::RequestType::Read, Ok(Command::PostCode)) => {
+			defmt::trace!("Reading PostCode");
+			data[0] = register_state.post_code.unwrap_or(0);
+			proto::Response::new_ok_with_data(&data)
+		}
+		(proto::RequestType::ShortWrite, Ok(Command::PostCode)) => {
+			defmt::trace!("POST code 0x{:02x}", req.length_or_data);
+			app::log_event(register_state, Kind::PostCode, req.length_or_data);
+			proto::Response::new_without_data(proto::ResponseResult::Ok)
+		}
+		(proto::RequestType::Read, Ok(Command::PreviousPostCode)) => {
+			defmt::trace!("Reading PreviousPostCode");
+			data[0] = register_state.previous_post_code;
+			proto::Response::new_ok_with_data(&data)
+		}
+		(proto::RequestType::Read, Ok(Command::BootReason)) => {
+			defmt::trace!("Reading BootReason");
+			data[0] = register_state.boot_reason;
+			proto::Response::new_ok_with_data(&data)
+		}
+		(proto::RequestType::Read, Ok(Command::HostResetControl)) => {
+			defmt::trace!("Reading HostResetControl");
+			data[0] = u8::from(register_state.reset_held);
+			proto::Response::new_ok_with_data(&data)
+		}
+		(proto::RequestType::ShortWrite, Ok(Command::HostResetControl)) => {
+			defmt::trace!("Host reset control 0x{:02x}", req.length_or_data);
+			register_state.reset_control = Some(req.length_or_data);
+			proto::Response::new_without_data(proto::ResponseResult::Ok)
+		}
+		(proto::RequestType::Read, Ok(Command::SettingsControl)) => {
+			defmt::trace!("Reading settings control");
+			data[0] = u8::from(register_state.settings_in_flash)
+				| u8::from(register_state.safe_mode) << 1
+				| u8::from(register_state.settings_error) << 2
+				| u8::from(register_state.settings != register_state.saved_settings) << 3;
+			proto::Response::new_ok_with_data(&data)
+		}
+		(proto::RequestType::ShortWrite, Ok(Command::SettingsControl)) => {
+			defmt::trace!("Writing settings control ({})", req.length_or_data);
+			// We do this after the response has been sent, as it takes a while
+			register_state.settings_action = match req.length_or_data {
+				1 => Some(SettingsAction::Save),
+				2 => Some(SettingsAction::RestoreDefaults),
+				3 => Some(SettingsAction::Reload),
+				_ => None,
+			};
+			proto::Response::new_without_data(proto::ResponseResult::Ok)
+		}
+		(proto::RequestType::Read, Ok(Command::PowerRestorePolicy)) => {
+			defmt::trace!("Reading power restore policy");
+			data[0] = register_state.settings.power_restore as u8;
+			proto::Response::new_ok_with_data(&data)
+		}
+		(proto::RequestType::ShortWrite, Ok(Command::PowerRestorePolicy)) => {
+			defmt::trace!("Writing power restore policy ({})", req.length_or_data);
+			register_state.settings.power_restore =
+				settings::PowerRestore::from_u8(req.length_or_data);
+			proto::Response::new_without_data(proto::ResponseResult::Ok)
+		}
+		(proto::RequestType::Read, Ok(Command::PowerRestoreDelay)) => {
+			defmt::trace!("Reading power restore delay");
+			data[0] = register_state.settings.power_restore_delay;
+			proto::Response::new_ok_with_data(&data)
+		}
+		(proto::RequestType::ShortWrite, Ok(Command::PowerRestoreDelay)) => {
+			defmt::trace!("Writing power restore delay ({})", req.length_or_data);
+			register_state.settings.power_restore_delay = req.length_or_data;
+			proto::Response::new_without_data(proto::ResponseResult::Ok)
+		}
+		(proto::RequestType::Read, Ok(Command::PowerRestoreRandomDelay)) => {
+			defmt::trace!("Reading power restore random delay");
+			data[0] = register_state.settings.power_restore_random;
+			proto::Response::new_ok_with_data(&data)
+		}
+		(proto::RequestType::ShortWrite, Ok(Command::PowerRestoreRandomDelay)) => {
+			defmt::trace!(
+				"Writing power restore random delay ({})",
+				req.length_or_data
+			);
+			register_state.settings.power_restore_random = req.length_or_data;
+			proto::Response::new_without_data(proto::ResponseResult::Ok)
+		}
+		(proto::RequestType::Read, Ok(Command::WakeSources)) => {
+			defmt::trace!("Reading wake sources");
+			data[0] = register_state.settings.wake.sources();
+			proto::Response::new_ok_with_data(&data)
+		}
+		(proto::RequestType::ShortWrite, Ok(Command::WakeSources)) => {
+			defmt::trace!("Writing wake sources ({})", req.length_or_data);
+			register_state.settings.wake.set_sources(req.length_or_data);
+			proto::Response::new_without_data(proto::ResponseResult::Ok)
+		}
+		(proto::RequestType::Read, Ok(Command::HostWatchdogTimeout)) => {
+			defmt::trace!("Reading host watchdog timeout");
+			data[0] = register_state.host_watchdog.timeout();
+			proto::Response::new_ok_with_data(&data)
+		}
+		(proto::RequestType::ShortWrite, Ok(Command::HostWatchdogTimeout)) => {
+			defmt::trace!("Writing host watchdog timeout ({})", req.length_or_data);
+			register_state.host_watchdog.set_timeout(req.length_or_data);
+			proto::Response::new_without_data(proto::ResponseResult::Ok)
+		}
+		(proto::RequestType::ShortWrite, Ok(Command::HostWatchdogKick)) => {
+			defmt::trace!("Kicking host watchdog");
+			register_state.host_watchdog.kick();
+			proto::Response::new_without_data(proto::ResponseResult::Ok)
+		}
+		(proto::RequestType::Read, Ok(Command::HostWatchdogAction)) => {
+			defmt::trace!("Reading host watchdog action");
+			data[0] = register_state.settings.watchdog.action as u8;
+			proto::Response::new_ok_with_data(&data)
+		}
+		(proto::RequestType::ShortWrite, Ok(Command::HostWatchdogAction)) => {
+			defmt::trace!("Writing host watchdog action ({})", req.length_or_data);
+			register_state.settings.watchdog.action = watchdog::Action::from_u8(req.length_or_data);
+			proto::Response::new_without_data(proto::ResponseResult::Ok)
+		}
+		(proto::RequestType::Read, Ok(Command::HostWatchdogCount)) => {
+			defmt::trace!("Reading host watchdog count");
+			data[0] = register_state.host_watchdog.count();
+			proto::Response::new_ok_with_data(&data)
+		}
+		(proto::RequestType::Read, Ok(Command::HostWatchdogReason)) => {
+			defmt::trace!("Reading host watchdog reason");
+			data[0] = register_state.host_watchdog.reason() as u8;
+			proto::Response::new_ok_with_data(&data)
+		}
+		(proto::RequestType::Read, Ok(Command::HostBootTimeout)) => {
+			defmt::trace!("Reading host boot timeout");
+			data[0] = register_state.settings.watchdog.boot_timeout_secs;
+			proto::Response::new_ok_with_data(&data)
+		}
+		(proto::RequestType::ShortWrite, Ok(Command::HostBootTimeout)) => {
+			defmt::trace!("Writing host boot timeout ({})", req.length_or_data);
+			register_state.settings.watchdog.boot_timeout_secs = req.length_or_data;
+			proto::Response::new_without_data(proto::ResponseResult::Ok)
+		}
+		(proto::RequestType::Read, Ok(Command::HostBootRetries)) => {
+			defmt::trace!("Reading host boot retries");
+			data[0] = register_state.settings.watchdog.boot_retries;
+			proto::Response::new_ok_with_data(&data)
+		}
+		(proto::RequestType::ShortWrite, Ok(Command::HostBootRetries)) => {
+			defmt::trace!("Writing host boot retries ({})", req.length_or_data);
+			register_state.settings.watchdog.boot_retries = req.length_or_data;
+			proto::Response::new_without_data(proto::ResponseResult::Ok)
+		}
+		(proto::RequestType::Read, Ok(Command::FirmwareUpdateControl)) => {
+			defmt::trace!("Reading firmware update control");
+			// Updates are handled by the bootloader, not us
+			data[0] = 0;
+			proto::Response::new_ok_with_data(&data)
+		}
+		(proto::RequestType::ShortWrite, Ok(Command::FirmwareUpdateControl)) => {
+			defmt::trace!("Writing firmware update control ({})", req.length_or_data);
+			if req.length_or_data == 1 {
+				// We go once our response has been sent
+				register_state.enter_bootloader = true;
+			}
+			proto::Response::new_without_data(proto::ResponseResult::Ok)
+		}
+		(proto::RequestType::Read, Ok(Command::UartBaudRate)) => {
+			defmt::trace!("Reading UART baud rate");
+			let length = req.length_or_data as usize;
+			if length == 4 {
+				let bytes = register_state.settings.uart_baud.to_le_bytes();
+				register_state.scratch[0..4].copy_from_slice(&bytes);
+				proto::Response::new_ok_with_data(&register_state.scratch[0..4])
+			} else {
+				proto::Response::new_without_data(proto::ResponseResult::BadLength)
+			}
+		}
+		(proto::RequestType::Read, Ok(Command::RtcDateTime | Command::RtcAlarm)) => {
+			defmt::trace!("Reading RTC 0x{:02x}", req.register);
+			let length = req.length_or_data as usize;
+			if length == 6 {
+				let date_time = if req.register == Command::RtcDateTime as u8 {
+					rtc::now()
+				} else {
+					rtc::alarm()
+				};
+				register_state.scratch[0..6].copy_from_slice(&date_time.to_bytes());
+				proto::Response::new_ok_with_data(&register_state.scratch[0..6])
+			} else {
+				proto::Response::new_without_data(proto::ResponseResult::BadLength)
+			}
+		}
+		(proto::RequestType::Read, Ok(Command::RtcAlarmAction)) => {
+			defmt::trace!("Reading RTC alarm action");
+			data[0] = rtc::alarm_status();
+			proto::Response::new_ok_with_data(&data)
+		}
+		(proto::RequestType::ShortWrite, Ok(Command::RtcAlarmAction)) => {
+			defmt::trace!("Writing RTC alarm action ({})", req.length_or_data);
+			rtc::set_alarm_action(rtc::AlarmAction::from_u8(req.length_or_data));
+			proto::Response::new_without_data(proto::ResponseResult::Ok)
+		}
+		(proto::RequestType::Read, Ok(Command::RtcFrequency)) => {
+			defmt::trace!("Reading RTC frequency");
+			let length = req.length_or_data as usize;
+			if length == 4 {
+				let bytes = register_state.settings.rtc_frequency_mhz().to_le_bytes();
+				register_state.scratch[0..4].copy_from_slice(&bytes);
+				proto::Response::new_ok_with_data(&register_state.scratch[0..4])
+			} else {
+				proto::Response::new_without_data(proto::ResponseResult::BadLength)
+			}
+		}
+		(proto::RequestType::LongWrite, Ok(command)) if long_write_length(command).is_some() => {
+			let length_ok = long_write_length(command) == Some(usize::from(req.length_or_data));
+			start_long_write(req, length_ok, register_state)
+		}
 		_ => {
 			// Sorry, that register / request type is not supported
 			defmt::warn!(
@@ -922,6 +2443,7 @@ where
 				req.request_type,
 				req.register
 			);
+			counters::increment(Counter::SpiBadRegister);
 			proto::Response::new_without_data(proto::ResponseResult::BadRegister)
 		}
 	};
@@ -929,4 +2451,136 @@ where
 	// defmt::debug!("Sent {:?}", rsp);
 }
 
+/// Accept a Long Write, if it has a length we can take, and wait for the
+/// payload.
+fn start_long_write(
+	req: proto::Request,
+	length_ok: bool,
+	register_state: &mut RegisterState,
+) -> proto::Response<'static> {
+	defmt::trace!("Long write to 0x{:02x}", req.register);
+	if length_ok {
+		register_state.long_write = Some((req, false));
+		proto::Response::new_without_data(proto::ResponseResult::Ok)
+	} else {
+		proto::Response::new_without_data(proto::ResponseResult::BadLength)
+	}
+}
+
+/// How long the payload must be, for a Long Write to a register which always
+/// takes the same number of bytes.
+fn long_write_length(command: Command) -> Option<usize> {
+	match command {
+		Command::SpeakerFrequency => Some(2),
+		Command::SpeakerBootChime => Some(speaker::CHIME_LEN),
+		Command::SpeakerKeyClick => Some(speaker::KeyClick::LEN),
+		Command::PowerLedPattern => Some(led::Pattern::LEN),
+		Command::RtcDateTime | Command::RtcAlarm => Some(6),
+		Command::RtcFrequency => Some(4),
+		Command::UartBaudRate => Some(4),
+		_ => None,
+	}
+}
+
+/// Set what we do for a gesture, if the host gave us an action we know.
+fn write_gesture_action(
+	register_state: &mut RegisterState,
+	gesture: button::Gesture,
+	value: u8,
+) -> proto::Response<'static> {
+	match button::Action::from_u8(value) {
+		Some(action) => {
+			register_state.settings.buttons.set_action(gesture, action);
+			proto::Response::new_without_data(proto::ResponseResult::Ok)
+		}
+		None => proto::Response::new_without_data(proto::ResponseResult::BadLength),
+	}
+}
+
+/// Handle the payload of the Long Write we accepted, which has passed its CRC
+/// check.
+fn process_long_write(payload: &[u8], register_state: &mut RegisterState) -> proto::ResponseResult {
+	let Some((req, is_retry)) = register_state.long_write.take() else {
+		return proto::ResponseResult::BadRequestType;
+	};
+	if is_retry {
+		// We did this last time
+		return proto::ResponseResult::Ok;
+	}
+	// Drop the CRC
+	let payload = &payload[0..payload.len() - 1];
+	let ok = match Command::try_from(req.register) {
+		Ok(Command::RtcDateTime) => rtc::DateTime::from_bytes(payload)
+			.map(|date_time| rtc::set_now(&date_time))
+			.is_some(),
+		Ok(Command::RtcAlarm) => rtc::DateTime::from_bytes(payload)
+			.map(|date_time| rtc::set_alarm(&date_time))
+			.is_some(),
+		Ok(Command::SpeakerNoteQueue) => register_state.speaker.queue_notes(payload),
+		Ok(Command::SpeakerFrequency) => {
+			let frequency = u16::from_le_bytes([payload[0], payload[1]]);
+			register_state.speaker.set_frequency(frequency);
+			true
+		}
+		Ok(Command::SpeakerBootChime) => {
+			register_state.settings.boot_chime.copy_from_slice(payload);
+			true
+		}
+		Ok(Command::SpeakerKeyClick) => {
+			register_state.settings.key_click = speaker::KeyClick::from_bytes(payload);
+			true
+		}
+		Ok(Command::PowerLedPattern) => {
+			register_state
+				.power_led
+				.set_host(led::Pattern::from_bytes(payload));
+			true
+		}
+		Ok(Command::RtcFrequency) => {
+			let mut bytes = [0u8; 4];
+			bytes.copy_from_slice(payload);
+			let settings = &mut register_state.settings;
+			settings.set_rtc_frequency_mhz(u32::from_le_bytes(bytes));
+			rtc::set_frequency(settings.rtc_frequency_mhz());
+			true
+		}
+		Ok(Command::UartBaudRate) => {
+			let mut bytes = [0u8; 4];
+			bytes.copy_from_slice(payload);
+			let baud = u32::from_le_bytes(bytes);
+			// `idle` switches the UART over once the response has gone
+			let valid = (settings::Settings::MIN_UART_BAUD..=settings::Settings::MAX_UART_BAUD)
+				.contains(&baud);
+			if valid {
+				register_state.settings.set_uart_baud(baud);
+			}
+			valid
+		}
+		_ => false,
+	};
+	if ok {
+		// So we can spot a retry
+		register_state.last_req = Some(req);
+		proto::ResponseResult::Ok
+	} else {
+		proto::ResponseResult::BadLength
+	}
+}
+
+/// Lets us unwrap a `Result` whose error doesn't implement `Debug`, like the
+/// `Message` that comes back when a spawn fails.
+trait OrPanic<T> {
+	/// Get the value, or panic if there's an error.
+	fn or_panic(self) -> T;
+}
+
+impl<T, E> OrPanic<T> for Result<T, E> {
+	fn or_panic(self) -> T {
+		match self {
+			Ok(value) => value,
+			Err(_) => panic!("unwrap failed"),
+		}
+	}
+}
+
 // End of file
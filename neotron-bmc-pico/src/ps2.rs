@@ -3,6 +3,9 @@
 //! Like the one in 'pc_keyboard' but simpler. Designed for use when you want to
 //! collect the bits but not decode the bytes.
 
+/// The scancode which comes before a key's release code
+pub const KEY_RELEASE: u8 = 0xF0;
+
 /// Handles decoding incoming PS/2 packets
 ///
 /// Each packet has 11 bits:
@@ -18,6 +21,12 @@ pub struct Ps2Decoder {
 	ticks: u8,
 }
 
+impl Default for Ps2Decoder {
+	fn default() -> Ps2Decoder {
+		Ps2Decoder::new()
+	}
+}
+
 impl Ps2Decoder {
 	const MAX_TICKS_BEFORE_RESET: u8 = 3;
 
@@ -82,7 +91,7 @@ impl Ps2Decoder {
 			return None;
 		}
 
-		let need_parity = (data.count_ones() % 2) == 0;
+		let need_parity = data.count_ones().is_multiple_of(2);
 
 		// Check we have the correct parity bit
 		if need_parity != parity_bit {
@@ -92,3 +101,93 @@ impl Ps2Decoder {
 		Some(data)
 	}
 }
+
+/// Spots key presses in the scancodes from a keyboard, ignoring key releases
+/// and the repeats a keyboard sends whilst a key is held down.
+#[derive(Debug, Default)]
+pub struct PressDetector {
+	/// Was the last scancode a key release prefix?
+	after_release: bool,
+	/// The key last pressed, until it is released (0 = none)
+	held: u8,
+}
+
+impl PressDetector {
+	/// Look at a scancode from the keyboard.
+	///
+	/// Returns `true` if a key has just been pressed.
+	pub fn key(&mut self, scancode: u8) -> bool {
+		let is_release = self.after_release;
+		self.after_release = scancode == KEY_RELEASE;
+		if !is_key_code(scancode) {
+			return false;
+		}
+		if is_release {
+			if scancode == self.held {
+				self.held = 0;
+			}
+			return false;
+		}
+		let is_repeat = scancode == self.held;
+		self.held = scancode;
+		!is_repeat
+	}
+}
+
+/// Is this the code for a key, rather than a prefix or a status report from
+/// the keyboard?
+pub fn is_key_code(scancode: u8) -> bool {
+	// 0xAA is the keyboard passing its self-test, which happens when it gets
+	// power, and everything from 0xE0 upwards is a prefix or a reply.
+	scancode != 0x00 && scancode != 0xAA && scancode < 0xE0
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+
+	/// Feed in each scancode, and collect what comes back.
+	fn presses(scancodes: &[u8]) -> Vec<bool> {
+		let mut detector = PressDetector::default();
+		scancodes.iter().map(|code| detector.key(*code)).collect()
+	}
+
+	#[test]
+	fn make_break_and_repeat() {
+		// Press A, with the keyboard repeating it, then let go, then press it
+		// again
+		assert_eq!(
+			presses(&[0x1C, 0x1C, 0x1C, 0xF0, 0x1C, 0x1C]),
+			[true, false, false, false, false, true]
+		);
+	}
+
+	#[test]
+	fn extended_keys() {
+		// Up arrow, repeated, released and pressed again
+		assert_eq!(
+			presses(&[0xE0, 0x75, 0xE0, 0x75, 0xE0, 0xF0, 0x75, 0xE0, 0x75]),
+			[false, true, false, false, false, false, false, false, true]
+		);
+	}
+
+	#[test]
+	fn rollover() {
+		// Press A, then S whilst A is down. The keyboard only repeats S,
+		// and letting go of A doesn't make the next S a new press.
+		assert_eq!(
+			presses(&[0x1C, 0x1B, 0x1B, 0xF0, 0x1C, 0x1B, 0xF0, 0x1B, 0x1C]),
+			[true, true, false, false, false, false, false, false, true]
+		);
+	}
+
+	#[test]
+	fn not_keys() {
+		// Self-test passed, an acknowledgement, and a buffer overrun, in the
+		// middle of a held key
+		assert_eq!(
+			presses(&[0xAA, 0x1C, 0xFA, 0x1C, 0x00, 0x1C]),
+			[false, true, false, false, false, false]
+		);
+	}
+}
@@ -0,0 +1,486 @@
+//! # Real-Time Clock
+//!
+//! The RTC runs from the LSI, so it keeps going whilst we're in STOP mode, and
+//! across BMC resets, for as long as we have standby power. It keeps the date
+//! and time for the host, and its alarm gives us a tick which wakes us up a
+//! couple of times a second.
+//!
+//! The LQFP-32 STM32F030 has no pins for a 32.768 kHz crystal, so the LSI is
+//! all we have. It is only accurate to a few percent, so the host can tell us
+//! how fast it really runs, and we trim the prescalers and the smooth
+//! calibration to suit.
+//!
+//! The host's alarm is kept in the backup registers, so it also survives a BMC
+//! reset. We check it on every tick.
+
+use stm32f0xx_hal::pac;
+
+/// The asynchronous prescaler, minus one. We divide the LSI by 16 here, and
+/// then get as close to 1 Hz as we can in the synchronous prescaler.
+const PREDIV_A: u8 = 15;
+
+/// The LSI frequency we assume, unless told otherwise, in millihertz
+pub const DEFAULT_FREQUENCY_MHZ: u32 = 40_000_000;
+
+/// The slowest LSI frequency we accept, in millihertz (from the datasheet)
+pub const MIN_FREQUENCY_MHZ: u32 = 30_000_000;
+
+/// The fastest LSI frequency we accept, in millihertz (from the datasheet)
+pub const MAX_FREQUENCY_MHZ: u32 = 50_000_000;
+
+/// The alarm only compares this many of the low bits of the sub-second
+/// counter, so it fires every 1024 counts. That's at least every 0.55 seconds,
+/// which keeps us well inside the IWDG timeout.
+const TICK_MASK_BITS: u8 = 10;
+
+/// The EXTI line the RTC alarm is wired to
+const EXTI_LINE: u32 = 17;
+
+/// The weekday bits in the date register. We don't keep track of the day of
+/// the week.
+const WEEKDAY_MASK: u32 = 0b111 << 13;
+
+/// The backup register holding the alarm date
+const BKP_ALARM_DATE: usize = 0;
+
+/// The backup register holding the alarm time
+const BKP_ALARM_TIME: usize = 1;
+
+/// The backup register holding the alarm action
+const BKP_ALARM_ACTION: usize = 2;
+
+/// Set in the alarm action register once the alarm has gone off
+pub const ALARM_FIRED: u8 = 1 << 7;
+
+/// What we do when the host's alarm goes off.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, defmt::Format)]
+#[repr(u8)]
+pub enum AlarmAction {
+	/// The alarm is off
+	None = 0,
+	/// Turn the system on
+	PowerOn = 1,
+	/// Turn the system off
+	PowerOff = 2,
+	/// Ask the host to shut down, with an interrupt
+	RequestShutdown = 3,
+}
+
+impl AlarmAction {
+	/// Convert from a register value. Unknown values are `None`.
+	pub fn from_u8(value: u8) -> AlarmAction {
+		match value {
+			1 => AlarmAction::PowerOn,
+			2 => AlarmAction::PowerOff,
+			3 => AlarmAction::RequestShutdown,
+			_ => AlarmAction::None,
+		}
+	}
+}
+
+/// A date and time, between 2000 and 2099.
+///
+/// We hold these as the BCD values from the RTC's date and time registers,
+/// which sort in the right order.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub struct DateTime {
+	/// The year, month and day, as in RTC_DR (without the weekday)
+	date: u32,
+	/// The hours, minutes and seconds, as in RTC_TR (in 24-hour format)
+	time: u32,
+}
+
+impl DateTime {
+	/// The largest value of each field, as bytes
+	const MAX: [u8; 6] = [99, 12, 31, 23, 59, 59];
+
+	/// Convert from `[year - 2000, month, day, hours, minutes, seconds]`.
+	///
+	/// Returns `None` if that isn't a real date and time.
+	pub fn from_bytes(bytes: &[u8]) -> Option<DateTime> {
+		if bytes.len() != Self::MAX.len() {
+			return None;
+		}
+		let mut words = [0u32; 2];
+		for (idx, (&value, &max)) in bytes.iter().zip(Self::MAX.iter()).enumerate() {
+			// The month and the day start at one
+			let min = u8::from(idx == 1 || idx == 2);
+			if value < min || value > max {
+				return None;
+			}
+			let word = &mut words[idx / 3];
+			*word = *word << 8 | to_bcd(value);
+		}
+		let days_in_month = match bytes[1] {
+			2 if bytes[0].is_multiple_of(4) => 29,
+			2 => 28,
+			4 | 6 | 9 | 11 => 30,
+			_ => 31,
+		};
+		if bytes[2] > days_in_month {
+			return None;
+		}
+		Some(DateTime {
+			date: words[0],
+			time: words[1],
+		})
+	}
+
+	/// Convert to `[year - 2000, month, day, hours, minutes, seconds]`.
+	pub fn to_bytes(&self) -> [u8; 6] {
+		let mut bytes = [0u8; 6];
+		for (idx, byte) in bytes.iter_mut().enumerate() {
+			let word = if idx < 3 { self.date } else { self.time };
+			*byte = from_bcd(word >> (16 - 8 * (idx % 3)));
+		}
+		bytes
+	}
+
+	/// Get the seconds, in BCD.
+	pub fn seconds(&self) -> u8 {
+		self.time as u8
+	}
+}
+
+/// Convert a number from 0 to 99 into two BCD digits.
+fn to_bcd(value: u8) -> u32 {
+	u32::from((value / 10) << 4 | (value % 10))
+}
+
+/// Convert the bottom two BCD digits of a register into a number.
+fn from_bcd(bcd: u32) -> u8 {
+	let bcd = bcd as u8;
+	(bcd >> 4) * 10 + (bcd & 0x0F)
+}
+
+/// Start the RTC (if it isn't already running) and its tick.
+pub fn init(rcc: &pac::RCC, pwr: &pac::PWR, rtc: &pac::RTC, exti: &pac::EXTI) {
+	// The RTC lives in the backup domain, which is write-protected
+	rcc.apb1enr.modify(|_r, w| w.pwren().enabled());
+	pwr.cr.modify(|_r, w| w.dbp().set_bit());
+	rcc.csr.modify(|_r, w| w.lsion().on());
+	while rcc.csr.read().lsirdy().is_not_ready() {}
+	if !rcc.bdcr.read().rtcsel().is_lsi() {
+		// The clock source can only be changed after a backup domain reset
+		rcc.bdcr.modify(|_r, w| w.bdrst().enabled());
+		rcc.bdcr.modify(|_r, w| w.bdrst().disabled());
+		rcc.bdcr.modify(|_r, w| w.rtcsel().lsi());
+	}
+	rcc.bdcr.modify(|_r, w| w.rtcen().enabled());
+
+	unlock(rtc);
+	// Read the calendar straight from the counters. The shadow registers
+	// aren't updated in STOP mode, and we'd have to wait for them on every
+	// wake-up.
+	rtc.cr.modify(|_r, w| w.bypshad().set_bit());
+
+	// Set the alarm to go off whenever the bottom bits of the sub-second
+	// counter are zero, whatever the time and date.
+	rtc.cr.modify(|_r, w| w.alrae().clear_bit());
+	while rtc.isr.read().alrawf().bit_is_clear() {}
+	rtc.alrmar.write(|w| {
+		w.msk1().set_bit();
+		w.msk2().set_bit();
+		w.msk3().set_bit();
+		w.msk4().set_bit()
+	});
+	rtc.alrmassr
+		.write(|w| unsafe { w.maskss().bits(TICK_MASK_BITS).ss().bits(0) });
+	rtc.cr.modify(|_r, w| {
+		w.alraie().set_bit();
+		w.alrae().set_bit()
+	});
+	lock(rtc);
+
+	clear_tick(rtc);
+	exti.imr
+		.modify(|r, w| unsafe { w.bits(r.bits() | (1 << EXTI_LINE)) });
+	exti.rtsr
+		.modify(|r, w| unsafe { w.bits(r.bits() | (1 << EXTI_LINE)) });
+}
+
+/// Set up the prescalers and calibration for an LSI running at the given
+/// frequency, in millihertz, which must be between [`MIN_FREQUENCY_MHZ`] and
+/// [`MAX_FREQUENCY_MHZ`].
+pub fn set_frequency(frequency_mhz: u32) {
+	let async_mhz = (u32::from(PREDIV_A) + 1) * 1000;
+	let sync_div = (frequency_mhz + async_mhz / 2) / async_mhz;
+	let prediv_s = (sync_div - 1) as u16;
+	// The smooth calibration trims out the rest. It adds or removes CALM
+	// pulses every 2^20 clocks (and adds 512 more if CALP is set). This is at
+	// most about 270 ppm, and 2^15 * 8,000 still fits in an i32.
+	let error_mhz = frequency_mhz as i32 - (sync_div * async_mhz) as i32;
+	let pulses = error_mhz * 32768 / (frequency_mhz / 32) as i32;
+	let (calp, calm) = if pulses >= 0 {
+		(false, pulses as u16)
+	} else {
+		(true, (512 + pulses) as u16)
+	};
+	defmt::trace!("RTC PREDIV_S {}, CALM {}, CALP {}", prediv_s, calm, calp);
+
+	// Safety: the RTC interrupt only reads these registers, and we don't let
+	// it in until we're done
+	let rtc = unsafe { &*pac::RTC::ptr() };
+	cortex_m::interrupt::free(|_cs| {
+		unlock(rtc);
+		let prer = rtc.prer.read();
+		if prer.prediv_s().bits() != prediv_s || prer.prediv_a().bits() != PREDIV_A {
+			// Changing the prescalers stops the calendar for a moment
+			enter_init(rtc);
+			// These have to be two separate writes
+			rtc.prer.write(|w| unsafe { w.prediv_s().bits(prediv_s) });
+			rtc.prer
+				.modify(|_r, w| unsafe { w.prediv_a().bits(PREDIV_A) });
+			exit_init(rtc);
+		}
+		while rtc.isr.read().recalpf().bit_is_set() {}
+		rtc.calr
+			.write(|w| unsafe { w.calp().bit(calp).calm().bits(calm) });
+		lock(rtc);
+	});
+}
+
+/// Get the current date and time.
+pub fn now() -> DateTime {
+	// Safety: these are read-only registers
+	let rtc = unsafe { &*pac::RTC::ptr() };
+	// We read the counters directly, so go round again if the time ticked
+	// over whilst we were reading the date.
+	loop {
+		let time = rtc.tr.read().bits();
+		let date = rtc.dr.read().bits() & !WEEKDAY_MASK;
+		if rtc.tr.read().bits() == time {
+			return DateTime { date, time };
+		}
+	}
+}
+
+/// How many eighths of a second there are in a day, when [`eighths`] goes back
+/// to zero.
+pub const EIGHTHS_PER_DAY: u32 = 24 * 60 * 60 * 8;
+
+/// Get the time of day, in eighths of a second.
+///
+/// This is for timing things which carry on in STOP mode, when the SysTick
+/// doesn't count.
+pub fn eighths() -> u32 {
+	// Safety: these are read-only registers
+	let rtc = unsafe { &*pac::RTC::ptr() };
+	let prediv_s = u32::from(rtc.prer.read().prediv_s().bits());
+	// The sub-second counter counts down, and reloads as the seconds tick
+	// over, so go round again if that happened whilst we were reading it.
+	loop {
+		let time = rtc.tr.read().bits();
+		let sub_seconds = u32::from(rtc.ssr.read().ss().bits());
+		if rtc.tr.read().bits() == time {
+			let seconds = (u32::from(from_bcd(time >> 16)) * 60 + u32::from(from_bcd(time >> 8)))
+				* 60 + u32::from(from_bcd(time));
+			return seconds * 8 + (prediv_s - sub_seconds) * 8 / (prediv_s + 1);
+		}
+	}
+}
+
+/// How many eighths of a second have passed since `then` (which came from
+/// [`eighths`]), allowing for midnight in between.
+pub fn eighths_since(then: u32) -> u32 {
+	eighths_between(then, eighths())
+}
+
+/// How many eighths of a second there are from `then` to `now` (both times of
+/// day, from [`eighths`]), allowing for midnight in between.
+pub fn eighths_between(then: u32, now: u32) -> u32 {
+	if now >= then {
+		now - then
+	} else {
+		now + EIGHTHS_PER_DAY - then
+	}
+}
+
+/// Set the current date and time.
+pub fn set_now(now: &DateTime) {
+	// Safety: the RTC interrupt only reads these registers, and we don't let
+	// it in until we're done
+	let rtc = unsafe { &*pac::RTC::ptr() };
+	cortex_m::interrupt::free(|_cs| {
+		unlock(rtc);
+		enter_init(rtc);
+		rtc.tr.write(|w| unsafe { w.bits(now.time) });
+		// The weekday can't be zero, so call it Monday
+		rtc.dr.write(|w| unsafe { w.bits(now.date | 1 << 13) });
+		exit_init(rtc);
+		lock(rtc);
+	});
+}
+
+/// Get the date and time the host's alarm is set for.
+pub fn alarm() -> DateTime {
+	// Safety: the backup registers aren't shared with anything else
+	let rtc = unsafe { &*pac::RTC::ptr() };
+	DateTime {
+		date: rtc.bkpr[BKP_ALARM_DATE].read().bits(),
+		time: rtc.bkpr[BKP_ALARM_TIME].read().bits(),
+	}
+}
+
+/// Set the date and time for the host's alarm.
+pub fn set_alarm(alarm: &DateTime) {
+	// Safety: the backup registers aren't write-protected, and the RTC
+	// interrupt only reads these two
+	let rtc = unsafe { &*pac::RTC::ptr() };
+	rtc.bkpr[BKP_ALARM_DATE].write(|w| unsafe { w.bits(alarm.date) });
+	rtc.bkpr[BKP_ALARM_TIME].write(|w| unsafe { w.bits(alarm.time) });
+}
+
+/// Get the alarm action, with [`ALARM_FIRED`] set if the alarm has gone off.
+pub fn alarm_status() -> u8 {
+	// Safety: the backup registers aren't shared with anything else
+	let rtc = unsafe { &*pac::RTC::ptr() };
+	rtc.bkpr[BKP_ALARM_ACTION].read().bits() as u8
+}
+
+/// Set what the alarm does, which also clears [`ALARM_FIRED`].
+pub fn set_alarm_action(action: AlarmAction) {
+	write_alarm_status(action as u8);
+}
+
+/// Write the alarm action register.
+fn write_alarm_status(status: u8) {
+	// Safety: the backup registers aren't write-protected, and we only go
+	// from an action to fired in the RTC interrupt
+	let rtc = unsafe { &*pac::RTC::ptr() };
+	cortex_m::interrupt::free(|_cs| {
+		rtc.bkpr[BKP_ALARM_ACTION].write(|w| unsafe { w.bits(u32::from(status)) });
+	});
+}
+
+/// See if the host's alarm has gone off. Call this on every tick.
+///
+/// The alarm only goes off once. After that it is marked as having fired,
+/// until the host sets the action again.
+pub fn check_alarm(now: &DateTime) -> Option<AlarmAction> {
+	// Once it's fired, this is never a valid action
+	let status = alarm_status();
+	let action = AlarmAction::from_u8(status);
+	if action == AlarmAction::None || *now < alarm() {
+		return None;
+	}
+	write_alarm_status(status | ALARM_FIRED);
+	Some(action)
+}
+
+/// Has the alarm asked the host to shut down (and the host not yet seen it)?
+pub fn is_shutdown_requested() -> bool {
+	alarm_status() == ALARM_FIRED | AlarmAction::RequestShutdown as u8
+}
+
+/// Clear the tick, so we can get another one.
+pub fn clear_tick(rtc: &pac::RTC) {
+	// ALRAF is cleared by writing zero to it
+	rtc.isr.modify(|_r, w| w.alraf().clear_bit());
+	// Safety: the pending register is write-1-to-clear, so we only touch our
+	// own bit
+	let exti = unsafe { &*pac::EXTI::ptr() };
+	exti.pr.write(|w| unsafe { w.bits(1 << EXTI_LINE) });
+}
+
+/// Stop the calendar, so we can change it.
+fn enter_init(rtc: &pac::rtc::RegisterBlock) {
+	rtc.isr.modify(|_r, w| w.init().set_bit());
+	while rtc.isr.read().initf().bit_is_clear() {}
+}
+
+/// Start the calendar again.
+fn exit_init(rtc: &pac::rtc::RegisterBlock) {
+	rtc.isr.modify(|_r, w| w.init().clear_bit());
+}
+
+/// Let us write to the RTC registers.
+fn unlock(rtc: &pac::rtc::RegisterBlock) {
+	rtc.wpr.write(|w| unsafe { w.key().bits(0xCA) });
+	rtc.wpr.write(|w| unsafe { w.key().bits(0x53) });
+}
+
+/// Stop anything writing to the RTC registers by accident.
+fn lock(rtc: &pac::rtc::RegisterBlock) {
+	rtc.wpr.write(|w| unsafe { w.key().bits(0xFF) });
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+
+	#[test]
+	fn round_trip() {
+		for bytes in [
+			[0, 1, 1, 0, 0, 0],
+			[24, 2, 29, 12, 34, 56],
+			[99, 12, 31, 23, 59, 59],
+		] {
+			let date_time = DateTime::from_bytes(&bytes).unwrap();
+			assert_eq!(date_time.to_bytes(), bytes);
+		}
+	}
+
+	#[test]
+	fn held_as_bcd() {
+		let date_time = DateTime::from_bytes(&[23, 10, 19, 12, 34, 56]).unwrap();
+		assert_eq!(date_time.date, 0x23_10_19);
+		assert_eq!(date_time.time, 0x12_34_56);
+		assert_eq!(date_time.seconds(), 0x56);
+	}
+
+	#[test]
+	fn sorts_in_order() {
+		let earlier = DateTime::from_bytes(&[23, 12, 31, 23, 59, 59]).unwrap();
+		let later = DateTime::from_bytes(&[24, 1, 1, 0, 0, 0]).unwrap();
+		assert!(earlier < later);
+	}
+
+	#[test]
+	fn bad_lengths() {
+		assert_eq!(DateTime::from_bytes(&[]), None);
+		assert_eq!(DateTime::from_bytes(&[24, 1, 1, 0, 0]), None);
+		assert_eq!(DateTime::from_bytes(&[24, 1, 1, 0, 0, 0, 0]), None);
+	}
+
+	#[test]
+	fn bad_fields() {
+		for bytes in [
+			[100, 1, 1, 0, 0, 0],
+			[24, 0, 1, 0, 0, 0],
+			[24, 13, 1, 0, 0, 0],
+			[24, 1, 0, 0, 0, 0],
+			[24, 1, 32, 0, 0, 0],
+			[24, 1, 1, 24, 0, 0],
+			[24, 1, 1, 0, 60, 0],
+			[24, 1, 1, 0, 0, 60],
+		] {
+			assert_eq!(DateTime::from_bytes(&bytes), None, "{:?}", bytes);
+		}
+	}
+
+	#[test]
+	fn days_in_each_month() {
+		for month in [4, 6, 9, 11] {
+			assert!(DateTime::from_bytes(&[23, month, 30, 0, 0, 0]).is_some());
+			assert_eq!(DateTime::from_bytes(&[23, month, 31, 0, 0, 0]), None);
+		}
+		for month in [1, 3, 5, 7, 8, 10, 12] {
+			assert!(DateTime::from_bytes(&[23, month, 31, 0, 0, 0]).is_some());
+		}
+	}
+
+	#[test]
+	fn leap_years() {
+		// Every fourth year is a leap year, including 2000, as it divides by
+		// 400
+		for year in [0, 4, 24, 96] {
+			assert!(DateTime::from_bytes(&[year, 2, 29, 0, 0, 0]).is_some());
+			assert_eq!(DateTime::from_bytes(&[year, 2, 30, 0, 0, 0]), None);
+		}
+		for year in [1, 23, 99] {
+			assert!(DateTime::from_bytes(&[year, 2, 28, 0, 0, 0]).is_some());
+			assert_eq!(DateTime::from_bytes(&[year, 2, 29, 0, 0, 0]), None);
+		}
+	}
+}
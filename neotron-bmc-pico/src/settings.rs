@@ -0,0 +1,527 @@
+//! # Persistent Settings
+//!
+//! The BMC settings are kept in the last two pages of flash (see `memory.x`),
+//! so they survive the loss of standby power. This is a simple EEPROM
+//! emulation: each save appends a new fixed-size record, and when we run out
+//! of space in one page we move to the other page (erasing it first). We
+//! never erase the page holding the newest record, so an interrupted save
+//! leaves the previous settings intact.
+//!
+//! Each record looks like:
+//!
+//! | Offset | Length | Contains                                     |
+//! | ------ | ------ | -------------------------------------------- |
+//! | 0      | 2      | Magic number                                 |
+//! | 2      | 1      | Payload layout version                       |
+//! | 3      | 1      | Payload length                               |
+//! | 4      | 2      | Sequence number (`u16le`), newest wins       |
+//! | 6      | 121    | Payload (padded with `0xFF`)                 |
+//! | 127    | 1      | CRC-8 of all the preceding bytes             |
+//!
+//! New versions of the firmware must only ever append fields to the payload.
+//! When an older (shorter) record is read, the missing fields take their
+//! default values.
+//!
+//! ## Wear
+//!
+//! Eight records fit in a page, so each page is erased once every sixteen
+//! saves. The STM32F030 datasheet only promises 1,000 erase cycles, which
+//! gives us about 16,000 saves. The host saving the settings now and again
+//! barely uses these, but we also save without being asked:
+//!
+//! * once each time the system is turned on or off, with the *Last State*
+//!   power restore policy
+//! * once each time the system is turned off, with *Event Log Persist* on
+//!   (shared with the *Last State* save, if both are on)
+//!
+//! Two saves per power cycle is about 8,000 power cycles, or ten years of
+//! turning the system on and off twice a day. The idle loop never does these
+//! saves more than once a minute, so even a host that power cycles itself in a
+//! loop takes over a week to wear the pages out.
+
+use crate::{button, event_log, flash, rtc, speaker, wake, watchdog};
+
+/// Where the settings live in flash. Must match `memory.x`.
+const SETTINGS_START: usize = 0x0800_7800;
+
+/// How many pages of flash we have for settings.
+const NUM_PAGES: usize = 2;
+
+/// How big each record is. Must be an even number, and a factor of the flash
+/// page size.
+const RECORD_SIZE: usize = 128;
+
+/// How many records fit in one page
+const RECORDS_PER_PAGE: usize = flash::PAGE_SIZE / RECORD_SIZE;
+
+/// How many records fit in all our pages
+const NUM_RECORDS: usize = RECORDS_PER_PAGE * NUM_PAGES;
+
+/// Marks the start of a valid record
+const MAGIC: [u8; 2] = *b"NS";
+
+/// The payload layout version we write.
+const VERSION: u8 = 1;
+
+/// Where the payload starts within a record
+const PAYLOAD_OFFSET: usize = 6;
+
+/// The most payload we can fit in a record
+const MAX_PAYLOAD: usize = RECORD_SIZE - PAYLOAD_OFFSET - 1;
+
+/// The UART baud rate we use if nothing else is configured.
+pub const DEFAULT_UART_BAUD: u32 = 115_200;
+
+/// What we do when standby power returns after being lost.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, defmt::Format)]
+#[repr(u8)]
+pub enum PowerRestore {
+	/// Stay off until the power button is pressed
+	AlwaysOff = 0,
+	/// Turn the system on
+	AlwaysOn = 1,
+	/// Turn the system on if it was on when standby power was lost
+	LastState = 2,
+}
+
+impl PowerRestore {
+	/// Convert from a register value. Unknown values are `AlwaysOff`.
+	pub fn from_u8(value: u8) -> PowerRestore {
+		match value {
+			1 => PowerRestore::AlwaysOn,
+			2 => PowerRestore::LastState,
+			_ => PowerRestore::AlwaysOff,
+		}
+	}
+}
+
+/// The settings we keep in flash.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Settings {
+	/// The button timings and gesture actions
+	pub buttons: button::Config,
+	/// The baud rate for the UART, in bits per second
+	pub uart_baud: u32,
+	/// What to do when standby power returns
+	pub power_restore: PowerRestore,
+	/// How long to wait before restoring power, in seconds
+	pub power_restore_delay: u8,
+	/// The most extra time to wait before restoring power, in seconds. The
+	/// actual extra time is different for each board.
+	pub power_restore_random: u8,
+	/// Was the system on, the last time it was turned on or off? Only kept
+	/// up to date when using [`PowerRestore::LastState`].
+	pub last_power_on: bool,
+	/// The host watchdog settings
+	pub watchdog: watchdog::Config,
+	/// Should we keep the most recent events in flash?
+	pub event_log_persist: bool,
+	/// The most recent events, as of the last time the system was turned off.
+	/// Only kept up to date when `event_log_persist` is set.
+	pub saved_events: [event_log::Event; event_log::SAVED_EVENTS],
+	/// What else, apart from the power button, can turn the system on
+	pub wake: wake::Config,
+	/// How fast the RTC's clock really runs, in millihertz
+	rtc_frequency_mhz: u32,
+	/// The tune we play when the host is turned on or reset
+	pub boot_chime: [u8; speaker::CHIME_LEN],
+	/// The click played for each key press
+	pub key_click: speaker::KeyClick,
+}
+
+impl Settings {
+	/// The slowest baud rate we support (the baud rate divisor is 16-bit)
+	pub const MIN_UART_BAUD: u32 = 1200;
+	/// The fastest baud rate we support (the baud rate divisor must be >= 16)
+	pub const MAX_UART_BAUD: u32 = 3_000_000;
+
+	/// Set the UART baud rate, clamped to the range we support.
+	pub fn set_uart_baud(&mut self, baud: u32) {
+		self.uart_baud = baud.clamp(Self::MIN_UART_BAUD, Self::MAX_UART_BAUD);
+	}
+
+	/// Get how fast the RTC's clock runs, in millihertz.
+	pub fn rtc_frequency_mhz(&self) -> u32 {
+		self.rtc_frequency_mhz
+	}
+
+	/// Set how fast the RTC's clock runs, in millihertz, clamped to the range
+	/// the LSI can run at.
+	pub fn set_rtc_frequency_mhz(&mut self, frequency_mhz: u32) {
+		self.rtc_frequency_mhz =
+			frequency_mhz.clamp(rtc::MIN_FREQUENCY_MHZ, rtc::MAX_FREQUENCY_MHZ);
+	}
+
+	/// Should we turn the system on when standby power returns?
+	pub fn should_restore_power(&self) -> bool {
+		match self.power_restore {
+			PowerRestore::AlwaysOff => false,
+			PowerRestore::AlwaysOn => true,
+			PowerRestore::LastState => self.last_power_on,
+		}
+	}
+
+	/// How long to wait before restoring power, in milliseconds.
+	///
+	/// The `seed` should be different on each board, so that a room full of
+	/// machines doesn't all switch on at the same moment.
+	pub fn power_restore_delay_ms(&self, seed: u32) -> u32 {
+		let extra = seed % (u32::from(self.power_restore_random) + 1);
+		(u32::from(self.power_restore_delay) + extra) * 1000
+	}
+
+	/// Render into a payload, returning the number of bytes used.
+	fn write_payload(&self, buffer: &mut [u8; MAX_PAYLOAD]) -> usize {
+		let mut writer = Writer { buffer, idx: 0 };
+		// Version 1
+		writer.u8(self.buttons.poll_interval_ms);
+		writer.u8(self.buttons.power_short_polls);
+		writer.u8(self.buttons.power_long_polls);
+		writer.u8(self.buttons.reset_short_polls);
+		writer.u8(self.buttons.double_press_polls);
+		writer.u8(self.buttons.power_very_long_polls);
+		writer.u8(self.buttons.reset_long_polls);
+		for action in self.buttons.actions.iter() {
+			writer.u8(*action as u8);
+		}
+		writer.u32(self.uart_baud);
+		writer.u8(self.power_restore as u8);
+		writer.u8(self.power_restore_delay);
+		writer.u8(self.power_restore_random);
+		writer.u8(u8::from(self.last_power_on));
+		writer.u8(self.watchdog.action as u8);
+		writer.u8(self.watchdog.boot_timeout_secs);
+		writer.u8(self.watchdog.boot_retries);
+		writer.u8(u8::from(self.event_log_persist));
+		for event in self.saved_events.iter() {
+			writer.u32(event.timestamp_ms);
+			writer.u8(event.kind);
+			writer.u8(event.data);
+		}
+		writer.u8(self.wake.sources());
+		writer.u32(self.rtc_frequency_mhz);
+		for b in self.boot_chime.iter() {
+			writer.u8(*b);
+		}
+		writer.u8(self.key_click.duration_ms);
+		writer.u8(self.key_click.midi_note);
+		writer.idx
+	}
+
+	/// Parse a payload. Any fields missing from the payload keep their
+	/// default value.
+	fn from_payload(payload: &[u8]) -> Settings {
+		let mut settings = Settings::default();
+		let mut reader = Reader { payload, idx: 0 };
+		// Version 1
+		let b = &mut settings.buttons;
+		reader.u8(|x| b.set_poll_interval_ms(x));
+		reader.u8(|x| b.set_power_short_polls(x));
+		reader.u8(|x| b.set_power_long_polls(x));
+		reader.u8(|x| b.set_reset_short_polls(x));
+		reader.u8(|x| b.set_double_press_polls(x));
+		reader.u8(|x| b.set_power_very_long_polls(x));
+		reader.u8(|x| b.set_reset_long_polls(x));
+		for action in b.actions.iter_mut() {
+			reader.u8(|x| *action = button::Action::from_u8(x).unwrap_or(*action));
+		}
+		reader.u32(|x| settings.set_uart_baud(x));
+		reader.u8(|x| settings.power_restore = PowerRestore::from_u8(x));
+		reader.u8(|x| settings.power_restore_delay = x);
+		reader.u8(|x| settings.power_restore_random = x);
+		reader.u8(|x| settings.last_power_on = x != 0);
+		let w = &mut settings.watchdog;
+		reader.u8(|x| w.action = watchdog::Action::from_u8(x));
+		reader.u8(|x| w.boot_timeout_secs = x);
+		reader.u8(|x| w.boot_retries = x);
+		reader.u8(|x| settings.event_log_persist = x != 0);
+		for event in settings.saved_events.iter_mut() {
+			reader.u32(|x| event.timestamp_ms = x);
+			reader.u8(|x| event.kind = x);
+			reader.u8(|x| event.data = x);
+		}
+		reader.u8(|x| settings.wake.set_sources(x));
+		reader.u32(|x| settings.set_rtc_frequency_mhz(x));
+		for b in settings.boot_chime.iter_mut() {
+			reader.u8(|x| *b = x);
+		}
+		let key_click = &mut settings.key_click;
+		reader.u8(|x| key_click.duration_ms = x);
+		reader.u8(|x| key_click.midi_note = x);
+		settings
+	}
+}
+
+impl Default for Settings {
+	fn default() -> Settings {
+		Settings {
+			buttons: button::Config::default(),
+			uart_baud: DEFAULT_UART_BAUD,
+			power_restore: PowerRestore::AlwaysOff,
+			power_restore_delay: 0,
+			power_restore_random: 0,
+			last_power_on: false,
+			watchdog: watchdog::Config::default(),
+			event_log_persist: false,
+			saved_events: [event_log::Event::EMPTY; event_log::SAVED_EVENTS],
+			wake: wake::Config::default(),
+			rtc_frequency_mhz: rtc::DEFAULT_FREQUENCY_MHZ,
+			boot_chime: speaker::DEFAULT_CHIME,
+			key_click: speaker::KeyClick::default(),
+		}
+	}
+}
+
+/// Helps us render fields into a payload
+struct Writer<'a> {
+	buffer: &'a mut [u8; MAX_PAYLOAD],
+	idx: usize,
+}
+
+impl<'a> Writer<'a> {
+	fn u8(&mut self, value: u8) {
+		self.buffer[self.idx] = value;
+		self.idx += 1;
+	}
+
+	fn u32(&mut self, value: u32) {
+		for b in value.to_le_bytes() {
+			self.u8(b);
+		}
+	}
+}
+
+/// Helps us parse fields from a payload, skipping those that aren't there
+struct Reader<'a> {
+	payload: &'a [u8],
+	idx: usize,
+}
+
+impl<'a> Reader<'a> {
+	fn u8<F: FnOnce(u8)>(&mut self, f: F) {
+		if let Some(b) = self.payload.get(self.idx) {
+			f(*b);
+		}
+		self.idx += 1;
+	}
+
+	fn u32<F: FnOnce(u32)>(&mut self, f: F) {
+		if let Some(b) = self.payload.get(self.idx..self.idx + 4) {
+			f(u32::from_le_bytes([b[0], b[1], b[2], b[3]]));
+		}
+		self.idx += 4;
+	}
+}
+
+/// Loads and saves [`Settings`] in flash.
+pub struct Store {
+	/// The flash driver
+	flash: flash::Flash,
+	/// Which record slot holds the newest settings, and its sequence number
+	latest: Option<(usize, u16)>,
+}
+
+impl Store {
+	/// Construct a new settings store.
+	pub fn new(flash: flash::Flash) -> Store {
+		Store {
+			flash,
+			latest: None,
+		}
+	}
+
+	/// Find the newest valid settings in flash.
+	///
+	/// Returns `None` if there are no valid settings (e.g. on first boot).
+	pub fn load(&mut self) -> Option<Settings> {
+		self.latest = None;
+		for slot in 0..NUM_RECORDS {
+			let record = Self::record(slot);
+			if !Self::is_valid(record) {
+				continue;
+			}
+			let sequence = u16::from_le_bytes([record[4], record[5]]);
+			let is_newer = match self.latest {
+				// Sequence numbers wrap, so compare them as a signed difference
+				Some((_, latest)) => (sequence.wrapping_sub(latest) as i16) > 0,
+				None => true,
+			};
+			if is_newer {
+				self.latest = Some((slot, sequence));
+			}
+		}
+		let (slot, _) = self.latest?;
+		let record = Self::record(slot);
+		let length = usize::from(record[3]).min(MAX_PAYLOAD);
+		Some(Settings::from_payload(
+			&record[PAYLOAD_OFFSET..PAYLOAD_OFFSET + length],
+		))
+	}
+
+	/// Save some settings to flash, as a new record.
+	pub fn save(&mut self, settings: &Settings) -> Result<(), flash::Error> {
+		let (mut slot, sequence) = match self.latest {
+			Some((slot, sequence)) => ((slot + 1) % NUM_RECORDS, sequence.wrapping_add(1)),
+			None => (0, 0),
+		};
+
+		if slot % RECORDS_PER_PAGE == 0 {
+			// We're moving into a new page, which only holds stale records.
+			self.flash.erase_page(Self::address(slot))?;
+		} else if Self::record(slot).iter().any(|b| *b != 0xFF) {
+			// Slot isn't blank (maybe we lost power half-way through a save).
+			// Skip to the next page, which must only hold stale records.
+			slot = (slot + RECORDS_PER_PAGE - (slot % RECORDS_PER_PAGE)) % NUM_RECORDS;
+			self.flash.erase_page(Self::address(slot))?;
+		}
+
+		let mut record = [0xFFu8; RECORD_SIZE];
+		let mut payload = [0xFFu8; MAX_PAYLOAD];
+		let length = settings.write_payload(&mut payload);
+		record[0..2].copy_from_slice(&MAGIC);
+		record[2] = VERSION;
+		record[3] = length as u8;
+		record[4..6].copy_from_slice(&sequence.to_le_bytes());
+		record[PAYLOAD_OFFSET..PAYLOAD_OFFSET + MAX_PAYLOAD].copy_from_slice(&payload);
+		record[RECORD_SIZE - 1] = neotron_bmc_protocol::calculate_crc(&record[0..RECORD_SIZE - 1]);
+
+		self.flash.program(Self::address(slot), &record)?;
+		self.latest = Some((slot, sequence));
+		Ok(())
+	}
+
+	/// Erase all the settings from flash, so we use the defaults on next boot.
+	pub fn erase(&mut self) -> Result<(), flash::Error> {
+		self.latest = None;
+		for page in 0..NUM_PAGES {
+			self.flash
+				.erase_page(SETTINGS_START + (page * flash::PAGE_SIZE))?;
+		}
+		Ok(())
+	}
+
+	/// Get the flash address of a record slot
+	fn address(slot: usize) -> usize {
+		SETTINGS_START + (slot * RECORD_SIZE)
+	}
+
+	/// Get the contents of a record slot
+	fn record(slot: usize) -> &'static [u8] {
+		// Safety: this is always within the settings area of flash
+		unsafe { core::slice::from_raw_parts(Self::address(slot) as *const u8, RECORD_SIZE) }
+	}
+
+	/// Does this record have a valid header, version and CRC?
+	fn is_valid(record: &[u8]) -> bool {
+		// It's a quirk of CRC-8 that including the CRC always produces a
+		// result of zero.
+		record[0..2] == MAGIC && record[2] != 0 && neotron_bmc_protocol::calculate_crc(record) == 0
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+
+	/// Settings with every field changed from its default.
+	fn custom() -> Settings {
+		let mut settings = Settings::default();
+		let b = &mut settings.buttons;
+		b.set_poll_interval_ms(20);
+		b.set_power_short_polls(3);
+		b.set_power_long_polls(40);
+		b.set_reset_short_polls(4);
+		b.set_double_press_polls(9);
+		b.set_power_very_long_polls(200);
+		b.set_reset_long_polls(100);
+		b.actions = [
+			button::Action::PowerOff,
+			button::Action::ResetHost,
+			button::Action::None,
+			button::Action::SafeMode,
+		];
+		settings.set_uart_baud(9600);
+		settings.power_restore = PowerRestore::LastState;
+		settings.power_restore_delay = 5;
+		settings.power_restore_random = 10;
+		settings.last_power_on = true;
+		settings.watchdog = watchdog::Config {
+			action: watchdog::Action::PowerCycle,
+			boot_timeout_secs: 30,
+			boot_retries: 2,
+		};
+		settings.event_log_persist = true;
+		for (idx, event) in settings.saved_events.iter_mut().enumerate() {
+			event.timestamp_ms = 0x1234_5678 + idx as u32;
+			event.kind = 2;
+			event.data = idx as u8;
+		}
+		settings
+			.wake
+			.set_sources(wake::SOURCE_ANY_KEY | wake::SOURCE_UART_BREAK);
+		settings.set_rtc_frequency_mhz(41_234_567);
+		settings.boot_chime = [72, 5, 76, 5, 79, 5, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+		settings.key_click = speaker::KeyClick {
+			duration_ms: 3,
+			midi_note: 84,
+		};
+		settings
+	}
+
+	/// Render some settings into a payload.
+	fn payload(settings: &Settings) -> ([u8; MAX_PAYLOAD], usize) {
+		let mut buffer = [0xFF; MAX_PAYLOAD];
+		let length = settings.write_payload(&mut buffer);
+		(buffer, length)
+	}
+
+	#[test]
+	fn round_trip() {
+		let settings = custom();
+		assert_ne!(settings, Settings::default());
+		let (buffer, length) = payload(&settings);
+		assert_eq!(Settings::from_payload(&buffer[0..length]), settings);
+	}
+
+	#[test]
+	fn defaults_round_trip() {
+		let (buffer, length) = payload(&Settings::default());
+		assert_eq!(
+			Settings::from_payload(&buffer[0..length]),
+			Settings::default()
+		);
+	}
+
+	#[test]
+	fn empty_payload_gives_defaults() {
+		assert_eq!(Settings::from_payload(&[]), Settings::default());
+	}
+
+	#[test]
+	fn short_payload_keeps_defaults_for_missing_fields() {
+		let settings = custom();
+		let (buffer, _length) = payload(&settings);
+		// Just the button timings and actions, and half of the baud rate
+		let loaded = Settings::from_payload(&buffer[0..13]);
+		assert_eq!(loaded.buttons, settings.buttons);
+		assert_eq!(loaded.uart_baud, DEFAULT_UART_BAUD);
+		assert_eq!(loaded.power_restore, PowerRestore::AlwaysOff);
+		assert_eq!(loaded.boot_chime, speaker::DEFAULT_CHIME);
+	}
+
+	#[test]
+	fn bad_values_are_fixed_up() {
+		let (mut buffer, length) = payload(&Settings::default());
+		// A zero poll interval, an unknown action, and a baud rate of zero
+		buffer[0] = 0;
+		buffer[7] = 0xFF;
+		buffer[11..15].copy_from_slice(&[0, 0, 0, 0]);
+		let loaded = Settings::from_payload(&buffer[0..length]);
+		assert_eq!(
+			loaded.buttons.poll_interval_ms,
+			button::Config::MIN_POLL_INTERVAL_MS
+		);
+		assert_eq!(loaded.buttons.actions, button::Config::default().actions);
+		assert_eq!(loaded.uart_baud, Settings::MIN_UART_BAUD);
+	}
+}
@@ -3,16 +3,148 @@ use stm32f0xx_hal::{
 	rcc::Rcc,
 };
 
+/// How many notes the host can queue up
+pub const QUEUE_LEN: usize = 16;
+
+/// How often we check whether the current note has finished, in milliseconds
+pub const STEP_MS: u16 = 10;
+
+/// The timer's clock, in Hz
+const CLOCK_HZ: u32 = 48_000_000;
+
+/// How many timer clocks there are in one of the 48 kHz ticks the host gives
+/// us periods in
+const CLOCKS_PER_TICK: u32 = 1000;
+
+/// The period of each note in the lowest MIDI octave (C-1 up to B-1), in timer
+/// clocks. Each octave up halves the period.
+const MIDI_OCTAVE: [u32; 12] = [
+	5870986, 5541473, 5230454, 4936891, 4659805, 4398270, 4151414, 3918413, 3698489, 3490909,
+	3294979, 3110046,
+];
+
+/// The highest MIDI note number
+const MAX_MIDI_NOTE: u8 = 127;
+
+/// How many bytes the boot chime takes up. It's up to eight notes, each a MIDI
+/// note number (0 = a rest) and a duration in [`STEP_MS`] units (0 = the end of
+/// the chime).
+pub const CHIME_LEN: usize = 16;
+
+/// The boot chime we play unless the host sets another (an F4 for 100 ms)
+pub const DEFAULT_CHIME: [u8; CHIME_LEN] = [65, 10, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+
+/// The duty cycle of the boot chime, which keeps it quiet
+const CHIME_DUTY_CYCLE: u8 = 10;
+
+/// Each beep in a beep code, in the same form as the boot chime: a G3 (about
+/// 196 Hz) for 400 ms, then a 200 ms gap
+const BEEP: [u8; 4] = [55, 40, 0, 20];
+
+/// The duty cycle of beep codes, which are meant to be heard
+const BEEP_DUTY_CYCLE: u8 = 127;
+
+/// The duty cycle of key clicks
+const CLICK_DUTY_CYCLE: u8 = 64;
+
+/// Faults we can't expect the host to tell anyone about, so we beep them out.
+/// The value is the number of beeps, and they must all fit in a chime.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[repr(u8)]
+pub enum BeepCode {
+	/// We crashed, and have recovered
+	BmcCrash = 1,
+	/// The host watchdog expired
+	HostWatchdog = 2,
+}
+
+/// One note for the speaker to play.
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub struct Note {
+	/// The PWM period (in timer clocks), or zero for a rest
+	pub period: u32,
+	/// The duty cycle (0 - 255)
+	pub duty_cycle: u8,
+	/// How long to play it for, in milliseconds
+	pub duration_ms: u16,
+}
+
+impl Note {
+	/// How many bytes a note takes up in the *Speaker Note Queue* register
+	pub const LEN: usize = 5;
+
+	/// Convert from `[period (u16le), duty cycle, duration (u16le)]`.
+	pub fn from_bytes(bytes: &[u8]) -> Note {
+		Note {
+			period: u32::from(u16::from_le_bytes([bytes[0], bytes[1]])) * CLOCKS_PER_TICK,
+			duty_cycle: bytes[2],
+			duration_ms: u16::from_le_bytes([bytes[3], bytes[4]]),
+		}
+	}
+}
+
+/// The click we play for each key press on the keyboard, so you get some
+/// feedback when typing even if the host has no sound driver.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct KeyClick {
+	/// How long each click lasts, in milliseconds (0 = no clicks)
+	pub duration_ms: u8,
+	/// The pitch of the click, as a MIDI note number
+	pub midi_note: u8,
+}
+
+impl KeyClick {
+	/// How many bytes this takes up in the *Speaker Key Click* register
+	pub const LEN: usize = 2;
+
+	/// Convert from `[duration, MIDI note]`.
+	pub fn from_bytes(bytes: &[u8]) -> KeyClick {
+		KeyClick {
+			duration_ms: bytes[0],
+			midi_note: bytes[1],
+		}
+	}
+
+	/// Convert to `[duration, MIDI note]`.
+	pub fn to_bytes(&self) -> [u8; Self::LEN] {
+		[self.duration_ms, self.midi_note]
+	}
+}
+
+impl Default for KeyClick {
+	fn default() -> KeyClick {
+		// Off, but a high C (about 2 kHz) when turned on
+		KeyClick {
+			duration_ms: 0,
+			midi_note: 96,
+		}
+	}
+}
+
 #[derive(Debug, Default)]
 pub struct RegisterState {
 	/// The duration of the current note (0 = off)
 	pub duration: u16,
-	/// The PWM period (in 48kHz ticks)
-	pub period: u16,
+	/// The PWM period (in timer clocks)
+	pub period: u32,
+	/// The MIDI note number last written
+	pub midi_note: u8,
 	/// The duty cycle (0 - 255)
 	pub duty_cycle: u8,
 	/// Whether the speaker config is dirty (needs to be sent to the PWM device)
 	pub needs_update: bool,
+	/// The note we're playing
+	note: Note,
+	/// How much longer we play `note` for, in milliseconds (0 = stopped)
+	remaining_ms: u16,
+	/// Notes waiting to be played after this one
+	queue: heapless::Deque<Note, QUEUE_LEN>,
+	/// We've taken the last note from the queue, and the host hasn't
+	/// acknowledged it
+	queue_empty: bool,
+	/// The queue holds a tune of our own (like the boot chime), which the
+	/// host doesn't need to hear about
+	own_tune: bool,
 }
 
 impl RegisterState {
@@ -24,39 +156,216 @@ impl RegisterState {
 		self.duty_cycle = duty_cycle;
 	}
 
+	/// Get the period in 48 kHz ticks.
 	pub fn period(&self) -> u16 {
-		self.period
+		(self.period / CLOCKS_PER_TICK).min(u32::from(u16::MAX)) as u16
 	}
 
+	/// Set the period in 48 kHz ticks.
 	pub fn set_period(&mut self, period: u16) {
-		self.period = period;
+		self.period = u32::from(period) * CLOCKS_PER_TICK;
 	}
 
 	pub fn period_high(&self) -> u8 {
-		(self.period >> 8) as u8
+		(self.period() >> 8) as u8
 	}
 
 	pub fn set_period_high(&mut self, period_high: u8) {
-		self.period = (self.period & 0x00ff) | ((period_high as u16) << 8);
+		self.set_period((self.period() & 0x00ff) | ((period_high as u16) << 8));
 	}
 
 	pub fn period_low(&self) -> u8 {
-		(self.period & 0xff) as u8
+		(self.period() & 0xff) as u8
 	}
 
 	pub fn set_period_low(&mut self, period_low: u8) {
-		self.period = (self.period & 0xff00) | period_low as u16;
+		self.set_period((self.period() & 0xff00) | period_low as u16);
+	}
+
+	/// Get the frequency in Hz (or zero for a rest).
+	pub fn frequency(&self) -> u16 {
+		CLOCK_HZ
+			.checked_div(self.period)
+			.map_or(0, |hz| hz.min(u32::from(u16::MAX)) as u16)
+	}
+
+	/// Set the frequency in Hz (or zero for a rest).
+	pub fn set_frequency(&mut self, frequency: u16) {
+		self.period = CLOCK_HZ.checked_div(u32::from(frequency)).unwrap_or(0);
+	}
+
+	/// Set the frequency from a MIDI note number, where 60 is middle C.
+	///
+	/// Numbers above 127 are treated as 127.
+	pub fn set_midi_note(&mut self, midi_note: u8) {
+		self.midi_note = midi_note.min(MAX_MIDI_NOTE);
+		self.period = midi_period(midi_note);
 	}
 
 	pub fn duration(&self) -> u16 {
 		self.duration
 	}
 
+	/// Play a note from the registers, in place of the current note. The
+	/// queue carries on afterwards.
+	///
+	/// A duration of zero stops the speaker, and empties the queue.
 	pub fn set_duration(&mut self, duration: u16) {
-		self.duration = duration;
+		if duration == 0 {
+			self.stop();
+		} else {
+			self.duration = duration;
+			self.play(Note {
+				period: self.period,
+				duty_cycle: self.duty_cycle,
+				duration_ms: duration,
+			});
+		}
+	}
+
+	/// Play a note now, in place of the current note.
+	pub fn play(&mut self, note: Note) {
+		self.note = note;
+		self.remaining_ms = note.duration_ms;
 		self.needs_update = true;
 	}
 
+	/// Stop playing, and empty the queue.
+	pub fn stop(&mut self) {
+		self.queue.clear();
+		self.duration = 0;
+		self.remaining_ms = 0;
+		self.needs_update = true;
+	}
+
+	/// Play a key click, unless we're already playing something (which
+	/// includes an earlier click).
+	#[inline(never)]
+	pub fn click(&mut self, key_click: &KeyClick) {
+		if key_click.duration_ms != 0 && !self.is_playing() {
+			self.play(Note {
+				period: midi_period(key_click.midi_note),
+				duty_cycle: CLICK_DUTY_CYCLE,
+				duration_ms: u16::from(key_click.duration_ms),
+			});
+		}
+	}
+
+	/// Add notes to the queue, as sent to the *Speaker Note Queue* register.
+	///
+	/// Returns `false`, and queues nothing, if that isn't a whole number of
+	/// notes or they don't all fit.
+	pub fn queue_notes(&mut self, bytes: &[u8]) -> bool {
+		if !self.can_queue(bytes.len()) {
+			return false;
+		}
+		for chunk in bytes.chunks_exact(Note::LEN) {
+			// We checked there was room
+			let _ = self.queue.push_back(Note::from_bytes(chunk));
+		}
+		self.own_tune = false;
+		if !self.is_playing() {
+			self.next();
+		}
+		true
+	}
+
+	/// Play the boot chime (see [`CHIME_LEN`]), in place of anything else.
+	pub fn play_chime(&mut self, chime: &[u8; CHIME_LEN]) {
+		self.play_tune(chime, CHIME_DUTY_CYCLE);
+	}
+
+	/// Beep out a fault code, in place of anything else.
+	pub fn beep(&mut self, code: BeepCode) {
+		let mut tune = [0u8; CHIME_LEN];
+		for beep in tune.chunks_exact_mut(BEEP.len()).take(code as usize) {
+			beep.copy_from_slice(&BEEP);
+		}
+		self.play_tune(&tune, BEEP_DUTY_CYCLE);
+	}
+
+	/// Play a tune of our own, in the same form as the boot chime.
+	fn play_tune(&mut self, tune: &[u8; CHIME_LEN], duty_cycle: u8) {
+		self.stop();
+		for note in tune.chunks_exact(2) {
+			if note[1] == 0 {
+				break;
+			}
+			// There's room for every note in a chime
+			let _ = self.queue.push_back(Note {
+				period: if note[0] == 0 {
+					0
+				} else {
+					midi_period(note[0])
+				},
+				duty_cycle,
+				duration_ms: u16::from(note[1]) * STEP_MS,
+			});
+		}
+		self.own_tune = true;
+		self.next();
+	}
+
+	/// Would this many bytes of notes fit in the queue?
+	pub fn can_queue(&self, num_bytes: usize) -> bool {
+		num_bytes != 0
+			&& num_bytes.is_multiple_of(Note::LEN)
+			&& num_bytes / Note::LEN <= QUEUE_LEN - self.queue.len()
+	}
+
+	/// How many notes are waiting in the queue.
+	pub fn queue_len(&self) -> usize {
+		self.queue.len()
+	}
+
+	/// Have we taken the last note from the queue, without the host
+	/// acknowledging it?
+	pub fn queue_empty(&self) -> bool {
+		self.queue_empty
+	}
+
+	/// The host knows the queue is empty.
+	pub fn clear_queue_empty(&mut self) {
+		self.queue_empty = false;
+	}
+
+	/// Is there a note (or a rest) playing?
+	pub fn is_playing(&self) -> bool {
+		self.remaining_ms != 0
+	}
+
+	/// Another [`STEP_MS`] has gone by, so move on to the next note if this
+	/// one has finished.
+	pub fn step(&mut self) {
+		if self.is_playing() {
+			self.remaining_ms = self.remaining_ms.saturating_sub(STEP_MS);
+			if self.remaining_ms == 0 {
+				self.next();
+			}
+		}
+	}
+
+	/// Play the next note in the queue, or stop if there isn't one.
+	fn next(&mut self) {
+		// Any note from the registers has finished
+		self.duration = 0;
+		if let Some(note) = self.queue.pop_front() {
+			self.queue_empty = self.queue.is_empty() && !self.own_tune;
+			self.play(note);
+		} else {
+			self.remaining_ms = 0;
+			self.needs_update = true;
+		}
+	}
+
+	/// The note the PWM should be playing, if any.
+	pub fn current(&self) -> Option<Note> {
+		if !self.is_playing() || self.note.period == 0 {
+			return None;
+		}
+		Some(self.note)
+	}
+
 	pub fn needs_update(&self) -> bool {
 		self.needs_update
 	}
@@ -77,12 +386,17 @@ impl RegisterState {
 		tim14
 			.ccmr1_output()
 			.modify(|_, w| w.oc1pe().set_bit().oc1m().bits(6));
-
-		// prescale 1000 (48MHz -> 48 kHz)
-		tim14.psc.write(|w| w.psc().bits(1000));
 	}
 }
 
+/// Get the period of a MIDI note number, in timer clocks.
+///
+/// Numbers above 127 are treated as 127.
+fn midi_period(midi_note: u8) -> u32 {
+	let midi_note = midi_note.min(MAX_MIDI_NOTE);
+	MIDI_OCTAVE[usize::from(midi_note % 12)] >> (midi_note / 12)
+}
+
 pub struct Hardware(TIM14);
 
 impl Hardware {
@@ -90,59 +404,57 @@ impl Hardware {
 		Self(tim14)
 	}
 
+	/// Go quiet, at the end of the current cycle.
+	///
+	/// Turning the output off part way through a pulse clicks, and so does
+	/// letting the pin float, so we keep driving it (low) instead.
 	pub fn disable(&mut self) {
-		self.0.ccer.modify(|_, w| w.cc1e().clear_bit());
+		// The compare register is preloaded, so this takes effect at the
+		// next update event
+		self.0.ccr1.write(|w| w.ccr().bits(0));
 	}
 
 	pub fn enable(&mut self) {
 		self.0.ccer.modify(|_, w| w.cc1e().set_bit());
 	}
 
-	fn update_register(&self, config: &RegisterState) {
-		// period
-		self.0
-			.arr
-			.write(|w| unsafe { w.bits(config.period() as u32) });
+	fn update_register(&self, note: &Note) {
+		// Prescale as little as we can, for the best accuracy, whilst keeping
+		// the period within the 16-bit counter
+		let prescale = note.period >> 16;
+		let period = note.period / (prescale + 1);
+		// These are all preloaded, so once we're running they take effect at
+		// the end of the current cycle. Cutting a cycle short clicks, and a
+		// tune changes note every few steps.
+		self.0.psc.write(|w| w.psc().bits(prescale as u16));
+		self.0.arr.write(|w| unsafe { w.bits(period - 1) });
 
 		// duty cycle (255 = whole period)
 		self.0.ccr1.write(|w| {
 			w.ccr()
-				.bits((config.period() * config.duty_cycle() as u16) / 255)
+				.bits((period * u32::from(note.duty_cycle) / 255) as u16)
 		});
 
-		// enable auto-reload preload
-		self.0.cr1.modify(|_, w| w.arpe().set_bit());
+		if self.0.cr1.read().cen().bit_is_clear() {
+			// enable auto-reload preload
+			self.0.cr1.modify(|_, w| w.arpe().set_bit());
 
-		// Trigger update event to load the registers
-		self.0.cr1.modify(|_, w| w.urs().set_bit());
-		self.0.egr.write(|w| w.ug().set_bit());
-		self.0.cr1.modify(|_, w| w.urs().clear_bit());
+			// Trigger update event to load the registers
+			self.0.cr1.modify(|_, w| w.urs().set_bit());
+			self.0.egr.write(|w| w.ug().set_bit());
+			self.0.cr1.modify(|_, w| w.urs().clear_bit());
 
-		self.0.cr1.write(|w| w.cen().set_bit());
+			self.0.cr1.modify(|_, w| w.cen().set_bit());
+		}
 	}
 
-	/// Update the status of the registers and PWM output. Return `true` if a note is to be played.
-	pub fn update<F: FnOnce()>(&mut self, register: &RegisterState, enable_cb: F) -> bool {
-		if register.duration > 0 {
-			// a note has to be played - enable PWM
-			self.update_register(register);
+	/// Play a note on the PWM, or go quiet if there isn't one.
+	pub fn update(&mut self, note: Option<&Note>) {
+		if let Some(note) = note {
+			self.update_register(note);
 			self.enable();
-			enable_cb();
-			true
 		} else {
-			// nothing to play (duration == 0), just disable PWM
 			self.disable();
-			false
 		}
 	}
-
-	pub fn set_note(&mut self, duration: u16, period: u16, duty_cycle: u8) {
-		let config = RegisterState {
-			duration,
-			period,
-			duty_cycle,
-			needs_update: true,
-		};
-		self.update_register(&config);
-	}
 }
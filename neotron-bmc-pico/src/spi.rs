@@ -2,6 +2,12 @@
 //!
 //! Unlike the HAL, this implement 'SPI Peripheral Mode', i.e. for when the
 //! clock signal is an input and not an output.
+//!
+//! Every byte the host clocks in clocks one of our bytes out. We keep the
+//! transmit FIFO topped up with padding from the start of each transaction,
+//! and count the bytes we load into it and the bytes we take out of the
+//! receive FIFO. That tells us which of the bytes we receive were sent after
+//! our response (and so are a Long Write Payload).
 
 use stm32f0xx_hal::{pac, prelude::*, rcc::Rcc};
 
@@ -22,6 +28,12 @@ pub struct SpiPeripheral<const RXC: usize, const TXC: usize> {
 	tx_ready: usize,
 	/// The in-progress RX CRC
 	rx_crc: neotron_bmc_protocol::CrcCalc,
+	/// How many bytes we've loaded into the TX FIFO
+	tx_count: usize,
+	/// How many bytes we've taken from the RX FIFO
+	rx_count: usize,
+	/// The received bytes up to and including this one are junk
+	rx_skip: usize,
 }
 
 impl<const RXC: usize, const TXC: usize> SpiPeripheral<RXC, TXC> {
@@ -38,8 +50,6 @@ impl<const RXC: usize, const TXC: usize> SpiPeripheral<RXC, TXC> {
 		MISOPIN: stm32f0xx_hal::spi::MisoPin<pac::SPI1>,
 		MOSIPIN: stm32f0xx_hal::spi::MosiPin<pac::SPI1>,
 	{
-		defmt::info!("pclk = {}", rcc.clocks.pclk().0,);
-
 		// Set SPI up in Controller mode. This will cause the HAL to enable the clocks and power to the IP block.
 		// It also checks the pins are OK.
 		let spi_controller =
@@ -56,6 +66,9 @@ impl<const RXC: usize, const TXC: usize> SpiPeripheral<RXC, TXC> {
 			tx_idx: 0,
 			tx_ready: 0,
 			rx_crc: neotron_bmc_protocol::CrcCalc::new(),
+			tx_count: 0,
+			rx_count: 0,
+			rx_skip: 0,
 		};
 
 		spi.config(Self::MODE);
@@ -127,8 +140,7 @@ impl<const RXC: usize, const TXC: usize> SpiPeripheral<RXC, TXC> {
 			// 3f. Disable DMA mode
 			w.txdmaen().disabled();
 			w.rxdmaen().disabled();
-			// Extra: Turn on RX and Error interrupts, but not TX. We swap
-			// interrupts once the read phase is complete.
+			// Extra: Leave the interrupts off until we're selected
 			w.rxneie().masked();
 			w.txeie().masked();
 			w.errie().masked();
@@ -149,18 +161,27 @@ impl<const RXC: usize, const TXC: usize> SpiPeripheral<RXC, TXC> {
 			panic!("Read too large");
 		}
 		self.rx_idx = 0;
-		self.rx_want = num_bytes as usize;
+		self.rx_want = num_bytes;
 		self.tx_idx = 0;
 		self.tx_ready = 0;
 		self.rx_crc.reset();
+		self.tx_count = 0;
+		self.rx_count = 0;
+		self.rx_skip = 0;
 		// Empty the receive register
 		while self.has_rx_data() {
 			let _ = self.raw_read();
 		}
-		// Turn on RX interrupt; turn off TX interrupt
-		self.dev.cr2.write(|w| {
+		// Fill the TX FIFO with padding before the host can clock anything
+		// out, so we've counted every byte it gets from us.
+		while self.dev.sr.read().txe().is_empty() {
+			self.tx_isr();
+		}
+		// Turn on the RX and TX interrupts. We keep both going for the whole
+		// transaction, to keep our counts right.
+		self.dev.cr2.modify(|_r, w| {
 			w.rxneie().not_masked();
-			w.txeie().masked();
+			w.txeie().not_masked();
 			w
 		});
 		// Tell the SPI engine it has a chip-select
@@ -171,20 +192,25 @@ impl<const RXC: usize, const TXC: usize> SpiPeripheral<RXC, TXC> {
 		});
 	}
 
-	/// Disable the SPI peripheral (i.e. when CS goes high)
-	pub fn stop(&mut self) {
-		self.dev.cr1.modify(|_r, w| {
-			w.ssi().slave_not_selected();
-			w.spe().disabled();
-			w
-		});
+	/// Receive a Long Write Payload of `num_bytes` bytes (including the CRC).
+	///
+	/// Call this after [`Self::set_transmit_sendable()`], and we'll collect
+	/// the bytes which the host sends after it has read our response.
+	pub fn start_payload(&mut self, num_bytes: usize) {
+		if num_bytes > RXC {
+			panic!("Read too large");
+		}
+		self.rx_idx = 0;
+		self.rx_want = num_bytes;
+		self.rx_crc.reset();
 	}
 
-	/// Fully reset the SPI peripheral
+	/// Disable the SPI peripheral (i.e. when CS goes high)
 	///
-	/// This is like calling [`Self::stop()`] but it reboots the IP block to clear any
-	/// partial words from the RX FIFO.
-	pub fn reset(&mut self, _rcc: &mut stm32f0xx_hal::rcc::Rcc) {
+	/// This reboots the IP block, as that's the only way to empty the TX FIFO
+	/// (and any partial words from the RX FIFO), which we need to do to keep
+	/// our counts right for the next transaction.
+	pub fn stop(&mut self) {
 		self.dev.cr1.write(|w| {
 			// Disable the peripheral
 			w.spe().disabled();
@@ -201,15 +227,12 @@ impl<const RXC: usize, const TXC: usize> SpiPeripheral<RXC, TXC> {
 			*reset_reg &= !(spi1_bit);
 		}
 
-		// Reconfigure
+		// Reconfigure, which leaves the SPI device turned off
 		self.config(Self::MODE);
+	}
 
-		// Empty the receive register
-		while self.has_rx_data() {
-			let _ = self.raw_read();
-		}
-
-		// Leave the SPI device turned off
+	/// Fully reset the SPI peripheral
+	pub fn reset(&mut self, _rcc: &mut stm32f0xx_hal::rcc::Rcc) {
 		self.stop();
 	}
 
@@ -248,16 +271,8 @@ impl<const RXC: usize, const TXC: usize> SpiPeripheral<RXC, TXC> {
 	pub fn handle_isr(&mut self) -> bool {
 		let mut have_packet = false;
 		let irq_status = self.dev.sr.read();
-		if irq_status.rxne().is_not_empty() {
-			if self.rx_isr() {
-				// We've got enough, turn the RX interrupt off. Everything else
-				// we receive is going to be garbage.
-				self.dev.cr2.write(|w| {
-					w.rxneie().masked();
-					w
-				});
-				have_packet = true;
-			}
+		if irq_status.rxne().is_not_empty() && self.rx_isr() {
+			have_packet = true;
 		}
 		if irq_status.txe().is_empty() {
 			self.tx_isr();
@@ -265,14 +280,18 @@ impl<const RXC: usize, const TXC: usize> SpiPeripheral<RXC, TXC> {
 		have_packet
 	}
 
-	/// Try and read from the SPI FIFO
+	/// Try and read from the SPI FIFO.
+	///
+	/// Returns `true` when we've just got all the bytes we wanted. Anything
+	/// else we receive is garbage.
 	fn rx_isr(&mut self) -> bool {
 		let byte = self.raw_read();
-		if self.rx_want == 0 {
-			panic!("unwanted data 0x{:02x}", byte);
+		self.rx_count += 1;
+		if self.rx_count <= self.rx_skip || self.rx_idx >= self.rx_want {
+			return false;
 		}
-		if self.rx_idx < self.rx_buffer.len() {
-			self.rx_buffer[self.rx_idx] = byte;
+		if let Some(slot) = self.rx_buffer.get_mut(self.rx_idx) {
+			*slot = byte;
 			self.rx_crc.add(byte);
 			self.rx_idx += 1;
 		}
@@ -288,15 +307,21 @@ impl<const RXC: usize, const TXC: usize> SpiPeripheral<RXC, TXC> {
 			let next_tx = unsafe { *self.tx_buffer.get_unchecked(self.tx_idx) };
 			self.raw_write(next_tx);
 			self.tx_idx += 1;
+			if self.tx_idx == self.tx_ready {
+				// Anything received from here on is a payload
+				self.rx_skip = self.tx_count + 1;
+			}
 		} else {
 			// No data - send 0x00
 			self.raw_write(0x00);
 		}
+		self.tx_count += 1;
 	}
 
 	/// Render some message into the TX buffer.
 	///
 	/// You get an error if you try to load too much.
+	#[allow(clippy::result_unit_err)]
 	pub fn set_transmit_sendable(
 		&mut self,
 		message: &dyn neotron_bmc_protocol::Sendable,
@@ -312,11 +337,8 @@ impl<const RXC: usize, const TXC: usize> SpiPeripheral<RXC, TXC> {
 				// We must never set this to be longer than `TXC` as we do an
 				// unchecked read from `self.tx_buffer` in [`Self::tx_isr`].
 				self.tx_ready = (n + 1).min(TXC);
-				// Turn on the TX interrupt
-				self.dev.cr2.write(|w| {
-					w.txeie().not_masked();
-					w
-				});
+				// Nothing is a payload until the response has gone
+				self.rx_skip = usize::MAX;
 				Ok(())
 			}
 			Err(_) => Err(()),
@@ -0,0 +1,86 @@
+//! # Low-Power Standby
+//!
+//! Whilst the host is off there is usually nothing for us to do, so we put the
+//! MCU into STOP mode. All the clocks stop except the LSI, which keeps the RTC
+//! and our watchdog running, and the voltage regulator goes into low-power
+//! mode. Any EXTI line wakes us up - the power or reset button, the PS/2
+//! keyboard clock, SPI chip select, or the RTC tick.
+//!
+//! We always wake up running from the 8 MHz HSI, so we start the PLL again
+//! before carrying on.
+
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use stm32f0xx_hal::pac;
+
+/// Has something happened which means we should stay awake for a while?
+static ACTIVITY: AtomicBool = AtomicBool::new(false);
+
+/// Set up the power controller so that we can enter STOP mode.
+pub fn setup(rcc: &pac::RCC, pwr: &pac::PWR) {
+	rcc.apb1enr.modify(|_r, w| w.pwren().enabled());
+	// STOP mode (not STANDBY), with the regulator in low-power mode
+	pwr.cr.modify(|_r, w| {
+		w.pdds().stop_mode();
+		w.lpds().set_bit()
+	});
+}
+
+/// Note that something happened, so we should stay awake for a while.
+///
+/// This is safe to call from an interrupt.
+pub fn activity() {
+	ACTIVITY.store(true, Ordering::Relaxed);
+}
+
+/// Has anything happened since we last asked?
+pub fn take_activity() -> bool {
+	// We don't have an atomic swap on the Cortex-M0
+	cortex_m::interrupt::free(|_cs| {
+		let activity = ACTIVITY.load(Ordering::Relaxed);
+		ACTIVITY.store(false, Ordering::Relaxed);
+		activity
+	})
+}
+
+/// Go into STOP mode until an interrupt arrives - unless `is_idle` says there
+/// is something to do, or there has been some activity.
+///
+/// We check with interrupts disabled, so nothing can sneak in between the
+/// check and going to sleep. Any interrupts which woke us up are handled (on
+/// the HSI) before we restart the PLL.
+///
+/// Returns `true` if we went to sleep.
+pub fn stop<F>(scb: &mut cortex_m::peripheral::SCB, is_idle: F) -> bool
+where
+	F: FnOnce() -> bool,
+{
+	cortex_m::interrupt::disable();
+	let sleep = is_idle() && !ACTIVITY.load(Ordering::Relaxed);
+	if sleep {
+		scb.set_sleepdeep();
+		cortex_m::asm::dsb();
+		cortex_m::asm::wfi();
+		scb.clear_sleepdeep();
+	}
+	// Safety: we're not inside a critical section
+	unsafe { cortex_m::interrupt::enable() };
+	if sleep {
+		restore_clocks();
+	}
+	sleep
+}
+
+/// Switch back to running from the PLL (at 48 MHz), which STOP mode turned
+/// off.
+///
+/// The PLL settings (and the flash wait states) are kept during STOP, so we
+/// just have to turn it on and switch over.
+fn restore_clocks() {
+	// Safety: the HAL doesn't touch these registers once the clocks are set up
+	let rcc = unsafe { &*pac::RCC::ptr() };
+	rcc.cr.modify(|_r, w| w.pllon().on());
+	while rcc.cr.read().pllrdy().is_not_ready() {}
+	rcc.cfgr.modify(|_r, w| w.sw().pll());
+	while !rcc.cfgr.read().sws().is_pll() {}
+}
@@ -0,0 +1,70 @@
+//! # Wake Sources
+//!
+//! Things other than the power button which can turn the system on, like the
+//! *Power On by Keyboard* option in a PC BIOS.
+//!
+//! The PS/2 keyboard and UART receive line can both wake us from STOP mode,
+//! using their EXTI lines. We can't receive from the UART in STOP mode, so any
+//! activity on the receive line counts as a character.
+
+use stm32f0xx_hal::pac;
+
+/// Turn on when any key is pressed on the PS/2 keyboard
+pub const SOURCE_ANY_KEY: u8 = 1 << 0;
+// Bit 1 is kept for a key sequence, which doesn't fit in flash
+/// Turn on when a character arrives on the UART
+pub const SOURCE_UART_CHARACTER: u8 = 1 << 2;
+/// Turn on when the UART receive line is held low (a break)
+pub const SOURCE_UART_BREAK: u8 = 1 << 3;
+/// All the sources we know about
+const ALL_SOURCES: u8 = SOURCE_ANY_KEY | SOURCE_UART_CHARACTER | SOURCE_UART_BREAK;
+/// The sources which use the UART
+pub const UART_SOURCES: u8 = SOURCE_UART_CHARACTER | SOURCE_UART_BREAK;
+
+/// How long the UART receive line has to be low for a break, in milliseconds.
+/// This is longer than a whole character at our slowest baud rate.
+pub const BREAK_MS: u64 = 50;
+
+/// The EXTI line for the UART receive pin (PA10)
+const UART_EXTI_LINE: u32 = 10;
+
+/// How we can be woken up. By default, only the power button wakes us.
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub struct Config {
+	/// Which sources are enabled (the `SOURCE_xxx` bits)
+	sources: u8,
+}
+
+impl Config {
+	/// Get the enabled sources, as a bitmask.
+	pub fn sources(&self) -> u8 {
+		self.sources
+	}
+
+	/// Set the enabled sources. Unknown bits are ignored.
+	pub fn set_sources(&mut self, sources: u8) {
+		self.sources = sources & ALL_SOURCES;
+	}
+}
+
+/// Turn the interrupt on the UART receive pin on or off.
+///
+/// We only want it whilst the host is off, as otherwise we'd be interrupted
+/// by every bit the UART receives.
+pub fn listen_uart(enable: bool) {
+	// Safety: we only touch our own bit, inside a critical section
+	let exti = unsafe { &*pac::EXTI::ptr() };
+	cortex_m::interrupt::free(|_cs| {
+		exti.imr.modify(|r, w| {
+			let bits = r.bits() & !(1 << UART_EXTI_LINE);
+			unsafe { w.bits(bits | (u32::from(enable) << UART_EXTI_LINE)) }
+		});
+	});
+}
+
+/// Is the UART receive line low?
+pub fn is_uart_low() -> bool {
+	// Safety: this is a read-only register
+	let gpioa = unsafe { &*pac::GPIOA::ptr() };
+	gpioa.idr.read().idr10().is_low()
+}
@@ -0,0 +1,312 @@
+//! # Host Watchdog
+//!
+//! Resets the host if it stops talking to us. There are two timers:
+//!
+//! * The boot timer starts whenever the host is powered on or reset. If the
+//!   host hasn't touched the watchdog registers before it runs out, we assume
+//!   the host failed to boot and try again, up to a limited number of times.
+//! * The run-time timer is enabled by the host writing a timeout. The host
+//!   must then kick us before the timeout expires, or we reset it.
+//!
+//! Both timers count in seconds, and are driven by calling
+//! [`HostWatchdog::tick`] once a second whilst the host is powered on.
+
+/// What we do to the host when the watchdog expires.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, defmt::Format)]
+#[repr(u8)]
+pub enum Action {
+	/// Pulse the host's reset line
+	Reset = 0,
+	/// Turn the system off, then on again
+	PowerCycle = 1,
+}
+
+impl Action {
+	/// Convert from a register value. Unknown values are `Reset`.
+	pub fn from_u8(value: u8) -> Action {
+		match value {
+			1 => Action::PowerCycle,
+			_ => Action::Reset,
+		}
+	}
+}
+
+/// Why the watchdog last fired.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, defmt::Format)]
+#[repr(u8)]
+pub enum Reason {
+	/// The watchdog hasn't fired
+	None = 0,
+	/// The host enabled the watchdog and then didn't kick it in time
+	Expired = 1,
+	/// The host didn't finish booting in time, so we had another go
+	BootTimeout = 2,
+	/// The host didn't finish booting in time, and we've run out of retries
+	BootRetriesExhausted = 3,
+}
+
+/// The watchdog settings we keep in flash.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Config {
+	/// What to do when the watchdog fires
+	pub action: Action,
+	/// How long the host has to boot, in seconds. Zero means no limit.
+	pub boot_timeout_secs: u8,
+	/// How many times we retry a failed boot before giving up
+	pub boot_retries: u8,
+}
+
+impl Default for Config {
+	fn default() -> Config {
+		Config {
+			action: Action::Reset,
+			boot_timeout_secs: 0,
+			boot_retries: 3,
+		}
+	}
+}
+
+/// Tracks the state of the host watchdog.
+#[derive(Debug)]
+pub struct HostWatchdog {
+	/// The run-time timeout the host gave us, in seconds. Zero means disabled.
+	timeout_secs: u8,
+	/// Seconds left on the run-time timer
+	remaining_secs: u8,
+	/// Seconds left on the boot timer, or `None` if the host has booted (or
+	/// there's no boot timeout).
+	boot_remaining_secs: Option<u8>,
+	/// How many times we've retried the current boot
+	boot_attempts: u8,
+	/// How many times the watchdog has fired
+	count: u8,
+	/// Why the watchdog last fired
+	reason: Reason,
+}
+
+impl HostWatchdog {
+	/// Create a new, idle, watchdog.
+	pub const fn new() -> HostWatchdog {
+		HostWatchdog {
+			timeout_secs: 0,
+			remaining_secs: 0,
+			boot_remaining_secs: None,
+			boot_attempts: 0,
+			count: 0,
+			reason: Reason::None,
+		}
+	}
+
+	/// Call this when the host is powered on or reset by someone other than
+	/// the watchdog.
+	///
+	/// Disables the run-time timer and starts a fresh set of boot attempts.
+	/// This gets called from all over `idle`, so we only want one copy of it.
+	#[inline(never)]
+	pub fn start(&mut self, config: &Config) {
+		self.boot_attempts = 0;
+		self.restart(config);
+	}
+
+	/// Set the run-time timeout, in seconds. Zero disables the run-time timer.
+	///
+	/// This also tells us the host has booted.
+	pub fn set_timeout(&mut self, timeout_secs: u8) {
+		self.timeout_secs = timeout_secs;
+		self.kick();
+	}
+
+	/// Get the run-time timeout, in seconds.
+	pub fn timeout(&self) -> u8 {
+		self.timeout_secs
+	}
+
+	/// The host is still alive - re-load the run-time timer.
+	///
+	/// This also tells us the host has booted.
+	pub fn kick(&mut self) {
+		self.remaining_secs = self.timeout_secs;
+		self.boot_remaining_secs = None;
+		self.boot_attempts = 0;
+	}
+
+	/// How many times has the watchdog fired?
+	pub fn count(&self) -> u8 {
+		self.count
+	}
+
+	/// Why did the watchdog last fire?
+	pub fn reason(&self) -> Reason {
+		self.reason
+	}
+
+	/// Call this once a second, whilst the host is powered on.
+	///
+	/// If you get back `Some(reason)`, the watchdog has fired. Unless the
+	/// reason is [`Reason::BootRetriesExhausted`], you should then perform the
+	/// configured [`Action`] on the host. The watchdog has already been set up
+	/// for the host booting again.
+	pub fn tick(&mut self, config: &Config) -> Option<Reason> {
+		if let Some(boot_remaining_secs) = self.boot_remaining_secs {
+			if boot_remaining_secs > 1 {
+				self.boot_remaining_secs = Some(boot_remaining_secs - 1);
+				return None;
+			}
+			if self.boot_attempts >= config.boot_retries {
+				// Leave the host alone
+				defmt::warn!("Host failed to boot - giving up");
+				self.boot_remaining_secs = None;
+				self.reason = Reason::BootRetriesExhausted;
+				return Some(self.reason);
+			}
+			self.boot_attempts += 1;
+			self.fired(Reason::BootTimeout);
+			self.restart(config);
+			return Some(self.reason);
+		}
+
+		if self.timeout_secs == 0 {
+			return None;
+		}
+		self.remaining_secs = self.remaining_secs.saturating_sub(1);
+		if self.remaining_secs != 0 {
+			return None;
+		}
+		self.fired(Reason::Expired);
+		self.boot_attempts = 0;
+		self.restart(config);
+		Some(self.reason)
+	}
+
+	/// Disable the run-time timer and start the boot timer.
+	fn restart(&mut self, config: &Config) {
+		self.timeout_secs = 0;
+		self.remaining_secs = 0;
+		self.boot_remaining_secs = Some(config.boot_timeout_secs).filter(|secs| *secs != 0);
+	}
+
+	/// Record that the watchdog fired.
+	fn fired(&mut self, reason: Reason) {
+		defmt::warn!("Host watchdog fired: {:?}", reason);
+		self.count = self.count.saturating_add(1);
+		self.reason = reason;
+	}
+}
+
+impl Default for HostWatchdog {
+	fn default() -> HostWatchdog {
+		HostWatchdog::new()
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+
+	/// Tick the watchdog this many times, and collect what comes back.
+	fn run(watchdog: &mut HostWatchdog, config: &Config, ticks: usize) -> Vec<Option<Reason>> {
+		(0..ticks).map(|_| watchdog.tick(config)).collect()
+	}
+
+	/// Three seconds to boot, and two retries
+	const CONFIG: Config = Config {
+		action: Action::Reset,
+		boot_timeout_secs: 3,
+		boot_retries: 2,
+	};
+
+	#[test]
+	fn idle_by_default() {
+		let mut watchdog = HostWatchdog::new();
+		let config = Config::default();
+		watchdog.start(&config);
+		assert!(run(&mut watchdog, &config, 300).iter().all(Option::is_none));
+		assert_eq!(watchdog.count(), 0);
+		assert_eq!(watchdog.reason(), Reason::None);
+	}
+
+	#[test]
+	fn boot_retries() {
+		let mut watchdog = HostWatchdog::new();
+		watchdog.start(&CONFIG);
+		for count in 1..=2 {
+			assert_eq!(
+				run(&mut watchdog, &CONFIG, 3),
+				[None, None, Some(Reason::BootTimeout)]
+			);
+			assert_eq!(watchdog.count(), count);
+		}
+		// Out of retries, so we give up and leave the host alone
+		assert_eq!(
+			run(&mut watchdog, &CONFIG, 3),
+			[None, None, Some(Reason::BootRetriesExhausted)]
+		);
+		assert!(run(&mut watchdog, &CONFIG, 10).iter().all(Option::is_none));
+		assert_eq!(watchdog.count(), 2);
+		assert_eq!(watchdog.reason(), Reason::BootRetriesExhausted);
+	}
+
+	#[test]
+	fn start_gives_fresh_retries() {
+		let mut watchdog = HostWatchdog::new();
+		watchdog.start(&CONFIG);
+		run(&mut watchdog, &CONFIG, 6);
+		// Someone else reset the host
+		watchdog.start(&CONFIG);
+		assert_eq!(
+			run(&mut watchdog, &CONFIG, 6),
+			[
+				None,
+				None,
+				Some(Reason::BootTimeout),
+				None,
+				None,
+				Some(Reason::BootTimeout)
+			]
+		);
+	}
+
+	#[test]
+	fn kick_means_booted() {
+		let mut watchdog = HostWatchdog::new();
+		watchdog.start(&CONFIG);
+		run(&mut watchdog, &CONFIG, 2);
+		watchdog.kick();
+		assert!(run(&mut watchdog, &CONFIG, 10).iter().all(Option::is_none));
+		assert_eq!(watchdog.count(), 0);
+	}
+
+	#[test]
+	fn run_time_expiry() {
+		let mut watchdog = HostWatchdog::new();
+		watchdog.start(&CONFIG);
+		watchdog.set_timeout(3);
+		assert_eq!(watchdog.timeout(), 3);
+		// Kicking re-loads the timer
+		run(&mut watchdog, &CONFIG, 2);
+		watchdog.kick();
+		assert_eq!(
+			run(&mut watchdog, &CONFIG, 3),
+			[None, None, Some(Reason::Expired)]
+		);
+		assert_eq!(watchdog.count(), 1);
+		assert_eq!(watchdog.reason(), Reason::Expired);
+		// The host is rebooting, so the run-time timer is off and the boot
+		// timer is running again, with all its retries
+		assert_eq!(watchdog.timeout(), 0);
+		assert_eq!(
+			run(&mut watchdog, &CONFIG, 3),
+			[None, None, Some(Reason::BootTimeout)]
+		);
+	}
+
+	#[test]
+	fn run_time_expiry_without_boot_timeout() {
+		let mut watchdog = HostWatchdog::new();
+		let config = Config::default();
+		watchdog.start(&config);
+		watchdog.set_timeout(1);
+		assert_eq!(run(&mut watchdog, &config, 1), [Some(Reason::Expired)]);
+		assert!(run(&mut watchdog, &config, 10).iter().all(Option::is_none));
+	}
+}
@@ -1,4 +1,4 @@
-/**
+/*
  * \file
  * Functions and types for CRC checks.
  *
@@ -479,6 +479,12 @@ impl CrcCalc {
 	}
 }
 
+impl Default for CrcCalc {
+	fn default() -> CrcCalc {
+		CrcCalc::new()
+	}
+}
+
 /// Calculates the CRC-8 of the given bytes.
 ///
 /// ```